@@ -1,18 +1,138 @@
 use the_man::Atom;
 
+use crate::logic::message::CodecCapabilities;
+
+use self::{
+    opus::CodecOpus,
+    pcm::{CodecPcm, PcmFormat},
+};
+
 pub mod opus;
+pub mod pcm;
 
 pub trait Codec: Sync + Send {
     fn name(&self) -> &str;
 
     fn settings(&self) -> Vec<String>;
     fn get_setting(&mut self, key: String) -> Option<Atom>;
-    fn set_setting(&mut self, key: String, value: Atom);
+    fn set_setting(&mut self, key: String, value: Atom) -> Result<(), String>;
+
+    /// Samples per channel of every frame [`Codec::encode`] takes.
+    fn frame_size(&self) -> usize;
+
+    /// Encodes one frame of [`Codec::frame_size`] interleaved samples per channel into one packet.
+    ///
+    /// `None` when the frame does not have to be sent, like silence with DTX.
+    fn encode(&mut self, frame: &[f32]) -> Result<Option<Vec<u8>>, String>;
+    /// Decodes one packet into interleaved samples.
+    fn decode(&mut self, packet: &[u8]) -> Result<Vec<f32>, String>;
+    /// Conceals a lost packet of `samples` per channel.
+    ///
+    /// `next` is the packet that follows the lost one when it already arrived,
+    /// codecs with in-band FEC can recover the end of the lost packet from it.
+    fn decode_lost(&mut self, samples: usize, next: Option<&[u8]>) -> Result<Vec<f32>, String>;
+    /// Forgets the decoder state, the next packet starts a new stream.
+    fn reset(&mut self);
+}
+
+/// Creates a new codec with its default settings.
+pub type CodecFactory = Box<dyn Fn() -> Box<dyn Codec> + Send + Sync>;
+
+struct RegisteredCodec {
+    capabilities: CodecCapabilities,
+    factory: CodecFactory,
+}
+
+/// Every codec that can be used, by name, in order of preference.
+///
+/// The name is what peers advertise and what voice packets carry.
+#[derive(Default)]
+pub struct CodecRegistry {
+    codecs: Vec<RegisteredCodec>,
+}
+
+impl CodecRegistry {
+    /// Opus, then the raw PCM codecs.
+    pub fn with_builtin() -> Self {
+        let mut registry = Self::default();
+        registry.register(
+            CodecCapabilities {
+                name: "opus".into(),
+                description: "Compressed, for voice and music over the internet".into(),
+                sample_rates: vec![8000, 12000, 16000, 24000, 48000],
+                channels: vec![1, 2],
+                lossless: false,
+                loss_concealment: true,
+            },
+            || Box::<CodecOpus>::default(),
+        );
+        for format in [PcmFormat::S16Le, PcmFormat::F32] {
+            registry.register(
+                CodecCapabilities {
+                    name: format.name().into(),
+                    description: match format {
+                        PcmFormat::S16Le => "Uncompressed 16 bit, for a LAN",
+                        PcmFormat::F32 => "Uncompressed 32 bit float, for a LAN or debugging",
+                    }
+                    .into(),
+                    sample_rates: pcm::SAMPLE_RATES.iter().map(|rate| *rate as u32).collect(),
+                    channels: vec![1, 2],
+                    lossless: format == PcmFormat::F32,
+                    loss_concealment: false,
+                },
+                move || Box::new(CodecPcm::new(format)),
+            );
+        }
+        registry
+    }
+
+    /// Adds a codec as the least preferred one, or replaces the one with the same name in place.
+    pub fn register(
+        &mut self,
+        capabilities: CodecCapabilities,
+        factory: impl Fn() -> Box<dyn Codec> + Send + Sync + 'static,
+    ) {
+        let codec = RegisteredCodec {
+            capabilities,
+            factory: Box::new(factory),
+        };
+        match self
+            .codecs
+            .iter_mut()
+            .find(|registered| registered.capabilities.name == codec.capabilities.name)
+        {
+            Some(registered) => *registered = codec,
+            None => self.codecs.push(codec),
+        }
+    }
+
+    pub fn create(&self, name: &str) -> Option<Box<dyn Codec>> {
+        self.codecs
+            .iter()
+            .find(|codec| codec.capabilities.name == name)
+            .map(|codec| (codec.factory)())
+    }
 
-    fn errors(&mut self) -> Vec<String>;
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.codecs
+            .iter()
+            .map(|codec| codec.capabilities.name.as_str())
+    }
 
-    fn encode(&mut self, data: &mut Vec<f32>) -> Vec<u8>;
-    fn decode(&mut self, data: &mut Vec<u8>) -> Vec<f32>;
+    pub fn capabilities(&self) -> Vec<CodecCapabilities> {
+        self.codecs
+            .iter()
+            .map(|codec| codec.capabilities.clone())
+            .collect()
+    }
 
-    fn c(&self) -> Box<dyn Codec>;
+    /// Moves the codecs in `names` to the front in that order, unknown names are ignored.
+    pub fn set_order(&mut self, names: &[String]) {
+        self.codecs.sort_by_key(|codec| {
+            names
+                .iter()
+                .position(|name| *name == codec.capabilities.name)
+                .unwrap_or(names.len())
+        });
+    }
 }
@@ -1,5 +1,4 @@
-use audiopus::{Application, Channels, SampleRate};
-use bytes_kman::TBytes;
+use audiopus::{coder::GenericCtl, Application, Bitrate, Channels, SampleRate, Signal};
 use the_man::Atom;
 
 use super::Codec;
@@ -12,12 +11,31 @@ pub struct CodecOpus {
     channels: Channels,
     application: Application,
 
-    errors: Vec<String>,
+    fec: bool,
+    packet_loss: u8,
+    dtx: bool,
+    /// Bits per second, `0` lets the encoder decide.
+    bitrate: u32,
+    vbr: bool,
+    complexity: u8,
+    /// In microseconds, so 2.5ms frames can be represented.
+    frame_duration: u32,
+    signal: Signal,
 
     output_buffer: Vec<u8>,
     input_buffer: Vec<f32>,
 }
 
+/// Frame durations Opus can encode, with their length in microseconds.
+const FRAME_DURATIONS: [(&str, u32); 6] = [
+    ("2.5", 2_500),
+    ("5", 5_000),
+    ("10", 10_000),
+    ("20", 20_000),
+    ("40", 40_000),
+    ("60", 60_000),
+];
+
 unsafe impl Sync for CodecOpus {}
 unsafe impl Send for CodecOpus {}
 
@@ -33,31 +51,28 @@ impl CodecOpus {
             sample_rate,
             channels,
             application,
-            errors: Vec::new(),
+            fec: false,
+            packet_loss: 0,
+            dtx: false,
+            bitrate: 0,
+            vbr: true,
+            complexity: 10,
+            frame_duration: 20_000,
+            signal: Signal::Auto,
             output_buffer: vec![0; 4096],
             input_buffer: vec![0.0; 48000],
         }
     }
-    fn init(&mut self, sample_rate: SampleRate, channels: Channels, application: Application) {
-        let encoder = audiopus::coder::Encoder::new(sample_rate, channels, application);
-        let decoder = audiopus::coder::Decoder::new(sample_rate, channels);
-
-        let encoder = match encoder {
-            Ok(e) => e,
-            Err(err) => {
-                self.errors
-                    .push(format!("CodecOpus error when creating Encoder: {err}"));
-                return;
-            }
-        };
-        let decoder = match decoder {
-            Ok(e) => e,
-            Err(err) => {
-                self.errors
-                    .push(format!("CodecOpus error when creating Decoder: {err}"));
-                return;
-            }
-        };
+    fn init(
+        &mut self,
+        sample_rate: SampleRate,
+        channels: Channels,
+        application: Application,
+    ) -> Result<(), String> {
+        let encoder = audiopus::coder::Encoder::new(sample_rate, channels, application)
+            .map_err(|err| format!("CodecOpus error when creating Encoder: {err}"))?;
+        let decoder = audiopus::coder::Decoder::new(sample_rate, channels)
+            .map_err(|err| format!("CodecOpus error when creating Decoder: {err}"))?;
 
         self.sample_rate = sample_rate;
         self.channels = channels;
@@ -65,6 +80,40 @@ impl CodecOpus {
 
         self.encoder = encoder;
         self.decoder = decoder;
+
+        self.apply_encoder_settings()
+    }
+
+    fn apply_encoder_settings(&mut self) -> Result<(), String> {
+        self.encoder
+            .set_inband_fec(self.fec)
+            .map_err(|err| format!("CodecOpus error when setting fec: {err}"))?;
+        self.encoder
+            .set_packet_loss_perc(self.packet_loss)
+            .map_err(|err| format!("CodecOpus error when setting packet_loss: {err}"))?;
+        self.encoder
+            .set_dtx(self.dtx)
+            .map_err(|err| format!("CodecOpus error when setting dtx: {err}"))?;
+        let bitrate = match self.bitrate {
+            0 => Bitrate::Auto,
+            bitrate => Bitrate::BitsPerSecond(bitrate as i32),
+        };
+        self.encoder
+            .set_bitrate(bitrate)
+            .map_err(|err| format!("CodecOpus error when setting bitrate: {err}"))?;
+        self.encoder
+            .set_vbr(self.vbr)
+            .map_err(|err| format!("CodecOpus error when setting vbr: {err}"))?;
+        self.encoder
+            .set_complexity(self.complexity)
+            .map_err(|err| format!("CodecOpus error when setting complexity: {err}"))?;
+        self.encoder
+            .set_signal(self.signal)
+            .map_err(|err| format!("CodecOpus error when setting signal: {err}"))
+    }
+
+    fn interleaved_len(&self, samples: usize) -> usize {
+        (samples * self.channels as i32 as usize).min(self.input_buffer.len())
     }
 }
 
@@ -87,6 +136,14 @@ impl Codec for CodecOpus {
             "sample_rate".into(),
             "channels".into(),
             "application".into(),
+            "fec".into(),
+            "packet_loss".into(),
+            "dtx".into(),
+            "bitrate".into(),
+            "vbr".into(),
+            "complexity".into(),
+            "frame_duration".into(),
+            "signal".into(),
         ]
     }
 
@@ -108,108 +165,203 @@ impl Codec for CodecOpus {
                 },
                 values: vec!["Voip".into(), "Audio".into(), "LowDelay".into()],
             }),
+            "fec" => Some(Atom::on_off(self.fec)),
+            "packet_loss" => Some(the_man::Atom::UnSigned {
+                value: self.packet_loss as usize,
+                range: 0..101,
+            }),
+            "dtx" => Some(Atom::on_off(self.dtx)),
+            "bitrate" => Some(the_man::Atom::UnSigned {
+                value: self.bitrate as usize,
+                range: 0..512_001,
+            }),
+            "vbr" => Some(Atom::on_off(self.vbr)),
+            "complexity" => Some(the_man::Atom::UnSigned {
+                value: self.complexity as usize,
+                range: 0..11,
+            }),
+            "frame_duration" => Some(the_man::Atom::StringValues {
+                value: FRAME_DURATIONS
+                    .iter()
+                    .find(|(_, duration)| *duration == self.frame_duration)
+                    .map_or("20", |(name, _)| name)
+                    .into(),
+                values: FRAME_DURATIONS
+                    .iter()
+                    .map(|(name, _)| name.to_string())
+                    .collect(),
+            }),
+            "signal" => Some(the_man::Atom::StringValues {
+                value: match self.signal {
+                    Signal::Auto => "Auto".into(),
+                    Signal::Voice => "Voice".into(),
+                    Signal::Music => "Music".into(),
+                },
+                values: vec!["Auto".into(), "Voice".into(), "Music".into()],
+            }),
             _ => None,
         }
     }
 
-    fn set_setting(&mut self, key: String, value: the_man::Atom) {
-        match key.trim() {
-            "sample_rate" => {
-                if value.valid() {
-                    if let Atom::UnSignedValues { value, .. } = value {
-                        let sample_rate = match value {
-                            8000 => SampleRate::Hz8000,
-                            12000 => SampleRate::Hz12000,
-                            16000 => SampleRate::Hz16000,
-                            24000 => SampleRate::Hz24000,
-                            48000 => SampleRate::Hz48000,
-                            _ => return,
-                        };
-                        self.init(sample_rate, self.channels, self.application)
-                    }
-                }
+    fn set_setting(&mut self, key: String, value: the_man::Atom) -> Result<(), String> {
+        if !value.valid() {
+            return Err(format!("CodecOpus invalid value for {key}: {value:?}"));
+        }
+        match (key.trim(), value) {
+            ("sample_rate", Atom::UnSignedValues { value, .. }) => {
+                let sample_rate = match value {
+                    8000 => SampleRate::Hz8000,
+                    12000 => SampleRate::Hz12000,
+                    16000 => SampleRate::Hz16000,
+                    24000 => SampleRate::Hz24000,
+                    48000 => SampleRate::Hz48000,
+                    _ => return Err(format!("CodecOpus unsupported sample_rate: {value}")),
+                };
+                self.init(sample_rate, self.channels, self.application)
+            }
+            ("channels", Atom::UnSignedValues { value, .. }) => {
+                let channels = match value {
+                    1 => Channels::Mono,
+                    2 => Channels::Stereo,
+                    _ => return Err(format!("CodecOpus unsupported channels: {value}")),
+                };
+                self.init(self.sample_rate, channels, self.application)
+            }
+            ("application", Atom::StringValues { value, .. }) => {
+                let application = match value.trim() {
+                    "Voip" => Application::Voip,
+                    "Audio" => Application::Audio,
+                    "LowDelay" => Application::LowDelay,
+                    _ => return Err(format!("CodecOpus unsupported application: {value}")),
+                };
+                self.init(self.sample_rate, self.channels, application)
             }
-            "channels" => {
-                if value.valid() {
-                    if let Atom::UnSignedValues { value, .. } = value {
-                        let channels = match value {
-                            1 => Channels::Mono,
-                            2 => Channels::Stereo,
-                            _ => return,
-                        };
-                        self.init(self.sample_rate, channels, self.application)
-                    }
-                }
+            ("fec", Atom::StringValues { value, .. }) => {
+                self.fec = value == "On";
+                self.apply_encoder_settings()
             }
-            "application" => {
-                if value.valid() {
-                    if let Atom::StringValues { value, .. } = value {
-                        let application = match value.trim() {
-                            "Voip" => Application::Voip,
-                            "Audio" => Application::Audio,
-                            "LowDelay" => Application::LowDelay,
-                            _ => return,
-                        };
-                        self.init(self.sample_rate, self.channels, application)
-                    }
-                }
+            ("packet_loss", Atom::UnSigned { value, .. }) => {
+                self.packet_loss = value as u8;
+                self.apply_encoder_settings()
             }
-            _ => {}
+            ("dtx", Atom::StringValues { value, .. }) => {
+                self.dtx = value == "On";
+                self.apply_encoder_settings()
+            }
+            ("bitrate", Atom::UnSigned { value, .. }) => {
+                self.bitrate = value as u32;
+                self.apply_encoder_settings()
+            }
+            ("vbr", Atom::StringValues { value, .. }) => {
+                self.vbr = value == "On";
+                self.apply_encoder_settings()
+            }
+            ("complexity", Atom::UnSigned { value, .. }) => {
+                self.complexity = value as u8;
+                self.apply_encoder_settings()
+            }
+            ("frame_duration", Atom::StringValues { value, .. }) => {
+                let (_, duration) = FRAME_DURATIONS
+                    .iter()
+                    .find(|(name, _)| *name == value)
+                    .ok_or_else(|| format!("CodecOpus unsupported frame_duration: {value}"))?;
+                self.frame_duration = *duration;
+                Ok(())
+            }
+            ("signal", Atom::StringValues { value, .. }) => {
+                self.signal = match value.trim() {
+                    "Auto" => Signal::Auto,
+                    "Voice" => Signal::Voice,
+                    "Music" => Signal::Music,
+                    _ => return Err(format!("CodecOpus unsupported signal: {value}")),
+                };
+                self.apply_encoder_settings()
+            }
+            (key, value) => Err(format!("CodecOpus has no setting {key} of {value:?}")),
         }
     }
 
-    fn errors(&mut self) -> Vec<String> {
-        std::mem::take(&mut self.errors)
+    fn frame_size(&self) -> usize {
+        self.sample_rate as i32 as usize * self.frame_duration as usize / 1_000_000
     }
 
-    fn encode(&mut self, data: &mut Vec<f32>) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        let chunk =
-            ((self.sample_rate as i32 as usize / 1000) * self.channels as i32 as usize) * 20;
-        let mut size;
-        let mut to_remove;
-        while {
-            size = data.len();
-            to_remove = size - (size % chunk);
-            to_remove >= chunk
-        } {
-            let data = data.drain(..to_remove).collect::<Vec<f32>>();
-            match self.encoder.encode_float(&data, &mut self.output_buffer) {
-                Ok(len) => {
-                    buffer.append(&mut self.output_buffer[..len].to_vec().to_bytes());
-                }
-                Err(err) => self
-                    .errors
-                    .push(format!("OpusCodec error when encoding: {err}")),
-            }
+    fn encode(&mut self, frame: &[f32]) -> Result<Option<Vec<u8>>, String> {
+        let expected = self.frame_size() * self.channels as i32 as usize;
+        if frame.len() != expected {
+            return Err(format!(
+                "OpusCodec frame of {} samples, expected {expected}",
+                frame.len()
+            ));
         }
-        buffer
+        let len = self
+            .encoder
+            .encode_float(frame, &mut self.output_buffer)
+            .map_err(|err| format!("OpusCodec error when encoding: {err}"))?;
+        // With DTX a silent frame is only the TOC byte, nothing to send.
+        if self.dtx && len <= 2 {
+            return Ok(None);
+        }
+        Ok(Some(self.output_buffer[..len].to_vec()))
     }
 
-    fn decode(&mut self, data: &mut Vec<u8>) -> Vec<f32> {
-        let mut buffer = Vec::new();
-        while data.len() >= 0usize.size() {
-            let Some(data) = Vec::<u8>::from_bytes(data) else {break};
-            if data.is_empty() {
-                continue;
-            }
-            match self.decoder.decode_float(
-                Some(data.as_slice().try_into().unwrap()),
+    fn decode(&mut self, packet: &[u8]) -> Result<Vec<f32>, String> {
+        let packet = packet
+            .try_into()
+            .map_err(|err| format!("OpusCodec invalid packet: {err}"))?;
+        let len = self
+            .decoder
+            .decode_float(
+                Some(packet),
                 self.input_buffer.as_mut_slice().try_into().unwrap(),
                 false,
-            ) {
-                Ok(len) => buffer
-                    .append(&mut self.input_buffer[..len * self.channels as i32 as usize].to_vec()),
-                Err(err) => self
-                    .errors
-                    .push(format!("OpusCodec error when decoding: {err}")),
-            }
+            )
+            .map_err(|err| format!("OpusCodec error when decoding: {err}"))?;
+        Ok(self.input_buffer[..len * self.channels as i32 as usize].to_vec())
+    }
+
+    fn decode_lost(&mut self, samples: usize, next: Option<&[u8]>) -> Result<Vec<f32>, String> {
+        let mut buffer = Vec::new();
+        let channels = self.channels as i32 as usize;
+
+        // The next packet can carry the end of the lost one.
+        let fec = next.and_then(|next| {
+            let fec_samples = self.decoder.nb_samples(next.try_into().ok()?).ok()?;
+            (fec_samples <= samples).then_some((next, fec_samples))
+        });
+        let fec_samples = fec.as_ref().map_or(0, |(_, fec_samples)| *fec_samples);
+
+        let plc_len = self.interleaved_len(samples - fec_samples);
+        if plc_len > 0 {
+            let len = self
+                .decoder
+                .decode_float(
+                    None,
+                    (&mut self.input_buffer[..plc_len]).try_into().unwrap(),
+                    false,
+                )
+                .map_err(|err| format!("OpusCodec error when concealing: {err}"))?;
+            buffer.extend_from_slice(&self.input_buffer[..len * channels]);
         }
 
-        buffer
+        if let Some((packet, fec_samples)) = fec {
+            let fec_len = self.interleaved_len(fec_samples);
+            let len = self
+                .decoder
+                .decode_float(
+                    Some(packet.try_into().unwrap()),
+                    (&mut self.input_buffer[..fec_len]).try_into().unwrap(),
+                    true,
+                )
+                .map_err(|err| format!("OpusCodec error when decoding fec: {err}"))?;
+            buffer.extend_from_slice(&self.input_buffer[..len * channels]);
+        }
+
+        Ok(buffer)
     }
 
-    fn c(&self) -> Box<dyn Codec> {
-        Box::new(Self::new(self.sample_rate, self.channels, self.application))
+    fn reset(&mut self) {
+        if let Err(err) = self.decoder.reset_state() {
+            log::debug!("OpusCodec error when resetting decoder: {err}");
+        }
     }
 }
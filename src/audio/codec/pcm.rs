@@ -0,0 +1,251 @@
+use the_man::Atom;
+
+use super::Codec;
+
+/// How [`CodecPcm`] stores a sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PcmFormat {
+    S16Le,
+    F32,
+}
+
+impl PcmFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PcmFormat::S16Le => "pcm_s16le",
+            PcmFormat::F32 => "pcm_f32",
+        }
+    }
+
+    fn sample_size(&self) -> usize {
+        match self {
+            PcmFormat::S16Le => 2,
+            PcmFormat::F32 => 4,
+        }
+    }
+}
+
+pub(super) const SAMPLE_RATES: [usize; 9] =
+    [8000, 16000, 22050, 24000, 32000, 44100, 48000, 88200, 96000];
+/// Frame durations in milliseconds.
+const FRAME_DURATIONS: [&str; 3] = ["5", "10", "20"];
+
+/// Uncompressed samples, for a LAN or to rule out the codec when debugging.
+pub struct CodecPcm {
+    format: PcmFormat,
+    sample_rate: u32,
+    channels: u16,
+    /// In milliseconds.
+    frame_duration: u32,
+}
+
+impl CodecPcm {
+    pub fn new(format: PcmFormat) -> Self {
+        Self {
+            format,
+            sample_rate: 48000,
+            channels: 1,
+            frame_duration: 20,
+        }
+    }
+}
+
+impl Codec for CodecPcm {
+    fn name(&self) -> &str {
+        self.format.name()
+    }
+
+    fn settings(&self) -> Vec<String> {
+        vec![
+            "sample_rate".into(),
+            "channels".into(),
+            "frame_duration".into(),
+        ]
+    }
+
+    fn get_setting(&mut self, key: String) -> Option<Atom> {
+        match key.trim() {
+            "sample_rate" => Some(Atom::UnSignedValues {
+                value: self.sample_rate as usize,
+                values: SAMPLE_RATES.to_vec(),
+            }),
+            "channels" => Some(Atom::UnSignedValues {
+                value: self.channels as usize,
+                values: vec![1, 2],
+            }),
+            "frame_duration" => Some(Atom::StringValues {
+                value: self.frame_duration.to_string(),
+                values: FRAME_DURATIONS
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+            }),
+            _ => None,
+        }
+    }
+
+    fn set_setting(&mut self, key: String, value: Atom) -> Result<(), String> {
+        if !value.valid() {
+            return Err(format!("CodecPcm invalid value for {key}: {value:?}"));
+        }
+        match (key.trim(), value) {
+            ("sample_rate", Atom::UnSignedValues { value, .. }) => self.sample_rate = value as u32,
+            ("channels", Atom::UnSignedValues { value, .. }) => self.channels = value as u16,
+            ("frame_duration", Atom::StringValues { value, .. }) => {
+                self.frame_duration = value
+                    .parse()
+                    .map_err(|err| format!("CodecPcm invalid frame_duration: {err}"))?;
+            }
+            (key, value) => return Err(format!("CodecPcm has no setting {key} of {value:?}")),
+        }
+        Ok(())
+    }
+
+    fn frame_size(&self) -> usize {
+        self.sample_rate as usize * self.frame_duration as usize / 1000
+    }
+
+    fn encode(&mut self, frame: &[f32]) -> Result<Option<Vec<u8>>, String> {
+        let expected = self.frame_size() * self.channels as usize;
+        if frame.len() != expected {
+            return Err(format!(
+                "CodecPcm frame of {} samples, expected {expected}",
+                frame.len()
+            ));
+        }
+        let mut packet = Vec::with_capacity(frame.len() * self.format.sample_size());
+        for sample in frame {
+            match self.format {
+                PcmFormat::S16Le => packet.extend_from_slice(
+                    &((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes(),
+                ),
+                PcmFormat::F32 => packet.extend_from_slice(&sample.to_le_bytes()),
+            }
+        }
+        Ok(Some(packet))
+    }
+
+    fn decode(&mut self, packet: &[u8]) -> Result<Vec<f32>, String> {
+        if !packet
+            .len()
+            .is_multiple_of(self.format.sample_size() * self.channels as usize)
+        {
+            return Err(format!(
+                "CodecPcm packet of {} bytes is not whole samples",
+                packet.len()
+            ));
+        }
+        Ok(match self.format {
+            PcmFormat::S16Le => packet
+                .chunks_exact(2)
+                .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0)
+                .collect(),
+            PcmFormat::F32 => packet
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .collect(),
+        })
+    }
+
+    /// Nothing to conceal with, a lost packet is silence.
+    fn decode_lost(&mut self, samples: usize, _next: Option<&[u8]>) -> Result<Vec<f32>, String> {
+        Ok(vec![0.0; samples * self.channels as usize])
+    }
+
+    /// Every packet stands alone, there is nothing to forget.
+    fn reset(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(codec: &CodecPcm) -> Vec<f32> {
+        let samples = codec.frame_size() * codec.channels as usize;
+        (0..samples)
+            .map(|sample| (sample as f32 / samples as f32) * 2.0 - 1.0)
+            .collect()
+    }
+
+    #[test]
+    fn f32_round_trips_exactly() {
+        let mut codec = CodecPcm::new(PcmFormat::F32);
+        let input = frame(&codec);
+        let packet = codec.encode(&input).unwrap().unwrap();
+        assert_eq!(packet.len(), input.len() * 4);
+        assert_eq!(codec.decode(&packet).unwrap(), input);
+    }
+
+    #[test]
+    fn s16le_round_trips_within_a_step() {
+        let mut codec = CodecPcm::new(PcmFormat::S16Le);
+        let input = frame(&codec);
+        let packet = codec.encode(&input).unwrap().unwrap();
+        assert_eq!(packet.len(), input.len() * 2);
+        let output = codec.decode(&packet).unwrap();
+        assert_eq!(output.len(), input.len());
+        for (input, output) in input.iter().zip(output) {
+            assert!((input - output).abs() < 2.0 / 32768.0, "{input} {output}");
+        }
+    }
+
+    #[test]
+    fn s16le_clamps() {
+        let mut codec = CodecPcm::new(PcmFormat::S16Le);
+        let mut input = vec![0.0; codec.frame_size()];
+        input[0] = 2.0;
+        input[1] = -2.0;
+        let packet = codec.encode(&input).unwrap().unwrap();
+        let output = codec.decode(&packet).unwrap();
+        assert!((output[0] - 1.0).abs() < 1e-4);
+        assert!((output[1] + 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn follows_the_settings() {
+        let mut codec = CodecPcm::new(PcmFormat::F32);
+        codec
+            .set_setting(
+                "channels".into(),
+                Atom::UnSignedValues {
+                    value: 2,
+                    values: vec![1, 2],
+                },
+            )
+            .unwrap();
+        codec
+            .set_setting(
+                "sample_rate".into(),
+                Atom::UnSignedValues {
+                    value: 16000,
+                    values: SAMPLE_RATES.to_vec(),
+                },
+            )
+            .unwrap();
+        codec
+            .set_setting(
+                "frame_duration".into(),
+                Atom::StringValues {
+                    value: "10".into(),
+                    values: FRAME_DURATIONS
+                        .iter()
+                        .map(|name| name.to_string())
+                        .collect(),
+                },
+            )
+            .unwrap();
+        assert_eq!(codec.frame_size(), 160);
+        let input = frame(&codec);
+        assert_eq!(input.len(), 320);
+        let packet = codec.encode(&input).unwrap().unwrap();
+        assert_eq!(codec.decode(&packet).unwrap(), input);
+        assert_eq!(codec.decode_lost(160, None).unwrap(), vec![0.0; 320]);
+    }
+
+    #[test]
+    fn rejects_partial_frames_and_samples() {
+        let mut codec = CodecPcm::new(PcmFormat::S16Le);
+        assert!(codec.encode(&[0.0; 10]).is_err());
+        assert!(codec.decode(&[0; 3]).is_err());
+    }
+}
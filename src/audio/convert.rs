@@ -0,0 +1,175 @@
+/// Converts interleaved samples from `from` channels to `to` channels.
+///
+/// Mono is copied to every output channel, anything else is averaged down
+/// to mono first when the channel counts differ.
+pub fn remix(input: &[f32], from: usize, to: usize, output: &mut Vec<f32>) {
+    if from == to || from == 0 || to == 0 {
+        output.extend_from_slice(input);
+        return;
+    }
+
+    for frame in input.chunks_exact(from) {
+        let sample = frame.iter().sum::<f32>() / from as f32;
+        for _ in 0..to {
+            output.push(sample);
+        }
+    }
+}
+
+/// Streaming linear resampler for interleaved samples.
+pub struct Resampler {
+    from: u32,
+    to: u32,
+    channels: usize,
+    /// Position of the next output frame, in input frames after `last`.
+    position: f64,
+    last: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32, channels: usize) -> Self {
+        Self {
+            from,
+            to,
+            channels: channels.max(1),
+            position: 0.0,
+            last: vec![0.0; channels.max(1)],
+        }
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.from == self.to
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if self.is_passthrough() {
+            output.extend_from_slice(input);
+            return;
+        }
+
+        let channels = self.channels;
+        let frames = input.len() / channels;
+        if frames == 0 {
+            return;
+        }
+
+        // Frame 0 is the last frame of the previous call, 1..=frames are from `input`.
+        let frame = |index: usize, channel: usize| {
+            if index == 0 {
+                self.last[channel]
+            } else {
+                input[(index - 1) * channels + channel]
+            }
+        };
+
+        let step = self.from as f64 / self.to as f64;
+        let mut position = self.position;
+        while position < frames as f64 {
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            for channel in 0..channels {
+                let a = frame(index, channel);
+                let b = frame(index + 1, channel);
+                output.push(a + (b - a) * fraction);
+            }
+            position += step;
+        }
+
+        self.position = position - frames as f64;
+        self.last
+            .copy_from_slice(&input[(frames - 1) * channels..frames * channels]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::*;
+
+    fn sine(frequency: f32, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| (TAU * frequency * frame as f32 / rate as f32).sin())
+            .collect()
+    }
+
+    fn resample(from: u32, to: u32, channels: usize, input: &[f32], chunk: usize) -> Vec<f32> {
+        let mut resampler = Resampler::new(from, to, channels);
+        let mut output = Vec::new();
+        for chunk in input.chunks(chunk * channels) {
+            resampler.process(chunk, &mut output);
+        }
+        output
+    }
+
+    #[test]
+    fn passthrough_copies() {
+        let input = sine(440.0, 48000, 480);
+        assert!(Resampler::new(48000, 48000, 1).is_passthrough());
+        assert_eq!(resample(48000, 48000, 1, &input, 100), input);
+    }
+
+    #[test]
+    fn length_follows_the_rate() {
+        for (from, to) in [
+            (44100, 48000),
+            (48000, 16000),
+            (16000, 48000),
+            (48000, 44100),
+        ] {
+            let frames = from as usize;
+            let input = vec![0.0; frames * 2];
+            // Odd chunk sizes, so the position is carried across calls.
+            let output = resample(from, to, 2, &input, 441);
+            assert_eq!(output.len() % 2, 0);
+            let expected = to as i64;
+            let got = (output.len() / 2) as i64;
+            assert!((got - expected).abs() <= 1, "{from} -> {to}: {got}");
+        }
+    }
+
+    #[test]
+    fn keeps_the_frequency() {
+        let (from, to) = (44100, 48000);
+        let input = sine(1000.0, from, from as usize / 10);
+        let output = resample(from, to, 1, &input, 333);
+        let step = from as f32 / to as f32;
+        // Output frame 0 is the frame before the input, so everything lags one input frame.
+        for (frame, sample) in output.iter().enumerate() {
+            let time = (frame as f32 * step - 1.0) / from as f32;
+            if time < 0.0 {
+                continue;
+            }
+            let expected = (TAU * 1000.0 * time).sin();
+            assert!(
+                (sample - expected).abs() < 0.01,
+                "{frame}: {sample} {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_channels_apart() {
+        let input: Vec<f32> = (0..4800).flat_map(|_| [1.0, -1.0]).collect();
+        let output = resample(48000, 16000, 2, &input, 480);
+        // The first frame interpolates from the silence before the input.
+        for frame in output.chunks_exact(2).skip(1) {
+            assert_eq!(frame, [1.0, -1.0]);
+        }
+    }
+
+    #[test]
+    fn remixes() {
+        let mut output = Vec::new();
+        remix(&[0.5, -0.5], 1, 2, &mut output);
+        assert_eq!(output, [0.5, 0.5, -0.5, -0.5]);
+
+        output.clear();
+        remix(&[1.0, 0.0, 0.5, 0.5], 2, 1, &mut output);
+        assert_eq!(output, [0.5, 0.5]);
+
+        output.clear();
+        remix(&[1.0, 2.0], 2, 2, &mut output);
+        assert_eq!(output, [1.0, 2.0]);
+    }
+}
@@ -0,0 +1,164 @@
+use the_man::Atom;
+
+use super::{super::gate::level_db, Stage};
+
+/// Quieter blocks are not speech, the gain is held instead of raising the noise.
+const SPEECH_DB: f32 = -55.0;
+/// How fast the gain is lowered and raised, in dB a second.
+const ATTACK_DB: f32 = 60.0;
+const RELEASE_DB: f32 = 6.0;
+
+/// Automatic gain control, brings speech to a target level without clipping.
+pub struct Agc {
+    enabled: bool,
+    /// Level in dBFS speech is brought to.
+    target: i32,
+    /// Most the signal is amplified, in dB.
+    max_gain: u32,
+    /// Seconds of one block.
+    block_seconds: f32,
+    /// Current gain in dB.
+    gain: f32,
+}
+
+impl Agc {
+    pub fn new(sample_rate: u32, block: usize) -> Self {
+        Self {
+            enabled: true,
+            target: -18,
+            max_gain: 20,
+            block_seconds: block as f32 / sample_rate.max(1) as f32,
+            gain: 0.0,
+        }
+    }
+}
+
+impl Stage for Agc {
+    fn settings(&self) -> Vec<String> {
+        vec!["agc".into(), "agc_target".into(), "agc_max_gain".into()]
+    }
+
+    fn get_setting(&mut self, key: String) -> Option<Atom> {
+        match key.trim() {
+            "agc" => Some(Atom::on_off(self.enabled)),
+            "agc_target" => Some(Atom::Signed {
+                value: self.target as isize,
+                range: -40..1,
+            }),
+            "agc_max_gain" => Some(Atom::UnSigned {
+                value: self.max_gain as usize,
+                range: 0..31,
+            }),
+            _ => None,
+        }
+    }
+
+    fn set_setting(&mut self, key: String, value: Atom) {
+        match key.trim() {
+            "agc" if value.valid() => {
+                if let Atom::StringValues { value, .. } = value {
+                    self.enabled = value == "On";
+                    self.gain = 0.0;
+                }
+            }
+            "agc_target" if value.valid() => {
+                if let Atom::Signed { value, .. } = value {
+                    self.target = value as i32;
+                }
+            }
+            "agc_max_gain" if value.valid() => {
+                if let Atom::UnSigned { value, .. } = value {
+                    self.max_gain = value as u32;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn process(&mut self, block: &mut [f32], _reference: &[f32]) {
+        let previous = self.gain;
+        let level = level_db(block);
+        if level > SPEECH_DB {
+            let wanted = (self.target as f32 - level).min(self.max_gain as f32);
+            self.gain = if wanted < self.gain {
+                (self.gain - ATTACK_DB * self.block_seconds).max(wanted)
+            } else {
+                (self.gain + RELEASE_DB * self.block_seconds).min(wanted)
+            };
+        }
+
+        // Ramp over the block so the gain change does not click.
+        let from = 10f32.powf(previous / 20.0);
+        let to = 10f32.powf(self.gain / 20.0);
+        let len = block.len() as f32;
+        for (index, sample) in block.iter_mut().enumerate() {
+            let gain = from + (to - from) * (index as f32 / len);
+            *sample = (*sample * gain).clamp(-1.0, 1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+    const BLOCK: usize = 480;
+
+    /// Runs `seconds` of a sine at `level` dBFS through, the level of the last block.
+    fn run(agc: &mut Agc, level: f32, seconds: usize) -> f32 {
+        // A sine peaks 3dB over its level.
+        let amplitude = 10f32.powf(level / 20.0) * std::f32::consts::SQRT_2;
+        let mut block = vec![0.0; BLOCK];
+        for index in 0..seconds * 100 {
+            for (sample, out) in block.iter_mut().enumerate() {
+                let time = (index * BLOCK + sample) as f32 / RATE as f32;
+                *out = (std::f32::consts::TAU * 440.0 * time).sin() * amplitude;
+            }
+            agc.process(&mut block, &[]);
+        }
+        level_db(&block)
+    }
+
+    #[test]
+    fn raises_quiet_speech_up_to_the_max_gain() {
+        let mut agc = Agc::new(RATE, BLOCK);
+        // 20dB below the target is raised at 6dB a second.
+        let level = run(&mut agc, -38.0, 1);
+        assert!((level - -32.0).abs() < 0.5, "{level}");
+        let level = run(&mut agc, -38.0, 5);
+        assert!((level - -18.0).abs() < 0.5, "{level}");
+
+        // The max gain stops short of the target.
+        let mut agc = Agc::new(RATE, BLOCK);
+        let level = run(&mut agc, -45.0, 10);
+        assert!((level - -25.0).abs() < 0.5, "{level}");
+    }
+
+    #[test]
+    fn lowers_loud_speech_fast() {
+        let mut agc = Agc::new(RATE, BLOCK);
+        let level = run(&mut agc, -3.0, 1);
+        assert!((level - -18.0).abs() < 0.5, "{level}");
+    }
+
+    #[test]
+    fn holds_the_gain_below_speech() {
+        let mut agc = Agc::new(RATE, BLOCK);
+        let level = run(&mut agc, -60.0, 2);
+        assert!((level - -60.0).abs() < 0.5, "{level}");
+    }
+
+    #[test]
+    fn never_clips() {
+        let mut agc = Agc::new(RATE, BLOCK);
+        run(&mut agc, -38.0, 5);
+        let mut block = vec![0.9; BLOCK];
+        agc.process(&mut block, &[]);
+        assert!(block.iter().all(|sample| sample.abs() <= 1.0));
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::VecDeque;
+
+use realfft::num_complex::Complex;
+use the_man::Atom;
+
+use super::{Fft, Stage};
+
+/// Adaptation step, between `0` and `1`.
+const STEP: f32 = 0.5;
+/// Smoothing of the reference power in every bin.
+const POWER_SMOOTHING: f32 = 0.9;
+/// The microphone is louder than this times the playback peak, somebody is talking locally.
+const DOUBLE_TALK: f32 = 0.5;
+
+/// Acoustic echo cancellation with a partitioned block frequency domain adaptive filter.
+///
+/// The playback is filtered by an estimate of the room and the speaker and
+/// subtracted from the microphone, the estimate adapts while only the far end talks.
+pub struct EchoCanceller {
+    enabled: bool,
+    /// Longest echo that is cancelled, in ms.
+    length: u32,
+    sample_rate: u32,
+    block: usize,
+
+    fft: Fft,
+    /// Spectrum of the playback for every partition, newest first.
+    history: VecDeque<Vec<Complex<f32>>>,
+    /// Peak of the playback of every partition, newest first.
+    peaks: VecDeque<f32>,
+    weights: Vec<Vec<Complex<f32>>>,
+    power: Vec<f32>,
+    previous: Vec<f32>,
+    frame: Vec<f32>,
+    estimate: Vec<Complex<f32>>,
+    error: Vec<Complex<f32>>,
+    gradient: Vec<Complex<f32>>,
+}
+
+impl EchoCanceller {
+    pub fn new(sample_rate: u32, block: usize) -> Self {
+        let fft = Fft::new(block * 2);
+        let mut echo = Self {
+            enabled: true,
+            length: 200,
+            sample_rate,
+            block,
+            estimate: fft.spectrum(),
+            error: fft.spectrum(),
+            gradient: fft.spectrum(),
+            fft,
+            history: VecDeque::new(),
+            peaks: VecDeque::new(),
+            weights: Vec::new(),
+            power: Vec::new(),
+            previous: vec![0.0; block],
+            frame: vec![0.0; block * 2],
+        };
+        echo.reset();
+        echo
+    }
+
+    /// Forgets the learned echo path, sized for `length`.
+    fn reset(&mut self) {
+        let samples = (self.sample_rate as usize / 1000 * self.length as usize).max(1);
+        let partitions = samples.div_ceil(self.block);
+        let bins = self.fft.bins();
+        self.history = (0..partitions).map(|_| self.fft.spectrum()).collect();
+        self.peaks = (0..partitions).map(|_| 0.0).collect();
+        self.weights = (0..partitions).map(|_| self.fft.spectrum()).collect();
+        self.power = vec![0.0; bins];
+        self.previous.fill(0.0);
+    }
+}
+
+impl Stage for EchoCanceller {
+    fn settings(&self) -> Vec<String> {
+        vec!["echo_cancellation".into(), "echo_length".into()]
+    }
+
+    fn get_setting(&mut self, key: String) -> Option<Atom> {
+        match key.trim() {
+            "echo_cancellation" => Some(Atom::on_off(self.enabled)),
+            "echo_length" => Some(Atom::UnSigned {
+                value: self.length as usize,
+                range: 20..501,
+            }),
+            _ => None,
+        }
+    }
+
+    fn set_setting(&mut self, key: String, value: Atom) {
+        match key.trim() {
+            "echo_cancellation" if value.valid() => {
+                if let Atom::StringValues { value, .. } = value {
+                    self.enabled = value == "On";
+                    self.reset();
+                }
+            }
+            "echo_length" if value.valid() => {
+                if let Atom::UnSigned { value, .. } = value {
+                    self.length = value as u32;
+                    self.reset();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn process(&mut self, block: &mut [f32], reference: &[f32]) {
+        let len = self.block;
+        let partitions = self.weights.len();
+
+        // Overlap save, the frame is the previous and the current playback block.
+        self.frame[..len].copy_from_slice(&self.previous);
+        self.frame[len..].copy_from_slice(reference);
+        self.previous.copy_from_slice(reference);
+        let mut spectrum = self
+            .history
+            .pop_back()
+            .unwrap_or_else(|| self.fft.spectrum());
+        self.fft.forward(&self.frame, &mut spectrum);
+        for (power, value) in self.power.iter_mut().zip(spectrum.iter()) {
+            *power = POWER_SMOOTHING * *power + (1.0 - POWER_SMOOTHING) * value.norm_sqr();
+        }
+        self.history.push_front(spectrum);
+        self.peaks.pop_back();
+        self.peaks.push_front(
+            reference
+                .iter()
+                .fold(0.0, |peak, sample| sample.abs().max(peak)),
+        );
+
+        self.estimate.fill(Complex::default());
+        for (weights, spectrum) in self.weights.iter().zip(self.history.iter()) {
+            for ((estimate, weight), value) in self
+                .estimate
+                .iter_mut()
+                .zip(weights.iter())
+                .zip(spectrum.iter())
+            {
+                *estimate += weight * value;
+            }
+        }
+        self.fft.inverse(&mut self.estimate, &mut self.frame);
+
+        let mut near = 0.0f32;
+        for (sample, echo) in block.iter_mut().zip(self.frame[len..].iter()) {
+            near = near.max(sample.abs());
+            *sample -= echo;
+        }
+
+        let far = self
+            .peaks
+            .iter()
+            .fold(0.0f32, |peak, value| peak.max(*value));
+        // Adapting while the near end talks would learn their voice as echo.
+        if far <= f32::EPSILON || near > DOUBLE_TALK * far {
+            return;
+        }
+
+        self.frame[..len].fill(0.0);
+        self.frame[len..].copy_from_slice(block);
+        self.fft.forward(&self.frame, &mut self.error);
+
+        let regularization = (len * 2) as f32 * 1e-6;
+        for (weights, spectrum) in self.weights.iter_mut().zip(self.history.iter()) {
+            for (bin, gradient) in self.gradient.iter_mut().enumerate() {
+                *gradient = spectrum[bin].conj() * self.error[bin] * STEP
+                    / (partitions as f32 * self.power[bin] + regularization);
+            }
+            // Keep the filter causal, only the first half of every partition is used.
+            self.fft.inverse(&mut self.gradient, &mut self.frame);
+            self.frame[len..].fill(0.0);
+            self.fft.forward(&self.frame, &mut self.gradient);
+            for (weight, gradient) in weights.iter_mut().zip(self.gradient.iter()) {
+                *weight += gradient;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{super::super::gate::level_db, *};
+
+    const RATE: u32 = 48000;
+    const BLOCK: usize = 480;
+
+    #[test]
+    fn converges_on_an_echo() {
+        let mut echo = EchoCanceller::new(RATE, BLOCK);
+        let mut rng = StdRng::seed_from_u64(1);
+        // The room returns the playback 5ms later at a third of the level.
+        let delay = RATE as usize / 200;
+        let mut played = vec![0.0; delay];
+        let (mut microphone, mut residual) = (Vec::new(), Vec::new());
+        for index in 0..300 {
+            let reference = (0..BLOCK)
+                .map(|_| rng.gen_range(-0.5..0.5))
+                .collect::<Vec<f32>>();
+            played.extend_from_slice(&reference);
+            let mut block = played
+                .drain(..BLOCK)
+                .map(|sample| sample / 3.0)
+                .collect::<Vec<f32>>();
+            let captured = block.clone();
+            echo.process(&mut block, &reference);
+            if index >= 250 {
+                microphone.extend(captured);
+                residual.extend(block);
+            }
+        }
+        let (microphone, residual) = (level_db(&microphone), level_db(&residual));
+        assert!(
+            residual < microphone - 20.0,
+            "{microphone}dB to {residual}dB"
+        );
+    }
+
+    #[test]
+    fn silent_playback_passes_the_microphone() {
+        let mut echo = EchoCanceller::new(RATE, BLOCK);
+        let reference = vec![0.0; BLOCK];
+        for _ in 0..10 {
+            let input = (0..BLOCK)
+                .map(|index| (index as f32 / 100.0).sin() * 0.3)
+                .collect::<Vec<f32>>();
+            let mut block = input.clone();
+            echo.process(&mut block, &reference);
+            assert_eq!(block, input);
+        }
+    }
+}
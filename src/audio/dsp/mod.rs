@@ -0,0 +1,212 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use realfft::{num_complex::Complex, ComplexToReal, RealFftPlanner, RealToComplex};
+use the_man::Atom;
+
+use self::{agc::Agc, echo::EchoCanceller, noise::NoiseSuppressor};
+
+pub mod agc;
+pub mod echo;
+pub mod noise;
+
+/// Every stage works on blocks of this length.
+const BLOCK_MS: u32 = 10;
+/// Playback that was not matched with captured audio after this is dropped.
+const MAX_REFERENCE_MS: u32 = 500;
+
+/// One step between capture and encode, working on a single channel.
+pub trait Stage: Sync + Send {
+    fn settings(&self) -> Vec<String>;
+    fn get_setting(&mut self, key: String) -> Option<Atom>;
+    fn set_setting(&mut self, key: String, value: Atom);
+
+    fn enabled(&self) -> bool;
+
+    /// Processes one block in place, `reference` is what was played at the same time.
+    fn process(&mut self, block: &mut [f32], reference: &[f32]);
+}
+
+/// Runs the captured audio through echo cancellation, noise suppression and
+/// gain control, every channel with its own stages.
+pub struct Dsp {
+    channels: Vec<Vec<Box<dyn Stage>>>,
+    block: usize,
+    max_reference: usize,
+    /// Interleaved input that does not fill a block yet.
+    input: Vec<f32>,
+    /// Mono playback at the same sample rate, not matched with input yet.
+    reference: VecDeque<f32>,
+    samples: Vec<f32>,
+    reference_block: Vec<f32>,
+}
+
+impl Dsp {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        let block = (sample_rate / 1000 * BLOCK_MS) as usize;
+        Self {
+            channels: (0..channels.max(1))
+                .map(|_| {
+                    vec![
+                        Box::new(EchoCanceller::new(sample_rate, block)) as Box<dyn Stage>,
+                        Box::new(NoiseSuppressor::new(sample_rate, block)),
+                        Box::new(Agc::new(sample_rate, block)),
+                    ]
+                })
+                .collect(),
+            block,
+            max_reference: (sample_rate / 1000 * MAX_REFERENCE_MS) as usize,
+            input: Vec::new(),
+            reference: VecDeque::new(),
+            samples: vec![0.0; block],
+            reference_block: vec![0.0; block],
+        }
+    }
+
+    pub fn settings(&self) -> Vec<String> {
+        self.channels[0]
+            .iter()
+            .flat_map(|stage| stage.settings())
+            .collect()
+    }
+
+    pub fn get_setting(&mut self, key: String) -> Option<Atom> {
+        self.channels[0]
+            .iter_mut()
+            .find_map(|stage| stage.get_setting(key.clone()))
+    }
+
+    pub fn set_setting(&mut self, key: String, value: Atom) {
+        for stages in self.channels.iter_mut() {
+            for stage in stages.iter_mut() {
+                if stage.settings().contains(&key) {
+                    stage.set_setting(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    /// Every setting with its current value.
+    pub fn values(&mut self) -> Vec<(String, Atom)> {
+        self.settings()
+            .into_iter()
+            .filter_map(|key| Some((key.clone(), self.get_setting(key)?)))
+            .collect()
+    }
+
+    fn is_active(&self) -> bool {
+        self.channels[0].iter().any(|stage| stage.enabled())
+    }
+
+    /// Adds mono playback, it is matched with the input that is processed next.
+    pub fn push_reference(&mut self, samples: &[f32]) {
+        self.reference.extend(samples);
+        if self.reference.len() > self.max_reference {
+            let excess = self.reference.len() - self.max_reference;
+            self.reference.drain(..excess);
+        }
+    }
+
+    /// Processes interleaved `input` into `output`, a block is held back until it is full.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if !self.is_active() {
+            output.append(&mut self.input);
+            output.extend_from_slice(input);
+            self.reference.clear();
+            return;
+        }
+
+        self.input.extend_from_slice(input);
+        let channels = self.channels.len();
+        let len = self.block * channels;
+        let mut start = 0;
+        while self.input.len() - start >= len {
+            let block = &self.input[start..start + len];
+            start += len;
+
+            // Silence when nothing is playing.
+            for sample in self.reference_block.iter_mut() {
+                *sample = self.reference.pop_front().unwrap_or(0.0);
+            }
+
+            let begin = output.len();
+            output.extend_from_slice(block);
+            for (channel, stages) in self.channels.iter_mut().enumerate() {
+                for (sample, input) in self
+                    .samples
+                    .iter_mut()
+                    .zip(block.iter().skip(channel).step_by(channels))
+                {
+                    *sample = *input;
+                }
+                for stage in stages.iter_mut().filter(|stage| stage.enabled()) {
+                    stage.process(&mut self.samples, &self.reference_block);
+                }
+                for (output, sample) in output[begin..]
+                    .iter_mut()
+                    .skip(channel)
+                    .step_by(channels)
+                    .zip(self.samples.iter())
+                {
+                    *output = *sample;
+                }
+            }
+        }
+        self.input.drain(..start);
+    }
+}
+
+/// Forward and inverse real FFT of one length.
+pub struct Fft {
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    time: Vec<f32>,
+    forward_scratch: Vec<Complex<f32>>,
+    inverse_scratch: Vec<Complex<f32>>,
+}
+
+impl Fft {
+    pub fn new(len: usize) -> Self {
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(len);
+        let inverse = planner.plan_fft_inverse(len);
+        Self {
+            time: forward.make_input_vec(),
+            forward_scratch: forward.make_scratch_vec(),
+            inverse_scratch: inverse.make_scratch_vec(),
+            forward,
+            inverse,
+        }
+    }
+
+    /// Bins of the spectrum, `len / 2 + 1`.
+    pub fn bins(&self) -> usize {
+        self.forward.complex_len()
+    }
+
+    pub fn spectrum(&self) -> Vec<Complex<f32>> {
+        self.forward.make_output_vec()
+    }
+
+    pub fn forward(&mut self, input: &[f32], output: &mut [Complex<f32>]) {
+        self.time.copy_from_slice(input);
+        let _ =
+            self.forward
+                .process_with_scratch(&mut self.time, output, &mut self.forward_scratch);
+    }
+
+    /// Inverse transform scaled back to the input level, `input` is overwritten.
+    pub fn inverse(&mut self, input: &mut [Complex<f32>], output: &mut [f32]) {
+        // A real signal has no imaginary part there, rounding errors would fail the transform.
+        input[0].im = 0.0;
+        if let Some(last) = input.last_mut() {
+            last.im = 0.0;
+        }
+        let _ = self
+            .inverse
+            .process_with_scratch(input, output, &mut self.inverse_scratch);
+        let scale = 1.0 / output.len() as f32;
+        for sample in output.iter_mut() {
+            *sample *= scale;
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+
+use nnnoiseless::DenoiseState;
+use the_man::Atom;
+
+use super::{super::convert::Resampler, Stage};
+
+/// The only rate the model runs at.
+const MODEL_RATE: u32 = 48000;
+/// The model takes samples in the range of an `i16`.
+const MODEL_SCALE: f32 = i16::MAX as f32;
+
+/// Noise suppression with RNNoise, a recurrent network that estimates
+/// a gain for every band from the features of the frame.
+///
+/// Other sample rates are converted to 48kHz and back around the model.
+/// The model delays by one frame, so the output is one block late,
+/// and one more at other sample rates.
+pub struct NoiseSuppressor {
+    enabled: bool,
+    /// Most the noise is attenuated, in dB.
+    reduction: u32,
+
+    denoise: Box<DenoiseState<'static>>,
+    upsampler: Resampler,
+    downsampler: Resampler,
+    /// Input at 48kHz that does not fill a frame yet.
+    input: Vec<f32>,
+    /// Input of the last frame, what the model output lines up with.
+    dry: Vec<f32>,
+    frame: Vec<f32>,
+    resampled: Vec<f32>,
+    /// Processed at the block rate, not taken yet.
+    output: VecDeque<f32>,
+}
+
+impl NoiseSuppressor {
+    pub fn new(sample_rate: u32, block: usize) -> Self {
+        let upsampler = Resampler::new(sample_rate, MODEL_RATE, 1);
+        // Converting does not give whole blocks, one block of slack keeps the output full.
+        let slack = if upsampler.is_passthrough() { 0 } else { block };
+        Self {
+            enabled: true,
+            reduction: 30,
+            denoise: DenoiseState::new(),
+            upsampler,
+            downsampler: Resampler::new(MODEL_RATE, sample_rate, 1),
+            input: Vec::new(),
+            dry: vec![0.0; DenoiseState::FRAME_SIZE],
+            frame: vec![0.0; DenoiseState::FRAME_SIZE],
+            resampled: Vec::new(),
+            output: std::iter::repeat_n(0.0, slack).collect(),
+        }
+    }
+}
+
+impl Stage for NoiseSuppressor {
+    fn settings(&self) -> Vec<String> {
+        vec!["noise_suppression".into(), "noise_reduction".into()]
+    }
+
+    fn get_setting(&mut self, key: String) -> Option<Atom> {
+        match key.trim() {
+            "noise_suppression" => Some(Atom::on_off(self.enabled)),
+            "noise_reduction" => Some(Atom::UnSigned {
+                value: self.reduction as usize,
+                range: 0..61,
+            }),
+            _ => None,
+        }
+    }
+
+    fn set_setting(&mut self, key: String, value: Atom) {
+        match key.trim() {
+            "noise_suppression" if value.valid() => {
+                if let Atom::StringValues { value, .. } = value {
+                    self.enabled = value == "On";
+                }
+            }
+            "noise_reduction" if value.valid() => {
+                if let Atom::UnSigned { value, .. } = value {
+                    self.reduction = value as u32;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn process(&mut self, block: &mut [f32], _reference: &[f32]) {
+        self.upsampler.process(block, &mut self.input);
+
+        // The model removes everything it takes for noise, some of the input
+        // is mixed back so the noise is attenuated by `reduction` at most.
+        let floor = 10f32.powf(-(self.reduction as f32) / 20.0);
+        let frames = self.input.len() / DenoiseState::FRAME_SIZE;
+        for input in self
+            .input
+            .chunks_exact_mut(DenoiseState::FRAME_SIZE)
+            .take(frames)
+        {
+            for sample in input.iter_mut() {
+                *sample *= MODEL_SCALE;
+            }
+            self.denoise.process_frame(&mut self.frame, input);
+            for (wet, dry) in self.frame.iter_mut().zip(self.dry.iter()) {
+                *wet = (*wet * (1.0 - floor) + dry * floor) / MODEL_SCALE;
+            }
+            self.dry.copy_from_slice(input);
+            self.resampled.clear();
+            self.downsampler.process(&self.frame, &mut self.resampled);
+            self.output.extend(self.resampled.iter());
+        }
+        self.input.drain(..frames * DenoiseState::FRAME_SIZE);
+
+        for sample in block.iter_mut() {
+            *sample = self.output.pop_front().unwrap_or(0.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{super::super::gate::level_db, *};
+
+    /// Runs `seconds` of white noise at about -35dBFS through, the level of the input and output of the last second.
+    fn suppress(suppressor: &mut NoiseSuppressor, rate: u32, seconds: usize) -> (f32, f32) {
+        let mut rng = StdRng::seed_from_u64(1);
+        let block = rate as usize / 100;
+        let (mut input, mut output) = (Vec::new(), Vec::new());
+        for index in 0..seconds * 100 {
+            let mut samples = (0..block)
+                .map(|_| rng.gen_range(-0.03..0.03))
+                .collect::<Vec<f32>>();
+            let dry = samples.clone();
+            suppressor.process(&mut samples, &[]);
+            assert_eq!(samples.len(), block);
+            if index >= (seconds - 1) * 100 {
+                input.extend(dry);
+                output.extend(samples);
+            }
+        }
+        (level_db(&input), level_db(&output))
+    }
+
+    #[test]
+    fn suppresses_white_noise() {
+        let mut suppressor = NoiseSuppressor::new(48000, 480);
+        let (input, output) = suppress(&mut suppressor, 48000, 5);
+        assert!(output < input - 20.0, "{input}dB to {output}dB");
+    }
+
+    #[test]
+    fn suppresses_at_other_rates() {
+        let mut suppressor = NoiseSuppressor::new(16000, 160);
+        let (input, output) = suppress(&mut suppressor, 16000, 5);
+        assert!(output < input - 20.0, "{input}dB to {output}dB");
+    }
+
+    #[test]
+    fn reduction_limits_the_attenuation() {
+        let mut suppressor = NoiseSuppressor::new(48000, 480);
+        suppressor.set_setting(
+            "noise_reduction".into(),
+            Atom::UnSigned {
+                value: 6,
+                range: 0..61,
+            },
+        );
+        let (input, output) = suppress(&mut suppressor, 48000, 5);
+        assert!(output > input - 7.0, "{input}dB to {output}dB");
+    }
+
+    #[test]
+    fn no_reduction_passes_through_one_block_late() {
+        let mut suppressor = NoiseSuppressor::new(48000, 480);
+        suppressor.set_setting(
+            "noise_reduction".into(),
+            Atom::UnSigned {
+                value: 0,
+                range: 0..61,
+            },
+        );
+        let mut first = (0..480)
+            .map(|index| (index as f32 / 480.0) - 0.5)
+            .collect::<Vec<f32>>();
+        let dry = first.clone();
+        suppressor.process(&mut first, &[]);
+        let mut second = vec![0.0; 480];
+        suppressor.process(&mut second, &[]);
+        for (dry, wet) in dry.iter().zip(second.iter()) {
+            assert!((dry - wet).abs() < 1e-4, "{dry} {wet}");
+        }
+    }
+}
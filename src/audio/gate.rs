@@ -0,0 +1,53 @@
+use crate::save_state::{CaptureMode, VoiceSettings};
+
+/// How long voice activity keeps the gate open after the level dropped,
+/// so the ends of words and short pauses are not cut off.
+const HANGOVER_MS: u32 = 300;
+
+/// Decides for every captured chunk if it is encoded and sent.
+#[derive(Default)]
+pub struct Gate {
+    /// Samples per channel the gate stays open without voice activity.
+    hangover: usize,
+}
+
+impl Gate {
+    pub fn is_open(
+        &mut self,
+        settings: &VoiceSettings,
+        push_to_talk: bool,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> bool {
+        if settings.muted || settings.deafened {
+            self.hangover = 0;
+            return false;
+        }
+
+        match settings.mode {
+            CaptureMode::AlwaysOn => true,
+            CaptureMode::PushToTalk => push_to_talk,
+            CaptureMode::VoiceActivity => {
+                if level_db(samples) >= settings.vad_threshold {
+                    self.hangover = (sample_rate / 1000 * HANGOVER_MS) as usize;
+                    return true;
+                }
+                let open = self.hangover > 0;
+                self.hangover = self
+                    .hangover
+                    .saturating_sub(samples.len() / channels.max(1));
+                open
+            }
+        }
+    }
+}
+
+/// RMS level of `samples` in dBFS, silence is `f32::NEG_INFINITY`.
+pub fn level_db(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    let power = samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32;
+    10.0 * power.log10()
+}
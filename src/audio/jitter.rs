@@ -0,0 +1,400 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::logic::message::VoiceStats;
+
+/// How much audio is buffered before playout starts.
+const TARGET_DELAY_MS: u32 = 60;
+/// When more than this is buffered the oldest frames are dropped to catch up.
+const MAX_DELAY_MS: u32 = 200;
+/// Used for concealment until two consecutive frames were seen.
+const DEFAULT_FRAME_MS: u32 = 20;
+/// A sequence further than this from the highest one means the sender restarted.
+const MAX_SEQUENCE_JUMP: i64 = 1000;
+
+struct Frame {
+    timestamp: u32,
+    data: Vec<u8>,
+}
+
+pub enum Playout {
+    Frame(Vec<u8>),
+    /// The frame was lost, `samples` per channel should be concealed.
+    /// `next` is the following frame when it already arrived.
+    Missing {
+        samples: usize,
+        next: Option<Vec<u8>>,
+    },
+    /// Nothing to play, the buffer is filling up to the target delay.
+    Empty,
+}
+
+/// Reorders voice packets from one peer and paces them out with a target delay.
+///
+/// Sequence numbers wrap, internally they are extended to `u64` relative to
+/// the highest sequence seen so far.
+pub struct JitterBuffer {
+    sample_rate: u32,
+    frames: BTreeMap<u64, Frame>,
+    highest: Option<u64>,
+    /// Last sequence that was played or skipped.
+    played: Option<u64>,
+    playing: bool,
+    /// Samples per channel of the last played frame.
+    frame_samples: u32,
+    last_timestamp: Option<u32>,
+    stats: Stats,
+}
+
+/// Counted across restarts of the sender.
+struct Stats {
+    received: u64,
+    lost: u64,
+    late: u64,
+    /// Received since `since`, for the bitrate.
+    bytes: usize,
+    since: Instant,
+    /// Arrival time minus timestamp of the last packet, in samples.
+    transit: Option<i64>,
+    /// In samples.
+    jitter: f64,
+    start: Instant,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            received: 0,
+            lost: 0,
+            late: 0,
+            bytes: 0,
+            since: Instant::now(),
+            transit: None,
+            jitter: 0.0,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl JitterBuffer {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            frames: BTreeMap::new(),
+            highest: None,
+            played: None,
+            playing: false,
+            frame_samples: sample_rate * DEFAULT_FRAME_MS / 1000,
+            last_timestamp: None,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Queues a frame, returns if the sender restarted and the buffer was reset.
+    pub fn push(&mut self, sequence: u32, timestamp: u32, data: Vec<u8>) -> bool {
+        self.push_at(sequence, timestamp, data, Instant::now())
+    }
+
+    fn push_at(&mut self, sequence: u32, timestamp: u32, data: Vec<u8>, now: Instant) -> bool {
+        let mut restarted = false;
+        if let Some(highest) = self.highest {
+            let diff = sequence.wrapping_sub(highest as u32) as i32 as i64;
+            if diff.abs() > MAX_SEQUENCE_JUMP {
+                log::debug!("Voice sequence jumped by {diff}, resetting jitter buffer");
+                let mut stats = std::mem::take(&mut self.stats);
+                stats.transit = None;
+                *self = Self::new(self.sample_rate);
+                self.stats = stats;
+                restarted = true;
+            }
+        }
+
+        self.stats.received += 1;
+        self.stats.bytes += data.len();
+        // Interarrival jitter as in RFC 3550.
+        let arrival = now.duration_since(self.stats.start).as_secs_f64() * self.sample_rate as f64;
+        let transit = arrival as i64 - timestamp as i64;
+        if let Some(last) = self.stats.transit {
+            let difference = (transit - last).unsigned_abs() as f64;
+            self.stats.jitter += (difference - self.stats.jitter) / 16.0;
+        }
+        self.stats.transit = Some(transit);
+
+        let sequence = match self.highest {
+            Some(highest) => {
+                let diff = sequence.wrapping_sub(highest as u32) as i32 as i64;
+                highest.saturating_add_signed(diff)
+            }
+            // Leave room below the first sequence for reordered packets.
+            None => sequence as u64 + (1 << 32),
+        };
+
+        if self.played.is_some_and(|played| sequence <= played) {
+            log::debug!("Dropping late voice frame: {sequence}");
+            self.stats.late += 1;
+            return restarted;
+        }
+
+        self.highest = Some(
+            self.highest
+                .map_or(sequence, |highest| highest.max(sequence)),
+        );
+        self.frames
+            .entry(sequence)
+            .or_insert(Frame { timestamp, data });
+
+        // The sender clock is faster than ours or a burst arrived, catch up.
+        while self.buffered() > self.ms_to_samples(MAX_DELAY_MS) {
+            let Some((sequence, frame)) = self.frames.pop_first() else {
+                break;
+            };
+            log::debug!("Jitter buffer over {MAX_DELAY_MS}ms, dropping: {sequence}");
+            self.stats.late += 1;
+            self.played = Some(sequence);
+            self.last_timestamp = Some(frame.timestamp);
+        }
+        restarted
+    }
+
+    pub fn pop(&mut self) -> Playout {
+        if !self.playing {
+            if self.frames.is_empty() || self.buffered() < self.ms_to_samples(TARGET_DELAY_MS) {
+                return Playout::Empty;
+            }
+            self.playing = true;
+        }
+
+        let next = match self.played {
+            Some(played) => played + 1,
+            None => *self.frames.keys().next().unwrap(),
+        };
+
+        if let Some(frame) = self.frames.remove(&next) {
+            if let Some(last) = self.last_timestamp {
+                let samples = frame.timestamp.wrapping_sub(last);
+                if samples > 0 && samples <= self.ms_to_samples(MAX_DELAY_MS) {
+                    self.frame_samples = samples;
+                }
+            }
+            self.last_timestamp = Some(frame.timestamp);
+            self.played = Some(next);
+            return Playout::Frame(frame.data);
+        }
+
+        if self.frames.is_empty() {
+            // Underrun, wait for the target delay again before resuming.
+            self.playing = false;
+            return Playout::Empty;
+        }
+
+        self.played = Some(next);
+        self.last_timestamp = self
+            .last_timestamp
+            .map(|timestamp| timestamp.wrapping_add(self.frame_samples));
+        self.stats.lost += 1;
+        Playout::Missing {
+            samples: self.frame_samples as usize,
+            next: self.frames.get(&(next + 1)).map(|frame| frame.data.clone()),
+        }
+    }
+
+    /// Counters since the first packet, the bitrate since the last call.
+    pub fn stats(&mut self) -> VoiceStats {
+        let elapsed = self.stats.since.elapsed().as_secs_f64();
+        let bitrate = if elapsed > 0.0 {
+            (self.stats.bytes as f64 * 8.0 / elapsed) as u32
+        } else {
+            0
+        };
+        self.stats.bytes = 0;
+        self.stats.since = Instant::now();
+        VoiceStats {
+            received: self.stats.received,
+            lost: self.stats.lost,
+            late: self.stats.late,
+            jitter: Duration::from_secs_f64(self.stats.jitter / self.sample_rate.max(1) as f64),
+            bitrate,
+            buffered: Duration::from_secs_f64(
+                self.buffered() as f64 / self.sample_rate.max(1) as f64,
+            ),
+            delay: None,
+        }
+    }
+
+    /// Samples per channel of the last played frame.
+    pub fn frame_samples(&self) -> usize {
+        self.frame_samples as usize
+    }
+
+    /// Samples per channel between the next frame to play and the end of the newest one.
+    fn buffered(&self) -> u32 {
+        let (Some((_, first)), Some((_, last))) =
+            (self.frames.first_key_value(), self.frames.last_key_value())
+        else {
+            return 0;
+        };
+        // While waiting for the target delay the sender may have paused,
+        // only what is in the buffer counts then.
+        let start = match self.last_timestamp {
+            Some(timestamp) if self.playing => timestamp.wrapping_add(self.frame_samples),
+            _ => first.timestamp,
+        };
+        let span = last.timestamp.wrapping_sub(start) as i32;
+        (span.max(0) as u32).saturating_add(self.frame_samples)
+    }
+
+    fn ms_to_samples(&self, ms: u32) -> u32 {
+        self.sample_rate / 1000 * ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+    /// 20ms at `RATE`.
+    const FRAME: u32 = 960;
+
+    fn frame(buffer: &mut JitterBuffer, sequence: u32) -> bool {
+        buffer.push(
+            sequence,
+            sequence.wrapping_mul(FRAME),
+            sequence.to_le_bytes().to_vec(),
+        )
+    }
+
+    fn played(buffer: &mut JitterBuffer) -> Option<u32> {
+        match buffer.pop() {
+            Playout::Frame(data) => Some(u32::from_le_bytes(data.try_into().unwrap())),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn waits_for_the_target_delay() {
+        let mut buffer = JitterBuffer::new(RATE);
+        frame(&mut buffer, 0);
+        frame(&mut buffer, 1);
+        assert!(matches!(buffer.pop(), Playout::Empty));
+        frame(&mut buffer, 2);
+        assert_eq!(played(&mut buffer), Some(0));
+    }
+
+    #[test]
+    fn reorders() {
+        let mut buffer = JitterBuffer::new(RATE);
+        for sequence in [2, 0, 3, 1] {
+            frame(&mut buffer, sequence);
+        }
+        for sequence in 0..4 {
+            assert_eq!(played(&mut buffer), Some(sequence));
+        }
+        assert!(matches!(buffer.pop(), Playout::Empty));
+    }
+
+    #[test]
+    fn plays_duplicates_once() {
+        let mut buffer = JitterBuffer::new(RATE);
+        for sequence in [0, 1, 1, 2, 0] {
+            frame(&mut buffer, sequence);
+        }
+        for sequence in 0..3 {
+            assert_eq!(played(&mut buffer), Some(sequence));
+        }
+        assert!(matches!(buffer.pop(), Playout::Empty));
+    }
+
+    #[test]
+    fn drops_late_frames() {
+        let mut buffer = JitterBuffer::new(RATE);
+        for sequence in 0..3 {
+            frame(&mut buffer, sequence);
+        }
+        assert_eq!(played(&mut buffer), Some(0));
+        assert_eq!(played(&mut buffer), Some(1));
+        frame(&mut buffer, 0);
+        frame(&mut buffer, 1);
+        assert_eq!(buffer.stats().late, 2);
+        assert_eq!(played(&mut buffer), Some(2));
+    }
+
+    #[test]
+    fn conceals_a_lost_frame() {
+        let mut buffer = JitterBuffer::new(RATE);
+        for sequence in [0, 1, 3, 4] {
+            frame(&mut buffer, sequence);
+        }
+        assert_eq!(played(&mut buffer), Some(0));
+        assert_eq!(played(&mut buffer), Some(1));
+        match buffer.pop() {
+            Playout::Missing { samples, next } => {
+                assert_eq!(samples, FRAME as usize);
+                assert_eq!(next, Some(3u32.to_le_bytes().to_vec()));
+            }
+            _ => panic!("frame 2 should be missing"),
+        }
+        assert_eq!(played(&mut buffer), Some(3));
+        assert_eq!(buffer.stats().lost, 1);
+    }
+
+    #[test]
+    fn drops_the_oldest_over_the_max_delay() {
+        let mut buffer = JitterBuffer::new(RATE);
+        let frames = MAX_DELAY_MS / 20 + 5;
+        for sequence in 0..frames {
+            frame(&mut buffer, sequence);
+        }
+        assert!(buffer.buffered() <= buffer.ms_to_samples(MAX_DELAY_MS));
+        assert_eq!(buffer.stats().late, 5);
+        assert_eq!(played(&mut buffer), Some(5));
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let mut buffer = JitterBuffer::new(RATE);
+        let first = u32::MAX - 1;
+        for sequence in [first, 0, u32::MAX, 1] {
+            assert!(!frame(&mut buffer, sequence));
+        }
+        for sequence in [first, u32::MAX, 0, 1] {
+            assert_eq!(played(&mut buffer), Some(sequence));
+        }
+    }
+
+    #[test]
+    fn resets_when_the_sender_restarts() {
+        let mut buffer = JitterBuffer::new(RATE);
+        for sequence in 0..3 {
+            frame(&mut buffer, sequence);
+        }
+        assert_eq!(played(&mut buffer), Some(0));
+        assert!(frame(&mut buffer, 50_000));
+        assert!(matches!(buffer.pop(), Playout::Empty));
+        assert_eq!(buffer.stats().received, 4);
+    }
+
+    #[test]
+    fn jitter_follows_rfc_3550() {
+        let mut buffer = JitterBuffer::new(RATE);
+        let start = buffer.stats.start;
+        let mut push = |sequence: u32, late_ms: u64| {
+            let arrival = start + Duration::from_millis(20 * sequence as u64 + late_ms);
+            buffer.push_at(sequence, sequence * FRAME, Vec::new(), arrival);
+            buffer.stats.jitter
+        };
+        // Constant transit time is no jitter.
+        for sequence in 0..10 {
+            assert!(push(sequence, 5) < 1.0);
+        }
+        // 10ms is 480 samples, the estimate moves a 16th of the way each packet.
+        let jitter = push(10, 15);
+        assert!((jitter - 30.0).abs() < 1.0, "{jitter}");
+        let jitter = push(11, 5);
+        assert!((jitter - 58.125).abs() < 1.0, "{jitter}");
+        let jitter = push(12, 5);
+        assert!((jitter - 54.5).abs() < 1.0, "{jitter}");
+    }
+}
@@ -0,0 +1,35 @@
+use crate::logic::message::Level;
+
+/// Collects the level of everything that passed through since it was last read.
+#[derive(Default)]
+pub struct Meter {
+    sum: f32,
+    count: usize,
+    peak: f32,
+}
+
+impl Meter {
+    pub fn push(&mut self, sample: f32) {
+        self.sum += sample * sample;
+        self.count += 1;
+        self.peak = self.peak.max(sample.abs());
+    }
+
+    pub fn extend(&mut self, samples: &[f32]) {
+        for sample in samples.iter() {
+            self.push(*sample);
+        }
+    }
+
+    /// Level since the last call, silence if nothing was pushed.
+    pub fn take(&mut self) -> Level {
+        let meter = std::mem::take(self);
+        if meter.count == 0 {
+            return Level::default();
+        }
+        Level {
+            rms: 10.0 * (meter.sum / meter.count as f32).log10(),
+            peak: 20.0 * meter.peak.log10(),
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use the_man::network::packet::CodecInfo;
+
+use super::{
+    codec::Codec,
+    convert::{remix, Resampler},
+    jitter::{JitterBuffer, Playout},
+    meter::Meter,
+    ringer::Ringer,
+};
+
+/// Where soft clipping starts, below this the mix is passed through.
+const CLIP_KNEE: f32 = 0.8;
+
+/// One remote peer, decoded and converted to the output device format.
+pub struct Source {
+    pub id: usize,
+    pub volume: f32,
+    pub muted: bool,
+    /// From `-1.0` left to `1.0` right, only applies to stereo and up.
+    pub pan: f32,
+    pub jitter: JitterBuffer,
+    /// Level of what the source adds to the mix.
+    pub meter: Meter,
+    /// What the source played before volume and pan, while the mixer is recording.
+    pub recorded: Vec<f32>,
+    codec: Box<dyn Codec>,
+    decoded: CodecInfo,
+    resampler: Resampler,
+    remixed: Vec<f32>,
+    buffer: Vec<f32>,
+}
+
+impl Source {
+    pub fn new(
+        id: usize,
+        codec: Box<dyn Codec>,
+        decoded: CodecInfo,
+        device: &cpal::StreamConfig,
+    ) -> Self {
+        Self {
+            id,
+            volume: 1.0,
+            muted: false,
+            pan: 0.0,
+            jitter: JitterBuffer::new(decoded.sample_rate),
+            meter: Meter::default(),
+            recorded: Vec::new(),
+            codec,
+            resampler: Resampler::new(
+                decoded.sample_rate,
+                device.sample_rate.0,
+                device.channels as usize,
+            ),
+            decoded,
+            remixed: Vec::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Converts to a new output device, the decoder and jitter buffer are kept.
+    pub fn set_device(&mut self, device: &cpal::StreamConfig) {
+        self.resampler = Resampler::new(
+            self.decoded.sample_rate,
+            device.sample_rate.0,
+            device.channels as usize,
+        );
+        self.buffer.clear();
+    }
+
+    /// Queues a packet, a sender that restarted also restarts the decoder.
+    pub fn push(&mut self, sequence: u32, timestamp: u32, data: Vec<u8>) {
+        if self.jitter.push(sequence, timestamp, data) {
+            self.codec.reset();
+        }
+    }
+
+    /// Gain of every device channel, pan only moves between the first two.
+    fn gains(&self, device_channels: usize) -> Vec<f32> {
+        let pan = self.pan.clamp(-1.0, 1.0);
+        let mut gains = vec![self.volume; device_channels.max(1)];
+        if device_channels >= 2 {
+            gains[0] *= (1.0 - pan).min(1.0);
+            gains[1] *= (1.0 + pan).min(1.0);
+        }
+        gains
+    }
+
+    /// Decodes until `len` device samples are buffered or the jitter buffer runs dry.
+    fn fill(&mut self, len: usize, device_channels: usize) {
+        while self.buffer.len() < len {
+            let decoded = match self.jitter.pop() {
+                // A packet that cannot be decoded is concealed like a lost one.
+                Playout::Frame(data) => self.codec.decode(&data).or_else(|error| {
+                    log::warn!("Cannot decode for: {}, {error}", self.id);
+                    self.codec.decode_lost(self.jitter.frame_samples(), None)
+                }),
+                Playout::Missing { samples, next } => {
+                    self.codec.decode_lost(samples, next.as_deref())
+                }
+                Playout::Empty => break,
+            };
+            let decoded = match decoded {
+                Ok(decoded) => decoded,
+                Err(error) => {
+                    log::warn!("Cannot conceal for: {}, {error}", self.id);
+                    continue;
+                }
+            };
+            self.remixed.clear();
+            remix(
+                &decoded,
+                self.decoded.channels as usize,
+                device_channels,
+                &mut self.remixed,
+            );
+            self.resampler.process(&self.remixed, &mut self.buffer);
+        }
+    }
+}
+
+/// Sums every [`Source`] into the one output stream of the device.
+#[derive(Default)]
+pub struct Mixer {
+    pub sources: Vec<Source>,
+    /// Sources are still decoded to keep their jitter buffers current, but not played.
+    pub deafened: bool,
+    /// Every source keeps what it played in `recorded`.
+    pub recording: bool,
+    /// Rings over the sources while a call waits for an answer.
+    pub ringer: Option<Ringer>,
+}
+
+impl Mixer {
+    pub fn source_mut(&mut self, id: usize) -> Option<&mut Source> {
+        self.sources.iter_mut().find(|source| source.id == id)
+    }
+
+    pub fn mix(&mut self, output: &mut [f32], device_channels: usize) {
+        output.fill(0.0);
+
+        for source in self.sources.iter_mut() {
+            source.fill(output.len(), device_channels);
+            // A source that underruns is silent for the whole callback,
+            // so it does not play out partial frames.
+            let len = output.len();
+            if source.buffer.len() < len {
+                if self.recording {
+                    source.recorded.resize(source.recorded.len() + len, 0.0);
+                }
+                continue;
+            }
+            if self.recording {
+                source.recorded.extend_from_slice(&source.buffer[..len]);
+            }
+            if source.muted {
+                source.buffer.drain(..len);
+                continue;
+            }
+            let gains = source.gains(device_channels);
+            for (index, (out, sample)) in output
+                .iter_mut()
+                .zip(source.buffer.drain(..len))
+                .enumerate()
+            {
+                let sample = sample * gains[index % gains.len()];
+                source.meter.push(sample);
+                *out += sample;
+            }
+        }
+
+        if self.deafened {
+            output.fill(0.0);
+        }
+
+        // Added after deafening, so a deafened user still hears a call come in.
+        if let Some(ringer) = &mut self.ringer {
+            ringer.add(output, device_channels);
+        }
+
+        for sample in output.iter_mut() {
+            *sample = soft_clip(*sample);
+        }
+    }
+}
+
+/// Passes the signal through below [`CLIP_KNEE`] and bends it smoothly towards `1.0` above.
+fn soft_clip(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= CLIP_KNEE {
+        return sample;
+    }
+    let headroom = 1.0 - CLIP_KNEE;
+    let clipped = CLIP_KNEE + headroom * ((magnitude - CLIP_KNEE) / headroom).tanh();
+    clipped.copysign(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::codec::pcm::{CodecPcm, PcmFormat},
+        *,
+    };
+
+    /// A stereo source with `samples` already decoded.
+    fn source(id: usize, samples: &[f32]) -> Source {
+        let device = cpal::StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48000),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let decoded = CodecInfo {
+            name: "pcm".into(),
+            sample_rate: 48000,
+            channels: 2,
+        };
+        let mut source = Source::new(
+            id,
+            Box::new(CodecPcm::new(PcmFormat::F32)),
+            decoded,
+            &device,
+        );
+        source.buffer.extend_from_slice(samples);
+        source
+    }
+
+    #[test]
+    fn sums_the_sources() {
+        let mut mixer = Mixer {
+            sources: vec![source(0, &[0.1, 0.2, 0.3, -0.4]), source(1, &[0.2; 4])],
+            ..Default::default()
+        };
+        let mut output = vec![0.0; 4];
+        mixer.mix(&mut output, 2);
+        for (sample, expected) in output.iter().zip([0.3, 0.4, 0.5, -0.2]) {
+            assert!((sample - expected).abs() < 1e-6, "{sample} {expected}");
+        }
+    }
+
+    #[test]
+    fn volume_scales_the_source() {
+        let mut quiet = source(0, &[0.4; 4]);
+        quiet.volume = 0.5;
+        let mut mixer = Mixer {
+            sources: vec![quiet],
+            ..Default::default()
+        };
+        let mut output = vec![0.0; 4];
+        mixer.mix(&mut output, 2);
+        assert!(output.iter().all(|sample| (sample - 0.2).abs() < 1e-6));
+    }
+
+    #[test]
+    fn underrun_is_silent() {
+        let mut mixer = Mixer {
+            sources: vec![source(0, &[0.5; 2]), source(1, &[0.1; 4])],
+            ..Default::default()
+        };
+        let mut output = vec![0.0; 4];
+        mixer.mix(&mut output, 2);
+        assert!(output.iter().all(|sample| (sample - 0.1).abs() < 1e-6));
+        // Kept for the next callback, not played in part.
+        assert_eq!(mixer.sources[0].buffer.len(), 2);
+    }
+
+    #[test]
+    fn pan_moves_between_left_and_right() {
+        let mut source = source(0, &[]);
+        source.volume = 0.5;
+        source.pan = -1.0;
+        assert_eq!(source.gains(2), [0.5, 0.0]);
+        source.pan = 1.0;
+        assert_eq!(source.gains(2), [0.0, 0.5]);
+        // Only the first two channels are panned.
+        assert_eq!(source.gains(4), [0.0, 0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn mono_ignores_pan() {
+        let mut source = source(0, &[]);
+        for pan in [-1.0, 0.0, 1.0] {
+            source.pan = pan;
+            assert_eq!(source.gains(1), [1.0]);
+        }
+    }
+
+    #[test]
+    fn muted_source_drains_its_buffer() {
+        let mut muted = source(0, &[0.5; 8]);
+        muted.muted = true;
+        let mut mixer = Mixer {
+            sources: vec![muted],
+            ..Default::default()
+        };
+        let mut output = vec![1.0; 4];
+        mixer.mix(&mut output, 2);
+        assert!(output.iter().all(|sample| *sample == 0.0));
+        assert_eq!(mixer.sources[0].buffer.len(), 4);
+    }
+
+    #[test]
+    fn soft_clip_is_continuous_and_bounded() {
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert_eq!(soft_clip(-CLIP_KNEE), -CLIP_KNEE);
+        let mut last = 0.0;
+        for step in 1..=2000 {
+            let sample = step as f32 / 1000.0;
+            let clipped = soft_clip(sample);
+            assert!(clipped < 1.0, "{sample} {clipped}");
+            assert!(clipped >= last, "{sample} {clipped}");
+            assert!(clipped - last < 0.0011, "{sample} {clipped}");
+            assert_eq!(soft_clip(-sample), -clipped);
+            last = clipped;
+        }
+        // Far past full scale `tanh` rounds to one, but never over.
+        for sample in [10.0, 1000.0, f32::MAX] {
+            assert!(soft_clip(sample) <= 1.0, "{sample}");
+        }
+    }
+
+    #[test]
+    fn deafened_still_rings() {
+        let mut mixer = Mixer {
+            deafened: true,
+            ..Default::default()
+        };
+        let mut output = vec![1.0; 4800];
+        mixer.mix(&mut output, 2);
+        assert!(output.iter().all(|sample| *sample == 0.0));
+
+        mixer.ringer = Some(Ringer::new(48000));
+        mixer.mix(&mut output, 2);
+        assert!(output.iter().any(|sample| sample.abs() > 0.01));
+    }
+}
@@ -1,28 +1,255 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
-use crate::{audio::codec::opus::CodecOpus, logic::message::AudioMessage, Message};
+use crate::{
+    logic::message::{AudioDevices, AudioMessage, Level, PlaybackMode},
+    save_state::{AudioSettings, VoiceSettings},
+    Message,
+};
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    InputCallbackInfo, OutputCallbackInfo, SizedSample, StreamError,
+    FromSample, InputCallbackInfo, OutputCallbackInfo, SampleFormat, SizedSample, StreamError,
 };
-use the_man::Atom;
+use the_man::{network::packet::CodecInfo, Atom};
 use tokio::sync::mpsc::{Receiver, Sender};
 
-use self::codec::Codec;
+use self::{
+    codec::{Codec, CodecRegistry},
+    convert::{remix, Resampler},
+    dsp::Dsp,
+    gate::Gate,
+    meter::Meter,
+    mixer::{Mixer, Source},
+    player::{Player, MEDIA_CHANNELS, MEDIA_SAMPLE_RATE},
+    recorder::Recording,
+    ringer::Ringer,
+    virtual_backend::{VirtualDevice, VirtualInput, VirtualOutput, VirtualStream, VIRTUAL_HOST},
+};
 
 mod codec;
+mod convert;
+mod dsp;
+mod gate;
+mod jitter;
+mod meter;
+mod mixer;
+mod player;
+mod recorder;
+mod ringer;
+pub mod virtual_backend;
+
+/// Where a [`Device`] captures from and plays to.
+pub enum Backend {
+    Cpal(cpal::Device),
+    /// Files and generators, for running without sound hardware.
+    Virtual(VirtualDevice),
+}
 
 pub struct Device {
-    pub device: cpal::Device,
+    pub backend: Backend,
     pub supported_config: cpal::SupportedStreamConfig,
     pub config: cpal::StreamConfig,
 }
 
+/// A running device stream, it stops when dropped.
+pub enum DeviceStream {
+    Cpal(cpal::Stream),
+    Virtual(VirtualStream),
+}
+
+impl DeviceStream {
+    pub fn play(&self) -> Result<(), String> {
+        match self {
+            DeviceStream::Cpal(stream) => stream.play().map_err(|error| error.to_string()),
+            // Virtual streams run from the start.
+            DeviceStream::Virtual(_) => Ok(()),
+        }
+    }
+}
+
+/// Preferred because the codecs run at it, so nothing has to be resampled.
+const PREFERRED_SAMPLE_RATE: cpal::SampleRate = cpal::SampleRate(48000);
+
 impl Device {
+    /// Uses `default` unless the device can do the same at [`PREFERRED_SAMPLE_RATE`].
+    pub fn new(
+        device: cpal::Device,
+        default: cpal::SupportedStreamConfig,
+        mut configs: impl Iterator<Item = cpal::SupportedStreamConfigRange>,
+    ) -> Self {
+        let supported_config = configs
+            .find(|config| {
+                config.channels() == default.channels()
+                    && config.sample_format() == default.sample_format()
+                    && config.min_sample_rate() <= PREFERRED_SAMPLE_RATE
+                    && config.max_sample_rate() >= PREFERRED_SAMPLE_RATE
+            })
+            .map(|config| config.with_sample_rate(PREFERRED_SAMPLE_RATE))
+            .unwrap_or(default);
+
+        Self {
+            backend: Backend::Cpal(device),
+            config: supported_config.config(),
+            supported_config,
+        }
+    }
+
+    /// Runs at the rate and channels of the virtual device, always as `f32`.
+    pub fn new_virtual(device: VirtualDevice) -> Result<Self, String> {
+        let (sample_rate, channels) = device.config()?;
+        let supported_config = cpal::SupportedStreamConfig::new(
+            channels,
+            cpal::SampleRate(sample_rate),
+            cpal::SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        );
+        Ok(Self {
+            backend: Backend::Virtual(device),
+            config: supported_config.config(),
+            supported_config,
+        })
+    }
+
+    pub fn name(&self) -> Option<String> {
+        match &self.backend {
+            Backend::Cpal(device) => device.name().ok(),
+            Backend::Virtual(device) => Some(device.name()),
+        }
+    }
+
+    /// Captures into `producer` as `f32`, whatever the device sample format is.
+    pub fn open_input_stream<E>(
+        &mut self,
+        producer: rtrb::Producer<f32>,
+        error_callback: E,
+    ) -> Result<DeviceStream, String>
+    where
+        E: FnMut(StreamError) + Send + 'static,
+    {
+        if let Backend::Virtual(device) = &self.backend {
+            return device
+                .open_input(
+                    producer,
+                    self.config.sample_rate.0,
+                    self.config.channels as usize,
+                )
+                .map(DeviceStream::Virtual);
+        }
+        let stream = match self.supported_config.sample_format() {
+            SampleFormat::I8 => self.input_stream::<i8, E>(producer, error_callback),
+            SampleFormat::I16 => self.input_stream::<i16, E>(producer, error_callback),
+            SampleFormat::I32 => self.input_stream::<i32, E>(producer, error_callback),
+            SampleFormat::I64 => self.input_stream::<i64, E>(producer, error_callback),
+            SampleFormat::U8 => self.input_stream::<u8, E>(producer, error_callback),
+            SampleFormat::U16 => self.input_stream::<u16, E>(producer, error_callback),
+            SampleFormat::U32 => self.input_stream::<u32, E>(producer, error_callback),
+            SampleFormat::U64 => self.input_stream::<u64, E>(producer, error_callback),
+            SampleFormat::F32 => self.input_stream::<f32, E>(producer, error_callback),
+            SampleFormat::F64 => self.input_stream::<f64, E>(producer, error_callback),
+            _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+        };
+        stream
+            .map(DeviceStream::Cpal)
+            .map_err(|error| error.to_string())
+    }
+
+    fn input_stream<T, E>(
+        &mut self,
+        mut producer: rtrb::Producer<f32>,
+        error_callback: E,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError>
+    where
+        T: SizedSample,
+        f32: FromSample<T>,
+        E: FnMut(StreamError) + Send + 'static,
+    {
+        let channels = self.config.channels as usize;
+        self.build_input_stream(
+            move |input: &[T], _| {
+                // Only whole frames, a partial one would shift the channels of everything after it.
+                let len = input.len().min(producer.slots() / channels * channels);
+                if let Ok(chunk) = producer.write_chunk_uninit(len) {
+                    chunk.fill_from_iter(input.iter().map(|sample| sample.to_sample::<f32>()));
+                }
+            },
+            error_callback,
+            None,
+        )
+    }
+
+    /// Plays what is in `consumer`, converted to the device sample format.
+    pub fn open_output_stream<E>(
+        &mut self,
+        consumer: rtrb::Consumer<f32>,
+        mut error_callback: E,
+    ) -> Result<DeviceStream, String>
+    where
+        E: FnMut(StreamError) + Send + 'static,
+    {
+        if let Backend::Virtual(device) = &self.backend {
+            return device
+                .open_output(
+                    consumer,
+                    self.config.sample_rate.0,
+                    self.config.channels as usize,
+                    move |description| {
+                        error_callback(StreamError::BackendSpecific {
+                            err: cpal::BackendSpecificError { description },
+                        })
+                    },
+                )
+                .map(DeviceStream::Virtual);
+        }
+        let stream = match self.supported_config.sample_format() {
+            SampleFormat::I8 => self.output_stream::<i8, E>(consumer, error_callback),
+            SampleFormat::I16 => self.output_stream::<i16, E>(consumer, error_callback),
+            SampleFormat::I32 => self.output_stream::<i32, E>(consumer, error_callback),
+            SampleFormat::I64 => self.output_stream::<i64, E>(consumer, error_callback),
+            SampleFormat::U8 => self.output_stream::<u8, E>(consumer, error_callback),
+            SampleFormat::U16 => self.output_stream::<u16, E>(consumer, error_callback),
+            SampleFormat::U32 => self.output_stream::<u32, E>(consumer, error_callback),
+            SampleFormat::U64 => self.output_stream::<u64, E>(consumer, error_callback),
+            SampleFormat::F32 => self.output_stream::<f32, E>(consumer, error_callback),
+            SampleFormat::F64 => self.output_stream::<f64, E>(consumer, error_callback),
+            _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+        };
+        stream
+            .map(DeviceStream::Cpal)
+            .map_err(|error| error.to_string())
+    }
+
+    fn output_stream<T, E>(
+        &mut self,
+        mut consumer: rtrb::Consumer<f32>,
+        error_callback: E,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError>
+    where
+        T: SizedSample + FromSample<f32>,
+        E: FnMut(StreamError) + Send + 'static,
+    {
+        self.build_output_stream(
+            move |output: &mut [T], _| {
+                let len = output.len().min(consumer.slots());
+                if let Ok(chunk) = consumer.read_chunk(len) {
+                    let (first, second) = chunk.as_slices();
+                    for (out, sample) in output.iter_mut().zip(first.iter().chain(second)) {
+                        *out = T::from_sample(*sample);
+                    }
+                    chunk.commit_all();
+                }
+                output[len..].fill(T::EQUILIBRIUM);
+            },
+            error_callback,
+            None,
+        )
+    }
+
     pub fn build_input_stream<T, D, E>(
         &mut self,
         data_callback: D,
@@ -34,8 +261,12 @@ impl Device {
         D: FnMut(&[T], &InputCallbackInfo) + Send + 'static,
         E: FnMut(StreamError) + Send + 'static,
     {
-        self.device
-            .build_input_stream(&self.config, data_callback, error_callback, timeout)
+        match &self.backend {
+            Backend::Cpal(device) => {
+                device.build_input_stream(&self.config, data_callback, error_callback, timeout)
+            }
+            Backend::Virtual(_) => Err(cpal::BuildStreamError::DeviceNotAvailable),
+        }
     }
 
     pub fn build_output_stream<T, D, E>(
@@ -49,42 +280,275 @@ impl Device {
         D: FnMut(&mut [T], &OutputCallbackInfo) + Send + 'static,
         E: FnMut(StreamError) + Send + 'static,
     {
-        self.device
-            .build_output_stream(&self.config, data_callback, error_callback, timeout)
+        match &self.backend {
+            Backend::Cpal(device) => {
+                device.build_output_stream(&self.config, data_callback, error_callback, timeout)
+            }
+            Backend::Virtual(_) => Err(cpal::BuildStreamError::DeviceNotAvailable),
+        }
     }
 }
 
+/// How often the codecs and the mixer run, outside of the device callbacks.
+const PROCESS_INTERVAL: Duration = Duration::from_millis(5);
+/// How often levels are reported.
+pub const LEVELS_INTERVAL: Duration = Duration::from_millis(100);
+/// How often receive statistics are sent to logic.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// Size of the ring buffers between the device callbacks and `Audio`.
+const RING_MS: usize = 500;
+/// Mixed audio kept queued for the output callback.
+const OUTPUT_QUEUE_MS: usize = 40;
+/// Mixed at once while filling the output queue.
+const MIX_CHUNK_MS: usize = 10;
+
+fn ms_to_samples(config: &cpal::StreamConfig, ms: usize) -> usize {
+    config.sample_rate.0 as usize * config.channels as usize * ms / 1000
+}
+
+/// Most of the played files an input stream keeps queued.
+const MAX_MEDIA_MS: usize = 200;
+
+/// An input channel, the device callback only copies samples into `consumer`.
 pub struct Stream {
-    pub codec: Option<Box<dyn Codec>>,
-    pub stream: Option<cpal::Stream>,
+    pub codec: Box<dyn Codec>,
+    pub stream: Option<DeviceStream>,
     pub volume: f32,
-    pub stream_type: StreamType,
     pub id: usize,
-    pub buffer: Vec<u8>,
+    pub device_channels: usize,
+    /// Channels and sample rate the codec encodes.
+    pub channels: usize,
+    pub sample_rate: u32,
+    /// From the device sample rate to the codec one.
+    pub resampler: Resampler,
+    pub captured: Vec<f32>,
+    pub remixed: Vec<f32>,
+    pub resampled: Vec<f32>,
+    pub dsp: Dsp,
+    pub processed: Vec<f32>,
+    /// From what the output device plays to mono at the codec sample rate.
+    pub reference_resampler: Resampler,
+    pub reference: Vec<f32>,
+    pub gate: Gate,
+    /// Level after processing, before the gate.
+    pub meter: Meter,
+    /// The gate was open since the levels were last sent.
+    pub transmitting: bool,
+    /// Keeps what was sent in `recorded`, silence while the gate is closed.
+    pub recording: bool,
+    pub recorded: Vec<f32>,
+    /// Played files at the codec sample rate and channels, sent past the gate.
+    pub media: VecDeque<f32>,
+    pub media_resampler: Resampler,
+    /// Sequence and timestamp of the next encoded input packet.
+    pub sequence: u32,
+    pub timestamp: u32,
+    pub consumer: rtrb::Consumer<f32>,
     pub input_buffer: Vec<f32>,
-    pub output_buffer: Vec<f32>,
-    pub sender: Sender<Message>,
 }
 
 unsafe impl Send for Stream {}
 unsafe impl Sync for Stream {}
 
-pub enum StreamType {
-    Input,
-    Output,
+impl Stream {
+    /// Encodes what the device captured since the last call.
+    fn process(
+        &mut self,
+        sender: &Sender<Message>,
+        voice: &VoiceSettings,
+        push_to_talk: bool,
+        replace_microphone: bool,
+    ) {
+        let slots = self.consumer.slots();
+        if let Ok(chunk) = self.consumer.read_chunk(slots) {
+            let (first, second) = chunk.as_slices();
+            let volume = self.volume;
+            self.captured.clear();
+            self.captured
+                .extend(first.iter().chain(second).map(|sample| sample * volume));
+            chunk.commit_all();
+        }
+
+        self.remixed.clear();
+        remix(
+            &self.captured,
+            self.device_channels,
+            self.channels,
+            &mut self.remixed,
+        );
+        self.captured.clear();
+        self.resampled.clear();
+        self.resampler.process(&self.remixed, &mut self.resampled);
+        self.processed.clear();
+        self.dsp.process(&self.resampled, &mut self.processed);
+        self.meter.extend(&self.processed);
+
+        let open = self.gate.is_open(
+            voice,
+            push_to_talk,
+            &self.processed,
+            self.channels,
+            self.sample_rate,
+        );
+        let mut open = open && !replace_microphone;
+        if !open {
+            self.processed.fill(0.0);
+        }
+        if !self.media.is_empty() {
+            // Replacing the microphone everything that is due is sent,
+            // mixed it follows the microphone.
+            if replace_microphone && self.media.len() > self.processed.len() {
+                self.processed.resize(self.media.len(), 0.0);
+            }
+            let len = self.media.len().min(self.processed.len());
+            for (sample, media) in self.processed.iter_mut().zip(self.media.drain(..len)) {
+                *sample = (*sample + media).clamp(-1.0, 1.0);
+            }
+            open = true;
+        }
+
+        self.transmitting |= open;
+        if self.recording {
+            self.recorded.extend_from_slice(&self.processed);
+        }
+        if !open {
+            // Nothing is sent while the gate is closed, the timestamp still
+            // advances so remotes play the pause instead of skipping it.
+            let skipped = self.input_buffer.len() + self.processed.len();
+            self.input_buffer.clear();
+            self.timestamp = self
+                .timestamp
+                .wrapping_add((skipped / self.channels.max(1)) as u32);
+            return;
+        }
+        self.input_buffer.extend_from_slice(&self.processed);
+
+        // Every frame is one packet.
+        let frame_size = self.codec.frame_size();
+        let frame_len = frame_size * self.channels;
+        if frame_len == 0 {
+            return;
+        }
+        let mut consumed = 0;
+        while self.input_buffer.len() - consumed >= frame_len {
+            match self
+                .codec
+                .encode(&self.input_buffer[consumed..consumed + frame_len])
+            {
+                Ok(Some(data)) => {
+                    let _ = sender.try_send(Message::Audio(AudioMessage::InputData {
+                        id: self.id,
+                        sequence: self.sequence,
+                        timestamp: self.timestamp,
+                        data,
+                    }));
+                    self.sequence = self.sequence.wrapping_add(1);
+                }
+                Ok(None) => {}
+                Err(error) => log::warn!("Cannot encode for: {}, {error}", self.id),
+            }
+            consumed += frame_len;
+            self.timestamp = self.timestamp.wrapping_add(frame_size as u32);
+        }
+        self.input_buffer.drain(..consumed);
+    }
+}
+
+impl Stream {
+    /// Gives what was just played to the echo canceller.
+    fn push_reference(&mut self, played: &[f32], channels: usize) {
+        self.remixed.clear();
+        remix(played, channels, 1, &mut self.remixed);
+        self.reference.clear();
+        self.reference_resampler
+            .process(&self.remixed, &mut self.reference);
+        self.dsp.push_reference(&self.reference);
+    }
+
+    /// Queues what the players played, converted to the codec format.
+    fn push_media(&mut self, media: &[f32]) {
+        if media.is_empty() {
+            return;
+        }
+        self.remixed.clear();
+        remix(media, MEDIA_CHANNELS, self.channels, &mut self.remixed);
+        self.resampled.clear();
+        self.media_resampler
+            .process(&self.remixed, &mut self.resampled);
+        self.media.extend(self.resampled.iter());
+
+        // The players run on the wall clock and the microphone on its own,
+        // the difference would add up.
+        let max = self.sample_rate as usize * self.channels * MAX_MEDIA_MS / 1000;
+        if self.media.len() > max {
+            let excess = self.media.len() - max;
+            self.media.drain(..excess);
+        }
+    }
+}
+
+/// The one device stream the [`Mixer`] plays on, fed through `producer`.
+pub struct OutputStream {
+    pub stream: DeviceStream,
+    pub producer: rtrb::Producer<f32>,
+    /// Set by the error callback, handled outside of it.
+    pub failed: Arc<AtomicBool>,
+    pub channels: usize,
+    pub queue: usize,
+    pub chunk: Vec<f32>,
+    /// Everything mixed since the last call, the echo reference of the inputs.
+    pub played: Vec<f32>,
+}
+
+unsafe impl Send for OutputStream {}
+unsafe impl Sync for OutputStream {}
+
+impl OutputStream {
+    /// Mixes until the callback has `queue` samples waiting.
+    fn process(&mut self, mixer: &mut Mixer) {
+        self.played.clear();
+        let capacity = self.producer.buffer().capacity();
+        while capacity - self.producer.slots() < self.queue {
+            mixer.mix(&mut self.chunk, self.channels);
+            let Ok(chunk) = self.producer.write_chunk_uninit(self.chunk.len()) else {
+                break;
+            };
+            chunk.fill_from_iter(self.chunk.iter().copied());
+            self.played.extend_from_slice(&self.chunk);
+        }
+    }
 }
 
 pub struct Audio {
     pub logic_sender: Sender<Message>,
     pub logic_receiver: Receiver<Message>,
 
+    /// `None` when the virtual devices are used.
     pub host: Option<cpal::Host>,
 
     pub output_device: Option<Device>,
     pub input_device: Option<Device>,
 
-    pub codecs: HashMap<String, Box<dyn Codec>>,
-    pub streams: Vec<Arc<RwLock<Stream>>>,
+    /// Codecs that can be used, more can be registered before [`Audio::run`].
+    pub codecs: CodecRegistry,
+    /// Codec of every requested input channel, kept while there is no device
+    /// so the channels can be reopened when one is selected.
+    pub inputs: HashMap<usize, String>,
+    pub streams: Vec<Stream>,
+
+    /// Every output channel is a source of this mixer, played by `output_stream`.
+    pub mixer: Mixer,
+    pub output_stream: Option<OutputStream>,
+
+    pub voice: VoiceSettings,
+    pub push_to_talk: bool,
+    /// Applied to the [`Dsp`] of every input stream.
+    pub dsp_settings: Vec<(String, Atom)>,
+    pub recordings: Vec<Recording>,
+    /// Files played into every input stream.
+    pub players: Vec<Player>,
+    /// What the players played this cycle, in the media format.
+    pub media: Vec<f32>,
 }
 
 impl Audio {
@@ -95,31 +559,30 @@ impl Audio {
             host: None,
             output_device: None,
             input_device: None,
-            codecs: HashMap::new(),
+            codecs: CodecRegistry::with_builtin(),
+            inputs: HashMap::new(),
             streams: Vec::new(),
+            mixer: Mixer::default(),
+            output_stream: None,
+            voice: VoiceSettings::default(),
+            push_to_talk: false,
+            dsp_settings: Vec::new(),
+            recordings: Vec::new(),
+            players: Vec::new(),
+            media: Vec::new(),
         }
     }
     pub async fn run(mut self) {
-        self.host = Some(cpal::default_host());
-
-        if let Some(host) = &self.host {
-            let input_config = host.default_input_device().unwrap().default_input_config();
-            println!("Default Input Config: {input_config:?}");
-            let output_config = host
-                .default_output_device()
-                .unwrap()
-                .default_output_config();
-            println!("Default Output Config: {output_config:?}");
-        }
-
-        self.try_get_default_devices();
+        self.select_devices(&AudioSettings::default());
 
         println!("Audio thread started!");
 
-        self.codecs
-            .insert("opus".into(), Box::<CodecOpus>::default());
-
-        let mut read_errors = tokio::time::Instant::now() + std::time::Duration::from_secs(1);
+        let mut process = tokio::time::interval(PROCESS_INTERVAL);
+        process.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut levels = tokio::time::interval(LEVELS_INTERVAL);
+        levels.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut stats = tokio::time::interval(STATS_INTERVAL);
+        stats.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
         loop {
             tokio::select! {
@@ -131,18 +594,14 @@ impl Audio {
                         self.process_logic(event).await;
                     }
                 }
-                _ = tokio::time::sleep_until(read_errors) => {
-                    for stream in self.streams.iter(){
-                        let id = stream.read().unwrap().id;
-                        if let Some(codec) = &mut stream.write().unwrap().codec{
-                            let errors = codec.errors();
-                            for error in errors{
-                                println!("Error for: {id}, {error}");
-                            }
-                        }
-                    }
-
-                    read_errors = tokio::time::Instant::now() + std::time::Duration::from_secs(1);
+                _ = process.tick() => {
+                    self.process_audio();
+                }
+                _ = levels.tick() => {
+                    self.send_levels();
+                }
+                _ = stats.tick() => {
+                    self.send_stats();
                 }
 
             }
@@ -152,167 +611,182 @@ impl Audio {
     fn shutdown(&mut self) {
         println!("Audio thread cloasing");
 
-        for stream in self.streams.drain(..) {
-            println!("Audio closing: {}", stream.read().unwrap().id);
-            let _ = stream.write().unwrap().stream.take();
+        for mut stream in self.streams.drain(..) {
+            println!("Audio closing: {}", stream.id);
+            let _ = stream.stream.take();
+        }
+        self.output_stream.take();
+        self.mixer.sources.clear();
+        for recording in self.recordings.drain(..) {
+            let _ = recording.finish();
         }
 
         println!("Audio thread shutdown succesfuly");
     }
 
+    /// Runs the codecs and the mixer, the device callbacks only move samples.
+    fn process_audio(&mut self) {
+        let failed = self
+            .output_stream
+            .as_ref()
+            .is_some_and(|output| output.failed.load(Ordering::Relaxed));
+        if failed {
+            // Every source plays through this stream, all of them have to be recreated.
+            self.output_stream.take();
+            for source in self.mixer.sources.iter() {
+                let _ = self
+                    .logic_sender
+                    .try_send(Message::Audio(AudioMessage::OutputError {
+                        id: source.id,
+                        error: "Output stream failed".into(),
+                    }));
+            }
+        }
+
+        if let Some(output) = &mut self.output_stream {
+            output.process(&mut self.mixer);
+        }
+
+        self.play_media();
+        let replace_microphone = self
+            .players
+            .iter()
+            .any(|player| player.mode == PlaybackMode::Replace);
+
+        for stream in self.streams.iter_mut() {
+            // Playback is mixed first, so the echo canceller has its reference in time.
+            if let Some(output) = &self.output_stream {
+                stream.push_reference(&output.played, output.channels);
+            }
+            stream.push_media(&self.media);
+            stream.process(
+                &self.logic_sender,
+                &self.voice,
+                self.push_to_talk,
+                replace_microphone,
+            );
+        }
+
+        self.record();
+    }
+
+    /// Sums what every player has due into `media`, players that ended are removed.
+    fn play_media(&mut self) {
+        self.media.clear();
+        let mut stopped = Vec::new();
+        for (index, player) in self.players.iter_mut().enumerate() {
+            match player.process() {
+                Ok(playing) => {
+                    if !playing {
+                        stopped.push((index, None));
+                    }
+                }
+                Err(error) => stopped.push((index, Some(error))),
+            }
+            for (index, sample) in player.output.iter().enumerate() {
+                if let Some(media) = self.media.get_mut(index) {
+                    *media += sample;
+                } else {
+                    self.media.push(*sample);
+                }
+            }
+        }
+        for (index, error) in stopped.into_iter().rev() {
+            let player = self.players.remove(index);
+            let _ = self
+                .logic_sender
+                .try_send(Message::Audio(AudioMessage::PlaybackStopped {
+                    id: player.id,
+                    error,
+                }));
+        }
+    }
+
+    /// Gives every recording what was played and sent, and writes it.
+    fn record(&mut self) {
+        let recording = !self.recordings.is_empty();
+        self.mixer.recording = recording;
+        // Only the first input is recorded, the others send the same microphone.
+        for (index, stream) in self.streams.iter_mut().enumerate() {
+            stream.recording = recording && index == 0;
+            stream.recorded.clear();
+        }
+        if !recording {
+            for source in self.mixer.sources.iter_mut() {
+                source.recorded.clear();
+            }
+            return;
+        }
+
+        if let Some(device) = &self.output_device {
+            let sample_rate = device.config.sample_rate.0;
+            let channels = device.config.channels as usize;
+            for source in self.mixer.sources.iter_mut() {
+                for recording in self.recordings.iter_mut() {
+                    recording.push_source(source.id, &source.recorded, sample_rate, channels);
+                }
+                source.recorded.clear();
+            }
+        }
+        if let Some(stream) = self.streams.first() {
+            for recording in self.recordings.iter_mut() {
+                recording.push_input(&stream.recorded, stream.sample_rate, stream.channels);
+            }
+        }
+
+        let mut failed = Vec::new();
+        for (index, recording) in self.recordings.iter_mut().enumerate() {
+            if let Err(error) = recording.process() {
+                failed.push((index, error));
+            }
+        }
+        for (index, error) in failed.into_iter().rev() {
+            let recording = self.recordings.remove(index);
+            let id = recording.id;
+            let _ = recording.finish();
+            let _ = self
+                .logic_sender
+                .try_send(Message::Audio(AudioMessage::RecordingStopped {
+                    id,
+                    error: Some(error),
+                }));
+        }
+    }
+
     async fn process_logic(&mut self, event: Message) {
         match event {
             Message::Audio(AudioMessage::CreateInputChannel { id, codec }) => {
-                let mut error = String::new();
-                if let Some(input_device) = &mut self.input_device {
-                    if let Some(codec) = self.codecs.get(&codec) {
-                        let mut codec = codec.c();
-                        {
-                            let mut channels = codec
-                                .get_setting("channels".into())
-                                .expect("Doze not have channels");
-                            if let Atom::UnSignedValues { value, .. } = &mut channels {
-                                *value = input_device.config.channels as usize;
-                            }
-                            codec.set_setting("channels".into(), channels);
-
-                            let mut sample_rate = codec
-                                .get_setting("sample_rate".into())
-                                .expect("Doze not have sample_rate");
-                            if let Atom::UnSignedValues { value, .. } = &mut sample_rate {
-                                *value = input_device.config.sample_rate.0 as usize;
-                            }
-                            codec.set_setting("sample_rate".into(), sample_rate);
-                        }
-                        let stream = Arc::new(RwLock::new(Stream {
-                            codec: Some(codec),
-                            stream: None,
-                            volume: 1.0,
-                            stream_type: StreamType::Input,
-                            id,
-                            buffer: Vec::new(),
-                            sender: self.logic_sender.clone(),
-                            input_buffer: Vec::new(),
-                            output_buffer: Vec::new(),
-                        }));
-                        let str = stream.clone();
-                        let str2 = stream.clone();
-                        let cpal_stream = input_device
-                            .build_input_stream(
-                                move |input: &[f32], _| {
-                                    let volume = str.read().unwrap().volume;
-                                    let mut codec = str.write().unwrap().codec.take().unwrap();
-                                    str.write().unwrap().input_buffer.append(
-                                        &mut input.iter().map(|d| d * volume).collect::<Vec<f32>>(),
-                                    );
-                                    let data = codec.encode(&mut str.write().unwrap().input_buffer);
-                                    let _ = str.read().unwrap().sender.try_send(Message::Audio(
-                                        AudioMessage::InputData { id, data },
-                                    ));
-                                    str.write().unwrap().codec = Some(codec);
-                                },
-                                move |error| {
-                                    let id = str2.read().unwrap().id;
-                                    let _ = str2.read().unwrap().sender.try_send(Message::Audio(
-                                        AudioMessage::InputError {
-                                            id,
-                                            error: error.to_string(),
-                                        },
-                                    ));
-                                    eprintln!("Input stream error! {error}");
-                                },
-                                None,
-                            )
-                            .unwrap();
-                        let _ = cpal_stream.play();
-                        stream.write().unwrap().stream = Some(cpal_stream);
+                self.inputs.insert(id, codec.clone());
+                self.streams.retain(|stream| stream.id != id);
+                let error = match self.create_input_stream(id, &codec) {
+                    Ok(stream) => {
                         self.streams.push(stream);
-                    } else {
-                        error.push_str("Invalid codec!\n");
+                        String::new()
                     }
-                } else {
-                    error.push_str("No input device!\n");
-                }
+                    Err(error) => error,
+                };
                 let _ = self.logic_sender.try_send(Message::Audio(
                     AudioMessage::ResCreateInputChannel(id, error),
                 ));
             }
-            Message::Audio(AudioMessage::CreateOutputChannel { id, codec }) => {
+            Message::Audio(AudioMessage::CreateOutputChannel { id, codec: info }) => {
                 let mut error = String::new();
-                if let Some(output_device) = &mut self.output_device {
-                    if let Some(codec) = self.codecs.get(&codec) {
-                        let mut codec = codec.c();
-                        {
-                            let mut channels = codec
-                                .get_setting("channels".into())
-                                .expect("Doze not have channels");
-                            if let Atom::UnSignedValues { value, .. } = &mut channels {
-                                *value = output_device.config.channels as usize;
-                            }
-                            codec.set_setting("channels".into(), channels);
-
-                            let mut sample_rate = codec
-                                .get_setting("sample_rate".into())
-                                .expect("Doze not have sample_rate");
-                            if let Atom::UnSignedValues { value, .. } = &mut sample_rate {
-                                *value = output_device.config.sample_rate.0 as usize;
-                            }
-                            codec.set_setting("sample_rate".into(), sample_rate);
+                if let Some(output_device) = &self.output_device {
+                    if let Some(mut codec) = self.codecs.create(&info.name) {
+                        // Decode with what the remote encodes, the mixer converts to the device.
+                        configure_codec(&mut codec, info.sample_rate, info.channels);
+                        let decoded = codec_info(&mut codec);
+                        if decoded != info {
+                            error.push_str(&format!(
+                                "Codec cannot decode {info:?}, using {decoded:?}\n"
+                            ));
+                        }
+                        let source = Source::new(id, codec, decoded, &output_device.config);
+                        self.mixer.sources.retain(|source| source.id != id);
+                        self.mixer.sources.push(source);
+                        if let Err(err) = self.start_output_stream() {
+                            error.push_str(&format!("Cannot start output stream: {err}\n"));
                         }
-                        let stream = Arc::new(RwLock::new(Stream {
-                            codec: Some(codec),
-                            stream: None,
-                            volume: 1.0,
-                            stream_type: StreamType::Output,
-                            id,
-                            buffer: Vec::new(),
-                            sender: self.logic_sender.clone(),
-                            input_buffer: Vec::new(),
-                            output_buffer: Vec::new(),
-                        }));
-                        let str = stream.clone();
-                        let str2 = stream.clone();
-                        let cpal_stream = output_device
-                            .build_output_stream(
-                                move |output: &mut [f32], _| {
-                                    let volume = str.read().unwrap().volume;
-                                    let mut codec = str.write().unwrap().codec.take().unwrap();
-                                    let mut buffer = {
-                                        let mut stre = str.write().unwrap();
-                                        codec.decode(&mut stre.buffer)
-                                    };
-                                    str.write().unwrap().output_buffer.append(&mut buffer);
-                                    let len = str.read().unwrap().output_buffer.len();
-                                    let len = if len >= output.len() { output.len() } else { 0 };
-                                    let mut buffer = str
-                                        .write()
-                                        .unwrap()
-                                        .output_buffer
-                                        .drain(..len)
-                                        .collect::<Vec<f32>>();
-                                    buffer.resize(output.len(), 0.0);
-                                    output.copy_from_slice(
-                                        &buffer.drain(..).map(|e| e * volume).collect::<Vec<f32>>(),
-                                    );
-                                    str.write().unwrap().codec = Some(codec);
-                                },
-                                move |error| {
-                                    let id = str2.read().unwrap().id;
-                                    let _ = str2.read().unwrap().sender.try_send(Message::Audio(
-                                        AudioMessage::OutputError {
-                                            id,
-                                            error: error.to_string(),
-                                        },
-                                    ));
-                                    eprintln!("Output stream error! {error}");
-                                },
-                                None,
-                            )
-                            .unwrap();
-
-                        let _ = cpal_stream.play();
-                        stream.write().unwrap().stream = Some(cpal_stream);
-                        self.streams.push(stream);
                     } else {
                         error.push_str("Invalid codec!\n");
                     }
@@ -323,74 +797,743 @@ impl Audio {
                     AudioMessage::ResCreateOutputChannel(id, error),
                 ));
             }
-            Message::Audio(AudioMessage::OutputData { id, mut data }) => {
-                for stream in self.streams.iter() {
-                    if stream.read().unwrap().id == id {
-                        stream.write().unwrap().buffer.append(&mut data);
-                        break;
+            Message::Audio(AudioMessage::GetCodecs) => self.send_codecs(),
+            Message::Audio(AudioMessage::SetCodecOrder(names)) => {
+                self.codecs.set_order(&names);
+                self.send_codecs();
+            }
+            Message::Audio(AudioMessage::GetDevices) => self.send_devices(),
+            Message::Audio(AudioMessage::SetDevices(settings)) => {
+                self.select_devices(&settings);
+                self.reopen_streams();
+                // The input device decides what we encode.
+                self.send_codecs();
+                self.send_devices();
+            }
+            Message::Audio(AudioMessage::SetVoice(voice)) => {
+                self.mixer.deafened = voice.deafened;
+                self.voice = voice;
+            }
+            Message::Audio(AudioMessage::PushToTalk(pressed)) => self.push_to_talk = pressed,
+            Message::Audio(AudioMessage::StartRecording {
+                id,
+                path,
+                format,
+                multitrack,
+                sources,
+            }) => match Recording::new(id, path, format, multitrack, sources) {
+                Ok(recording) => self.recordings.push(recording),
+                Err(error) => {
+                    let _ = self.logic_sender.try_send(Message::Audio(
+                        AudioMessage::RecordingStopped {
+                            id,
+                            error: Some(error),
+                        },
+                    ));
+                }
+            },
+            Message::Audio(AudioMessage::SetRecordingSources { id, sources }) => {
+                let result = self
+                    .recordings
+                    .iter_mut()
+                    .find(|recording| recording.id == id)
+                    .map(|recording| recording.set_sources(sources));
+                if let Some(Err(error)) = result {
+                    self.stop_recording(id, Some(error));
+                }
+            }
+            Message::Audio(AudioMessage::StopRecording { id }) => self.stop_recording(id, None),
+            Message::Audio(AudioMessage::PlayFile {
+                id,
+                path,
+                mode,
+                looping,
+            }) => {
+                self.players.retain(|player| player.id != id);
+                match Player::new(id, &path, mode, looping) {
+                    Ok(player) => self.players.push(player),
+                    Err(error) => {
+                        let _ = self.logic_sender.try_send(Message::Audio(
+                            AudioMessage::PlaybackStopped {
+                                id,
+                                error: Some(error),
+                            },
+                        ));
                     }
                 }
             }
+            Message::Audio(AudioMessage::PausePlayback { id, paused }) => {
+                if let Some(player) = self.player_mut(id) {
+                    player.set_paused(paused);
+                }
+            }
+            Message::Audio(AudioMessage::SeekPlayback { id, position }) => {
+                let result = self.player_mut(id).map(|player| player.seek(position));
+                if let Some(Err(error)) = result {
+                    self.players.retain(|player| player.id != id);
+                    let _ =
+                        self.logic_sender
+                            .try_send(Message::Audio(AudioMessage::PlaybackStopped {
+                                id,
+                                error: Some(error),
+                            }));
+                }
+            }
+            Message::Audio(AudioMessage::LoopPlayback { id, looping }) => {
+                if let Some(player) = self.player_mut(id) {
+                    player.looping = looping;
+                }
+            }
+            Message::Audio(AudioMessage::StopPlayback { id }) => {
+                let len = self.players.len();
+                self.players.retain(|player| player.id != id);
+                if self.players.len() != len {
+                    let _ =
+                        self.logic_sender
+                            .try_send(Message::Audio(AudioMessage::PlaybackStopped {
+                                id,
+                                error: None,
+                            }));
+                }
+            }
+            Message::Audio(AudioMessage::GetDsp) => self.send_dsp(),
+            Message::Audio(AudioMessage::SetDsp(key, value)) => {
+                for stream in self.streams.iter_mut() {
+                    stream.dsp.set_setting(key.clone(), value.clone());
+                }
+                self.dsp_settings.retain(|(setting, _)| *setting != key);
+                self.dsp_settings.push((key, value));
+                self.send_dsp();
+            }
+            Message::Audio(AudioMessage::SetOutputVoice { id, voice }) => {
+                if let Some(source) = self.mixer.source_mut(id) {
+                    source.volume = voice.volume;
+                    source.muted = voice.muted;
+                    source.pan = voice.pan;
+                }
+            }
+            Message::Audio(AudioMessage::OutputData {
+                id,
+                sequence,
+                timestamp,
+                data,
+            }) => {
+                if let Some(source) = self.mixer.source_mut(id) {
+                    source.push(sequence, timestamp, data);
+                }
+            }
             Message::Audio(AudioMessage::DestroyInputChannel { id }) => {
-                self.streams.retain(|stream| {
-                    if stream.read().unwrap().id != id {
-                        false
-                    } else {
-                        stream.write().unwrap().stream.take();
-                        true
-                    }
-                });
+                self.inputs.remove(&id);
+                self.streams.retain(|stream| stream.id != id);
             }
             Message::Audio(AudioMessage::DestroyOuputChannel { id }) => {
-                self.streams.retain(|stream| {
-                    if stream.read().unwrap().id != id {
-                        false
-                    } else {
-                        stream.write().unwrap().stream.take();
-                        true
+                self.mixer.sources.retain(|source| source.id != id);
+                self.release_output_stream();
+            }
+            Message::Audio(AudioMessage::Ring(ringing)) => {
+                if !ringing {
+                    self.mixer.ringer = None;
+                    self.release_output_stream();
+                } else if let Some(output_device) = &self.output_device {
+                    self.mixer.ringer = Some(Ringer::new(output_device.config.sample_rate.0));
+                    if let Err(error) = self.start_output_stream() {
+                        eprintln!("Cannot ring: {error}");
                     }
-                });
+                }
             }
             _ => {}
         }
     }
 
-    pub fn try_get_default_devices(&mut self) {
-        if let Some(host) = &mut self.host {
-            self.output_device = host
-                .default_output_device()
-                .and_then(|device| {
-                    device
-                        .default_output_config()
-                        .map(|config| (device, config))
-                        .ok()
-                })
-                .map(|(device, config)| Device {
-                    device,
-                    config: cpal::StreamConfig {
-                        channels: config.channels(),
-                        sample_rate: cpal::SampleRate(48000),
-                        buffer_size: config.config().buffer_size,
-                    },
-                    supported_config: config,
-                });
-            self.input_device = host
-                .default_input_device()
-                .and_then(|device| {
-                    device
-                        .default_input_config()
-                        .map(|config| (device, config))
-                        .ok()
-                })
-                .map(|(device, config)| Device {
-                    device,
-                    config: cpal::StreamConfig {
-                        channels: config.channels(),
-                        sample_rate: cpal::SampleRate(48000),
-                        buffer_size: config.config().buffer_size,
-                    },
-                    supported_config: config,
-                });
+    /// Releases the device while nobody is talking to us and nothing rings.
+    fn release_output_stream(&mut self) {
+        if self.mixer.sources.is_empty() && self.mixer.ringer.is_none() {
+            self.output_stream.take();
+        }
+    }
+
+    /// Opens the shared output stream if it is not running yet.
+    fn start_output_stream(&mut self) -> Result<(), String> {
+        if self.output_stream.is_some() {
+            return Ok(());
         }
+        let Some(output_device) = &mut self.output_device else {
+            return Err("No output device!".into());
+        };
+
+        let channels = output_device.config.channels as usize;
+        let (producer, consumer) =
+            rtrb::RingBuffer::new(ms_to_samples(&output_device.config, RING_MS));
+        let failed = Arc::new(AtomicBool::new(false));
+        let failed2 = failed.clone();
+        let stream = output_device.open_output_stream(consumer, move |error| {
+            eprintln!("Output stream error! {error}");
+            failed2.store(true, Ordering::Relaxed);
+        })?;
+        stream.play()?;
+        self.output_stream = Some(OutputStream {
+            stream,
+            producer,
+            failed,
+            channels,
+            queue: ms_to_samples(&output_device.config, OUTPUT_QUEUE_MS),
+            chunk: vec![0.0; ms_to_samples(&output_device.config, MIX_CHUNK_MS)],
+            played: Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn create_input_stream(&mut self, id: usize, codec: &str) -> Result<Stream, String> {
+        let Some(input_device) = &mut self.input_device else {
+            return Err("No input device!\n".into());
+        };
+        let Some(mut codec) = self.codecs.create(codec) else {
+            return Err("Invalid codec!\n".into());
+        };
+
+        let encoding = configure_codec_for(&mut codec, &input_device.config);
+        let (producer, consumer) =
+            rtrb::RingBuffer::new(ms_to_samples(&input_device.config, RING_MS));
+        let sender = self.logic_sender.clone();
+        let device_stream = input_device
+            .open_input_stream(producer, move |error| {
+                let _ = sender.try_send(Message::Audio(AudioMessage::InputError {
+                    id,
+                    error: error.to_string(),
+                }));
+                eprintln!("Input stream error! {error}");
+            })
+            .map_err(|err| format!("Cannot start input stream: {err}\n"))?;
+        let _ = device_stream.play();
+
+        let mut dsp = Dsp::new(encoding.sample_rate, encoding.channels as usize);
+        for (key, value) in self.dsp_settings.iter() {
+            dsp.set_setting(key.clone(), value.clone());
+        }
+        let played_rate = self
+            .output_device
+            .as_ref()
+            .map_or(encoding.sample_rate, |device| device.config.sample_rate.0);
+
+        Ok(Stream {
+            codec,
+            stream: Some(device_stream),
+            volume: 1.0,
+            id,
+            device_channels: input_device.config.channels as usize,
+            channels: encoding.channels as usize,
+            sample_rate: encoding.sample_rate,
+            resampler: Resampler::new(
+                input_device.config.sample_rate.0,
+                encoding.sample_rate,
+                encoding.channels as usize,
+            ),
+            captured: Vec::new(),
+            remixed: Vec::new(),
+            resampled: Vec::new(),
+            dsp,
+            processed: Vec::new(),
+            reference_resampler: Resampler::new(played_rate, encoding.sample_rate, 1),
+            reference: Vec::new(),
+            gate: Gate::default(),
+            meter: Meter::default(),
+            transmitting: false,
+            recording: false,
+            recorded: Vec::new(),
+            media: VecDeque::new(),
+            media_resampler: Resampler::new(
+                MEDIA_SAMPLE_RATE,
+                encoding.sample_rate,
+                encoding.channels as usize,
+            ),
+            sequence: 0,
+            timestamp: 0,
+            consumer,
+            input_buffer: Vec::new(),
+        })
+    }
+
+    /// Rebuilds every stream on the current devices, after they changed.
+    fn reopen_streams(&mut self) {
+        let previous = self
+            .streams
+            .drain(..)
+            .map(|stream| (stream.id, (stream.sequence, stream.timestamp)))
+            .collect::<HashMap<usize, (u32, u32)>>();
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|(id, codec)| (*id, codec.clone()))
+            .collect::<Vec<(usize, String)>>();
+        for (id, codec) in inputs {
+            match self.create_input_stream(id, &codec) {
+                Ok(mut stream) => {
+                    // Continue the packet numbering, so remotes do not see a restart.
+                    if let Some((sequence, timestamp)) = previous.get(&id) {
+                        stream.sequence = *sequence;
+                        stream.timestamp = *timestamp;
+                    }
+                    self.streams.push(stream);
+                }
+                Err(error) => eprintln!("Cannot reopen input: {id}, {error}"),
+            }
+        }
+
+        self.output_stream.take();
+        if let Some(output_device) = &self.output_device {
+            for source in self.mixer.sources.iter_mut() {
+                source.set_device(&output_device.config);
+            }
+            if let Some(ringer) = &mut self.mixer.ringer {
+                ringer.set_sample_rate(output_device.config.sample_rate.0);
+            }
+        }
+        if !self.mixer.sources.is_empty() || self.mixer.ringer.is_some() {
+            if let Err(error) = self.start_output_stream() {
+                eprintln!("Cannot reopen output: {error}");
+            }
+        }
+    }
+
+    fn player_mut(&mut self, id: usize) -> Option<&mut Player> {
+        self.players.iter_mut().find(|player| player.id == id)
+    }
+
+    fn stop_recording(&mut self, id: usize, error: Option<String>) {
+        let Some(index) = self
+            .recordings
+            .iter()
+            .position(|recording| recording.id == id)
+        else {
+            return;
+        };
+        let result = self.recordings.remove(index).finish();
+        let _ = self
+            .logic_sender
+            .try_send(Message::Audio(AudioMessage::RecordingStopped {
+                id,
+                error: error.or(result.err()),
+            }));
+    }
+
+    /// What the codecs encode with on the current input device.
+    fn send_codecs(&mut self) {
+        let config = self
+            .input_device
+            .as_ref()
+            .or(self.output_device.as_ref())
+            .map(|device| device.config.clone())
+            .unwrap_or(cpal::StreamConfig {
+                channels: 1,
+                sample_rate: PREFERRED_SAMPLE_RATE,
+                buffer_size: cpal::BufferSize::Default,
+            });
+
+        let codecs = self
+            .codecs
+            .names()
+            .filter_map(|name| self.codecs.create(name))
+            .map(|mut codec| configure_codec_for(&mut codec, &config))
+            .collect::<Vec<CodecInfo>>();
+
+        let _ = self
+            .logic_sender
+            .try_send(Message::Audio(AudioMessage::Codecs(codecs)));
+        let _ = self
+            .logic_sender
+            .try_send(Message::Audio(AudioMessage::CodecCapabilities(
+                self.codecs.capabilities(),
+            )));
+    }
+
+    fn send_levels(&mut self) {
+        for player in self.players.iter() {
+            let _ = self
+                .logic_sender
+                .try_send(Message::Audio(AudioMessage::Playback {
+                    id: player.id,
+                    state: player.state(),
+                }));
+        }
+
+        // Only stay quiet when nothing could be heard or sent anyway.
+        if self.streams.is_empty() && self.mixer.sources.is_empty() {
+            return;
+        }
+        let mut input = Level::default();
+        let mut transmitting = false;
+        for stream in self.streams.iter_mut() {
+            let level = stream.meter.take();
+            if level.rms > input.rms {
+                input = level;
+            }
+            transmitting |= std::mem::take(&mut stream.transmitting);
+        }
+        let outputs = self
+            .mixer
+            .sources
+            .iter_mut()
+            .map(|source| (source.id, source.meter.take()))
+            .collect();
+        let _ = self
+            .logic_sender
+            .try_send(Message::Audio(AudioMessage::Levels {
+                input,
+                transmitting,
+                outputs,
+            }));
+    }
+
+    fn send_stats(&mut self) {
+        if self.mixer.sources.is_empty() {
+            return;
+        }
+        let queued = Duration::from_millis(OUTPUT_QUEUE_MS as u64);
+        let stats = self
+            .mixer
+            .sources
+            .iter_mut()
+            .map(|source| {
+                let mut stats = source.jitter.stats();
+                stats.buffered += queued;
+                (source.id, stats)
+            })
+            .collect();
+        let _ = self
+            .logic_sender
+            .try_send(Message::Audio(AudioMessage::Stats(stats)));
+    }
+
+    /// Every processing setting with its current value.
+    fn send_dsp(&mut self) {
+        let mut dsp = Dsp::new(PREFERRED_SAMPLE_RATE.0, 1);
+        for (key, value) in self.dsp_settings.iter() {
+            dsp.set_setting(key.clone(), value.clone());
+        }
+        let _ = self
+            .logic_sender
+            .try_send(Message::Audio(AudioMessage::Dsp(dsp.values())));
+    }
+
+    fn send_devices(&mut self) {
+        let input = self.input_device.as_ref().and_then(Device::name);
+        let output = self.output_device.as_ref().and_then(Device::name);
+        let (inputs, outputs) = match &self.host {
+            Some(host) => (
+                host.input_devices().map(device_names).unwrap_or_default(),
+                host.output_devices().map(device_names).unwrap_or_default(),
+            ),
+            // Virtual devices with a file are not listed, the selected one is.
+            None => (
+                virtual_names(
+                    VirtualInput::presets().iter().map(VirtualInput::name),
+                    &input,
+                ),
+                virtual_names(
+                    VirtualOutput::presets().iter().map(VirtualOutput::name),
+                    &output,
+                ),
+            ),
+        };
+        let devices = AudioDevices {
+            hosts: cpal::available_hosts()
+                .iter()
+                .map(|host| host.name().to_string())
+                .chain(std::iter::once(VIRTUAL_HOST.to_string()))
+                .collect(),
+            host: self
+                .host
+                .as_ref()
+                .map(|host| host.id().name().to_string())
+                .unwrap_or_else(|| VIRTUAL_HOST.into()),
+            inputs,
+            outputs,
+            input,
+            output,
+        };
+        let _ = self
+            .logic_sender
+            .try_send(Message::Audio(AudioMessage::Devices(devices)));
+    }
+
+    /// Opens the host and devices by name, missing ones fall back to the defaults.
+    ///
+    /// When there is no device at all the channels are kept, they just stay silent.
+    pub fn select_devices(&mut self, settings: &AudioSettings) {
+        if settings.host.as_deref() == Some(VIRTUAL_HOST) {
+            self.select_virtual_devices(settings);
+            return;
+        }
+
+        let host = settings
+            .host
+            .as_ref()
+            .and_then(|name| {
+                cpal::available_hosts()
+                    .into_iter()
+                    .find(|host| host.name() == name)
+            })
+            .and_then(|host| cpal::host_from_id(host).ok())
+            .unwrap_or_else(cpal::default_host);
+
+        let input = settings
+            .input_device
+            .as_ref()
+            .and_then(|name| {
+                host.input_devices()
+                    .ok()?
+                    .find(|device| device.name().is_ok_and(|device| device == *name))
+            })
+            .or_else(|| host.default_input_device());
+        self.input_device = input.and_then(|device| {
+            let config = device.default_input_config().ok()?;
+            let configs = device.supported_input_configs().ok()?.collect::<Vec<_>>();
+            Some(Device::new(device, config, configs.into_iter()))
+        });
+
+        let output = settings
+            .output_device
+            .as_ref()
+            .and_then(|name| {
+                host.output_devices()
+                    .ok()?
+                    .find(|device| device.name().is_ok_and(|device| device == *name))
+            })
+            .or_else(|| host.default_output_device());
+        self.output_device = output.and_then(|device| {
+            let config = device.default_output_config().ok()?;
+            let configs = device.supported_output_configs().ok()?.collect::<Vec<_>>();
+            Some(Device::new(device, config, configs.into_iter()))
+        });
+
+        println!(
+            "Audio host: {}, input: {:?}, output: {:?}",
+            host.id().name(),
+            self.input_device
+                .as_ref()
+                .map(|device| &device.supported_config),
+            self.output_device
+                .as_ref()
+                .map(|device| &device.supported_config),
+        );
+        self.host = Some(host);
+    }
+
+    /// Uses files and generators, the input is silent and the output discarded by default.
+    fn select_virtual_devices(&mut self, settings: &AudioSettings) {
+        let input = settings
+            .input_device
+            .as_deref()
+            .and_then(VirtualInput::parse)
+            .unwrap_or(VirtualInput::Silence);
+        let output = settings
+            .output_device
+            .as_deref()
+            .and_then(VirtualOutput::parse)
+            .unwrap_or(VirtualOutput::Null);
+
+        self.input_device = Device::new_virtual(VirtualDevice::Input(input))
+            .map_err(|error| eprintln!("Cannot open virtual input: {error}"))
+            .ok();
+        self.output_device = Device::new_virtual(VirtualDevice::Output(output))
+            .map_err(|error| eprintln!("Cannot open virtual output: {error}"))
+            .ok();
+
+        println!(
+            "Audio host: {VIRTUAL_HOST}, input: {:?}, output: {:?}",
+            self.input_device.as_ref().and_then(Device::name),
+            self.output_device.as_ref().and_then(Device::name),
+        );
+        self.host = None;
+    }
+}
+
+/// The presets, and the selected device when it is not one of them.
+fn virtual_names(presets: impl Iterator<Item = String>, selected: &Option<String>) -> Vec<String> {
+    let mut names = presets.collect::<Vec<String>>();
+    if let Some(selected) = selected {
+        if !names.contains(selected) {
+            names.push(selected.clone());
+        }
+    }
+    names
+}
+
+fn device_names(devices: impl Iterator<Item = cpal::Device>) -> Vec<String> {
+    devices.filter_map(|device| device.name().ok()).collect()
+}
+
+/// Sets the codec `sample_rate` and `channels`, when the codec supports them.
+fn configure_codec(codec: &mut Box<dyn Codec>, sample_rate: u32, channels: u16) {
+    if let Some(mut setting) = codec.get_setting("channels".into()) {
+        if let Atom::UnSignedValues { value, .. } = &mut setting {
+            *value = channels as usize;
+        }
+        if let Err(error) = codec.set_setting("channels".into(), setting) {
+            log::debug!(
+                "Codec {} cannot use {channels} channels: {error}",
+                codec.name()
+            );
+        }
+    }
+
+    if let Some(mut setting) = codec.get_setting("sample_rate".into()) {
+        if let Atom::UnSignedValues { value, .. } = &mut setting {
+            *value = sample_rate as usize;
+        }
+        if let Err(error) = codec.set_setting("sample_rate".into(), setting) {
+            log::debug!(
+                "Codec {} cannot run at {sample_rate}Hz: {error}",
+                codec.name()
+            );
+        }
+    }
+}
+
+/// Configures the codec for a device, when it cannot run at the device sample rate
+/// it runs at [`PREFERRED_SAMPLE_RATE`] and the audio is resampled.
+fn configure_codec_for(codec: &mut Box<dyn Codec>, config: &cpal::StreamConfig) -> CodecInfo {
+    configure_codec(codec, config.sample_rate.0, config.channels);
+    let info = codec_info(codec);
+    if info.sample_rate == config.sample_rate.0 {
+        return info;
+    }
+    configure_codec(codec, PREFERRED_SAMPLE_RATE.0, config.channels);
+    codec_info(codec)
+}
+
+fn codec_info(codec: &mut Box<dyn Codec>) -> CodecInfo {
+    let sample_rate = match codec.get_setting("sample_rate".into()) {
+        Some(Atom::UnSignedValues { value, .. }) => value as u32,
+        _ => 48000,
+    };
+    let channels = match codec.get_setting("channels".into()) {
+        Some(Atom::UnSignedValues { value, .. }) => value as u16,
+        _ => 1,
+    };
+    CodecInfo {
+        name: codec.name().to_string(),
+        sample_rate,
+        channels,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the virtual sine input through `codec` into a WAV output for a second, what was written.
+    async fn loopback(codec: &str) -> (Vec<f32>, hound::WavSpec) {
+        let path = std::env::temp_dir().join(format!(
+            "the-man-loopback-{codec}-{}.wav",
+            std::process::id()
+        ));
+        let (logic_sender, mut logic_receiver) = tokio::sync::mpsc::channel(1024);
+        let (_sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut audio = Audio::new(logic_sender, receiver);
+        audio.select_devices(&AudioSettings {
+            host: Some(VIRTUAL_HOST.into()),
+            input_device: Some(VirtualInput::Sine { frequency: 440.0 }.name()),
+            output_device: Some(VirtualOutput::Wav(path.clone()).name()),
+        });
+        // Only the codec is under test, the DSP would shape the sine.
+        for key in ["echo_cancellation", "noise_suppression", "agc"] {
+            audio
+                .process_logic(Message::Audio(AudioMessage::SetDsp(
+                    key.into(),
+                    Atom::on_off(false),
+                )))
+                .await;
+        }
+        audio
+            .process_logic(Message::Audio(AudioMessage::CreateInputChannel {
+                id: 0,
+                codec: codec.into(),
+            }))
+            .await;
+        audio
+            .process_logic(Message::Audio(AudioMessage::CreateOutputChannel {
+                id: 1,
+                codec: CodecInfo {
+                    name: codec.into(),
+                    sample_rate: 48000,
+                    channels: 1,
+                },
+            }))
+            .await;
+
+        let start = std::time::Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            tokio::time::sleep(PROCESS_INTERVAL).await;
+            audio.process_audio();
+            while let Ok(message) = logic_receiver.try_recv() {
+                match message {
+                    Message::Audio(AudioMessage::InputData {
+                        sequence,
+                        timestamp,
+                        data,
+                        ..
+                    }) => {
+                        audio
+                            .process_logic(Message::Audio(AudioMessage::OutputData {
+                                id: 1,
+                                sequence,
+                                timestamp,
+                                data,
+                            }))
+                            .await
+                    }
+                    Message::Audio(
+                        AudioMessage::ResCreateInputChannel(_, error)
+                        | AudioMessage::ResCreateOutputChannel(_, error),
+                    ) => assert!(error.is_empty(), "{error}"),
+                    _ => {}
+                }
+            }
+        }
+        // Stops the output, which finishes the file.
+        audio.shutdown();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        let samples = reader.samples::<f32>().map(Result::unwrap).collect();
+        let _ = std::fs::remove_file(&path);
+        (samples, spec)
+    }
+
+    /// The level and frequency of the first channel of the last 300ms.
+    fn analyze(samples: &[f32], spec: hound::WavSpec) -> (f32, f32) {
+        let frames = spec.sample_rate as usize * 3 / 10;
+        let channels = spec.channels as usize;
+        let tail = samples[samples.len() - frames * channels..]
+            .iter()
+            .step_by(channels)
+            .copied()
+            .collect::<Vec<f32>>();
+        let crossings = tail
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count();
+        (gate::level_db(&tail), crossings as f32 / 2.0 / 0.3)
+    }
+
+    async fn plays_the_sine(codec: &str) {
+        let (samples, spec) = loopback(codec).await;
+        assert_eq!(spec.sample_rate, 48000);
+        assert!(samples.len() > spec.sample_rate as usize * spec.channels as usize / 2);
+        let (level, frequency) = analyze(&samples, spec);
+        // The generator plays at half scale, -9dBFS.
+        assert!(level > -15.0, "{codec} at {level}dB");
+        assert!((frequency - 440.0).abs() < 20.0, "{codec} at {frequency}Hz");
+    }
+
+    #[tokio::test]
+    async fn virtual_sine_through_opus() {
+        plays_the_sine("opus").await;
+    }
+
+    #[tokio::test]
+    async fn virtual_sine_through_pcm() {
+        plays_the_sine("pcm_s16le").await;
     }
 }
@@ -0,0 +1,396 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use audiopus::{coder::Decoder as OpusDecoder, Channels, SampleRate};
+use ogg::PacketReader;
+
+use crate::logic::message::{PlaybackMode, PlaybackState};
+
+use super::convert::{remix, Resampler};
+
+/// Every player is converted to this before it is mixed into the inputs.
+pub const MEDIA_SAMPLE_RATE: u32 = 48000;
+pub const MEDIA_CHANNELS: usize = 2;
+/// Opus always decodes at this rate, granule positions count in it.
+const OPUS_SAMPLE_RATE: u32 = 48000;
+/// Largest Opus packet, 120ms.
+const OPUS_MAX_FRAME: usize = OPUS_SAMPLE_RATE as usize / 1000 * 120;
+/// Decoded before a seek target so the decoder converges, RFC 7845 recommends 80ms.
+const OPUS_PRE_ROLL: u64 = OPUS_SAMPLE_RATE as u64 / 1000 * 80;
+/// The last page is searched for in this much of the end of the file.
+const OGG_TAIL: u64 = 64 * 1024;
+/// After a stall the player does not catch up more than this.
+const MAX_CATCH_UP_MS: u64 = 200;
+
+/// Reads a file as interleaved `f32` samples.
+trait Decoder: Send {
+    fn sample_rate(&self) -> u32;
+    fn channels(&self) -> usize;
+    /// Length in samples per channel, if it is known.
+    fn len(&self) -> Option<u64>;
+    /// Appends up to `frames` samples per channel, returns how many, `0` at the end.
+    fn read(&mut self, frames: usize, output: &mut Vec<f32>) -> Result<usize, String>;
+    /// Continues reading at `frame` samples per channel into the file.
+    fn seek(&mut self, frame: u64) -> Result<(), String>;
+}
+
+/// Picks the decoder by the magic at the start of the file.
+fn open_decoder(path: &Path) -> Result<Box<dyn Decoder>, String> {
+    let mut magic = [0; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map_err(|error| error.to_string())?;
+    match &magic {
+        b"RIFF" => Ok(Box::new(WavDecoder::new(path)?)),
+        b"OggS" => Ok(Box::new(OggOpusDecoder::new(path)?)),
+        _ => Err("Unsupported file, only WAV and Ogg Opus can be played".into()),
+    }
+}
+
+struct WavDecoder {
+    reader: hound::WavReader<BufReader<File>>,
+    spec: hound::WavSpec,
+}
+
+impl WavDecoder {
+    fn new(path: &Path) -> Result<Self, String> {
+        let reader = hound::WavReader::open(path).map_err(|error| error.to_string())?;
+        Ok(Self {
+            spec: reader.spec(),
+            reader,
+        })
+    }
+}
+
+impl Decoder for WavDecoder {
+    fn sample_rate(&self) -> u32 {
+        self.spec.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.spec.channels as usize
+    }
+
+    fn len(&self) -> Option<u64> {
+        Some(self.reader.duration() as u64)
+    }
+
+    fn read(&mut self, frames: usize, output: &mut Vec<f32>) -> Result<usize, String> {
+        let len = frames * self.channels();
+        let before = output.len();
+        match self.spec.sample_format {
+            hound::SampleFormat::Float => {
+                for sample in self.reader.samples::<f32>().take(len) {
+                    output.push(sample.map_err(|error| error.to_string())?);
+                }
+            }
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1u64 << (self.spec.bits_per_sample - 1)) as f32;
+                for sample in self.reader.samples::<i32>().take(len) {
+                    output.push(sample.map_err(|error| error.to_string())? as f32 * scale);
+                }
+            }
+        }
+        Ok((output.len() - before) / self.channels().max(1))
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        let frame = frame.min(self.reader.duration() as u64) as u32;
+        self.reader.seek(frame).map_err(|error| error.to_string())
+    }
+}
+
+/// Ogg Opus as in RFC 7845, one logical stream with mono or stereo.
+struct OggOpusDecoder {
+    packets: PacketReader<BufReader<File>>,
+    decoder: OpusDecoder,
+    channels: usize,
+    pre_skip: u64,
+    /// Samples per channel without the pre skip, from the granule of the last page.
+    len: Option<u64>,
+    /// Samples per channel that were returned, to cut the padding of the last packet.
+    position: u64,
+    /// Samples per channel that are decoded but belong before the position.
+    skip: u64,
+    decoded: Vec<f32>,
+    buffer: Vec<f32>,
+}
+
+impl OggOpusDecoder {
+    fn new(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|error| error.to_string())?;
+        let len = last_granule(&file).map_err(|error| error.to_string())?;
+        let mut packets = PacketReader::new(BufReader::new(file));
+
+        let head = packets
+            .read_packet()
+            .map_err(|error| error.to_string())?
+            .ok_or("Empty Ogg file")?
+            .data;
+        if head.len() < 19 || &head[..8] != b"OpusHead" {
+            return Err("Only Opus is supported in Ogg files".into());
+        }
+        let channels = head[9] as usize;
+        let pre_skip = u16::from_le_bytes([head[10], head[11]]) as u64;
+        // Mapping family 0 is mono or stereo without a mapping table.
+        if head[18] != 0 || !(1..=2).contains(&channels) {
+            return Err("Only mono and stereo Opus files are supported".into());
+        }
+
+        let mut decoder = Self {
+            packets,
+            decoder: new_opus_decoder(channels)?,
+            channels,
+            pre_skip,
+            len: len.map(|len| len.saturating_sub(pre_skip)),
+            position: 0,
+            skip: 0,
+            decoded: Vec::new(),
+            buffer: vec![0.0; OPUS_MAX_FRAME * channels],
+        };
+        decoder.seek(0)?;
+        Ok(decoder)
+    }
+
+    fn next_packet(&mut self) -> Result<Option<Vec<u8>>, String> {
+        self.packets
+            .read_packet()
+            .map(|packet| packet.map(|packet| packet.data))
+            .map_err(|error| error.to_string())
+    }
+
+    fn decode(&mut self, packet: &[u8]) -> Result<(), String> {
+        let len = self
+            .decoder
+            .decode_float(
+                Some(packet.try_into().map_err(|error| format!("{error}"))?),
+                self.buffer.as_mut_slice().try_into().unwrap(),
+                false,
+            )
+            .map_err(|error| error.to_string())?;
+        self.decoded
+            .extend_from_slice(&self.buffer[..len * self.channels]);
+        Ok(())
+    }
+}
+
+impl Decoder for OggOpusDecoder {
+    fn sample_rate(&self) -> u32 {
+        OPUS_SAMPLE_RATE
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn len(&self) -> Option<u64> {
+        self.len
+    }
+
+    fn read(&mut self, frames: usize, output: &mut Vec<f32>) -> Result<usize, String> {
+        let frames = match self.len {
+            Some(len) => frames.min(len.saturating_sub(self.position) as usize),
+            None => frames,
+        };
+        while self.decoded.len() < (self.skip as usize + frames) * self.channels {
+            let Some(packet) = self.next_packet()? else {
+                break;
+            };
+            self.decode(&packet)?;
+        }
+
+        let skip = (self.skip as usize * self.channels).min(self.decoded.len());
+        self.decoded.drain(..skip);
+        self.skip -= (skip / self.channels) as u64;
+
+        let len = (frames * self.channels).min(self.decoded.len());
+        output.extend(self.decoded.drain(..len));
+        let read = len / self.channels;
+        self.position += read as u64;
+        Ok(read)
+    }
+
+    /// Reads from the start without decoding up to the pre roll before `frame`,
+    /// packets only have to be counted for that.
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        self.packets
+            .seek_bytes(SeekFrom::Start(0))
+            .map_err(|error| error.to_string())?;
+        // Identification and comment header.
+        self.next_packet()?;
+        self.next_packet()?;
+        self.decoder = new_opus_decoder(self.channels)?;
+        self.decoded.clear();
+
+        let target = frame + self.pre_skip;
+        let start = target.saturating_sub(OPUS_PRE_ROLL);
+        let mut granule = 0;
+        self.skip = 0;
+        while let Some(packet) = self.next_packet()? {
+            let samples = self
+                .decoder
+                .nb_samples(
+                    packet
+                        .as_slice()
+                        .try_into()
+                        .map_err(|error| format!("{error}"))?,
+                )
+                .map_err(|error| error.to_string())? as u64;
+            if granule + samples <= start {
+                granule += samples;
+                continue;
+            }
+            self.decode(&packet)?;
+            self.skip = target.saturating_sub(granule);
+            break;
+        }
+        self.position = frame;
+        Ok(())
+    }
+}
+
+fn new_opus_decoder(channels: usize) -> Result<OpusDecoder, String> {
+    let channels = if channels == 1 {
+        Channels::Mono
+    } else {
+        Channels::Stereo
+    };
+    OpusDecoder::new(SampleRate::Hz48000, channels).map_err(|error| error.to_string())
+}
+
+/// Granule position of the last Ogg page, if one is found near the end.
+fn last_granule(mut file: &File) -> std::io::Result<Option<u64>> {
+    let size = file.seek(SeekFrom::End(0))?;
+    let start = size.saturating_sub(OGG_TAIL);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    // The page header is `OggS`, version, flags and the granule position.
+    Ok(tail
+        .windows(4)
+        .rposition(|window| window == b"OggS")
+        .and_then(|index| tail.get(index + 6..index + 14))
+        .map(|granule| u64::from_le_bytes(granule.try_into().unwrap())))
+}
+
+/// A file played into the input channels, paced by the wall clock.
+pub struct Player {
+    pub id: usize,
+    pub mode: PlaybackMode,
+    pub looping: bool,
+    decoder: Box<dyn Decoder>,
+    paused: bool,
+    /// Samples per channel into the file.
+    position: u64,
+    /// Samples per channel produced, and when production was at a count.
+    produced: u64,
+    clock: (Instant, u64),
+    resampler: Resampler,
+    decoded: Vec<f32>,
+    remixed: Vec<f32>,
+    /// What was played by the last call to [`Player::process`], in the media format.
+    pub output: Vec<f32>,
+}
+
+impl Player {
+    pub fn new(id: usize, path: &Path, mode: PlaybackMode, looping: bool) -> Result<Self, String> {
+        let decoder = open_decoder(path)?;
+        Ok(Self {
+            id,
+            mode,
+            looping,
+            resampler: Resampler::new(decoder.sample_rate(), MEDIA_SAMPLE_RATE, MEDIA_CHANNELS),
+            decoder,
+            paused: false,
+            position: 0,
+            produced: 0,
+            clock: (Instant::now(), 0),
+            decoded: Vec::new(),
+            remixed: Vec::new(),
+            output: Vec::new(),
+        })
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.paused && !paused {
+            self.clock = (Instant::now(), self.produced);
+        }
+        self.paused = paused;
+    }
+
+    pub fn seek(&mut self, position: Duration) -> Result<(), String> {
+        let mut frame = (position.as_secs_f64() * self.decoder.sample_rate() as f64) as u64;
+        if let Some(len) = self.decoder.len() {
+            frame = frame.min(len);
+        }
+        self.decoder.seek(frame)?;
+        self.position = frame;
+        Ok(())
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        let sample_rate = self.decoder.sample_rate().max(1) as f64;
+        PlaybackState {
+            position: Duration::from_secs_f64(self.position as f64 / sample_rate),
+            length: self
+                .decoder
+                .len()
+                .map(|len| Duration::from_secs_f64(len as f64 / sample_rate)),
+            paused: self.paused,
+            looping: self.looping,
+            mode: self.mode,
+        }
+    }
+
+    /// Decodes what is due since the last call into `output`, `false` once the file ended.
+    pub fn process(&mut self) -> Result<bool, String> {
+        self.output.clear();
+        if self.paused {
+            return Ok(true);
+        }
+
+        let sample_rate = self.decoder.sample_rate() as u64;
+        let (start, at) = self.clock;
+        let mut due = (at + (start.elapsed().as_secs_f64() * sample_rate as f64) as u64)
+            .saturating_sub(self.produced);
+        let max = sample_rate * MAX_CATCH_UP_MS / 1000;
+        if due > max {
+            due = max;
+            self.clock = (Instant::now(), self.produced + due);
+        }
+
+        self.decoded.clear();
+        let mut left = due as usize;
+        while left > 0 {
+            let read = self.decoder.read(left, &mut self.decoded)?;
+            if read == 0 {
+                if !self.looping || self.position == 0 {
+                    break;
+                }
+                self.decoder.seek(0)?;
+                self.position = 0;
+                continue;
+            }
+            left -= read;
+            self.position += read as u64;
+        }
+        let read = due - left as u64;
+        self.produced += read;
+
+        self.remixed.clear();
+        remix(
+            &self.decoded,
+            self.decoder.channels(),
+            MEDIA_CHANNELS,
+            &mut self.remixed,
+        );
+        self.resampler.process(&self.remixed, &mut self.output);
+        Ok(left == 0)
+    }
+}
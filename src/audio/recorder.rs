@@ -0,0 +1,398 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use audiopus::{coder::Encoder, Application, Channels, SampleRate};
+use ogg::{PacketWriteEndInfo, PacketWriter};
+
+use crate::logic::message::RecordingFormat;
+
+use super::convert::{remix, Resampler};
+
+/// Every track is converted to this before it is written.
+pub const RECORD_SAMPLE_RATE: u32 = 48000;
+pub const RECORD_CHANNELS: usize = 2;
+/// Tracks are written this far behind real time, so every device delivered its part.
+const RECORD_DELAY: Duration = Duration::from_millis(200);
+/// A track that got further ahead than this is cut back, its clock is faster than ours.
+const MAX_AHEAD_MS: usize = 500;
+/// 20ms Opus frames.
+const OPUS_FRAME: usize = RECORD_SAMPLE_RATE as usize / 50;
+/// Ogg pages are closed after this many packets, so little is lost when we crash.
+const OPUS_PAGE_PACKETS: u32 = 50;
+
+/// Writes interleaved samples at [`RECORD_SAMPLE_RATE`] with [`RECORD_CHANNELS`].
+trait Writer: Send {
+    fn write(&mut self, samples: &[f32]) -> Result<(), String>;
+    fn finish(self: Box<Self>) -> Result<(), String>;
+}
+
+fn create_writer(path: &Path, format: RecordingFormat) -> Result<Box<dyn Writer>, String> {
+    Ok(match format {
+        RecordingFormat::Wav => Box::new(WavWriter::new(path)?),
+        RecordingFormat::OggOpus => Box::new(OggOpusWriter::new(path)?),
+    })
+}
+
+struct WavWriter(hound::WavWriter<BufWriter<File>>);
+
+impl WavWriter {
+    fn new(path: &Path) -> Result<Self, String> {
+        let spec = hound::WavSpec {
+            channels: RECORD_CHANNELS as u16,
+            sample_rate: RECORD_SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        hound::WavWriter::create(path, spec)
+            .map(Self)
+            .map_err(|error| error.to_string())
+    }
+}
+
+impl Writer for WavWriter {
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        for sample in samples.iter() {
+            self.0
+                .write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+                .map_err(|error| error.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        self.0.finalize().map_err(|error| error.to_string())
+    }
+}
+
+struct OggOpusWriter {
+    encoder: Encoder,
+    packets: PacketWriter<BufWriter<File>>,
+    serial: u32,
+    pre_skip: u64,
+    /// Samples per channel that were encoded.
+    encoded: u64,
+    /// Input that does not fill a frame yet.
+    pending: Vec<f32>,
+    /// Held back until we know if it is the last packet of the stream.
+    last: Option<(Vec<u8>, u64)>,
+    page_packets: u32,
+    output: Vec<u8>,
+}
+
+impl OggOpusWriter {
+    fn new(path: &Path) -> Result<Self, String> {
+        let encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)
+            .map_err(|error| error.to_string())?;
+        let pre_skip = encoder.lookahead().map_err(|error| error.to_string())? as u64;
+        let file = File::create(path).map_err(|error| error.to_string())?;
+        let mut packets = PacketWriter::new(BufWriter::new(file));
+        // Only has to differ from other streams in the same file, there are none.
+        let serial = 1;
+
+        // RFC 7845, identification and comment headers each on their own page.
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(RECORD_CHANNELS as u8);
+        head.extend((pre_skip as u16).to_le_bytes());
+        head.extend(RECORD_SAMPLE_RATE.to_le_bytes());
+        head.extend(0i16.to_le_bytes());
+        head.push(0);
+        packets
+            .write_packet(head.into(), serial, PacketWriteEndInfo::EndPage, 0)
+            .map_err(|error| error.to_string())?;
+
+        let vendor = b"TheMan";
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend((vendor.len() as u32).to_le_bytes());
+        tags.extend(vendor);
+        tags.extend(0u32.to_le_bytes());
+        packets
+            .write_packet(tags.into(), serial, PacketWriteEndInfo::EndPage, 0)
+            .map_err(|error| error.to_string())?;
+
+        Ok(Self {
+            encoder,
+            packets,
+            serial,
+            pre_skip,
+            encoded: 0,
+            pending: Vec::new(),
+            last: None,
+            page_packets: 0,
+            output: vec![0; 4000],
+        })
+    }
+
+    /// Encodes one full frame from `pending`, `samples` per channel of it are real audio.
+    fn encode_frame(&mut self, samples: usize) -> Result<(), String> {
+        let frame = self
+            .pending
+            .drain(..OPUS_FRAME * RECORD_CHANNELS)
+            .collect::<Vec<f32>>();
+        let len = self
+            .encoder
+            .encode_float(&frame, &mut self.output)
+            .map_err(|error| error.to_string())?;
+        self.encoded += samples as u64;
+        let packet = (self.output[..len].to_vec(), self.pre_skip + self.encoded);
+
+        if let Some((data, granule)) = self.last.replace(packet) {
+            self.page_packets += 1;
+            let end = if self.page_packets >= OPUS_PAGE_PACKETS {
+                self.page_packets = 0;
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            self.packets
+                .write_packet(data.into(), self.serial, end, granule)
+                .map_err(|error| error.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl Writer for OggOpusWriter {
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.pending.extend_from_slice(samples);
+        while self.pending.len() >= OPUS_FRAME * RECORD_CHANNELS {
+            self.encode_frame(OPUS_FRAME)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        // The encoder is `pre_skip` behind, silence flushes the end out of it.
+        // The granule position of the last page trims the padding again.
+        let mut samples = self.pending.len() / RECORD_CHANNELS;
+        let frames = (samples + self.pre_skip as usize).div_ceil(OPUS_FRAME);
+        self.pending
+            .resize(frames * OPUS_FRAME * RECORD_CHANNELS, 0.0);
+        for _ in 0..frames {
+            let frame = samples.min(OPUS_FRAME);
+            samples -= frame;
+            self.encode_frame(frame)?;
+        }
+        if let Some((data, granule)) = self.last.take() {
+            self.packets
+                .write_packet(
+                    data.into(),
+                    self.serial,
+                    PacketWriteEndInfo::EndStream,
+                    granule,
+                )
+                .map_err(|error| error.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// One participant, converted to the recording format.
+struct Track {
+    name: String,
+    queue: VecDeque<f32>,
+    from: Option<(u32, usize)>,
+    resampler: Resampler,
+    remixed: Vec<f32>,
+    resampled: Vec<f32>,
+    /// Only used for multitrack recordings.
+    writer: Option<Box<dyn Writer>>,
+}
+
+impl Track {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            queue: VecDeque::new(),
+            from: None,
+            resampler: Resampler::new(RECORD_SAMPLE_RATE, RECORD_SAMPLE_RATE, RECORD_CHANNELS),
+            remixed: Vec::new(),
+            resampled: Vec::new(),
+            writer: None,
+        }
+    }
+
+    fn push(&mut self, samples: &[f32], sample_rate: u32, channels: usize) {
+        if self.from != Some((sample_rate, channels)) {
+            self.from = Some((sample_rate, channels));
+            self.resampler = Resampler::new(sample_rate, RECORD_SAMPLE_RATE, RECORD_CHANNELS);
+        }
+        self.remixed.clear();
+        remix(samples, channels, RECORD_CHANNELS, &mut self.remixed);
+        self.resampled.clear();
+        self.resampler.process(&self.remixed, &mut self.resampled);
+        self.queue.extend(self.resampled.iter());
+
+        let max = RECORD_SAMPLE_RATE as usize * RECORD_CHANNELS * MAX_AHEAD_MS / 1000;
+        if self.queue.len() > max {
+            let excess = self.queue.len() - max;
+            self.queue.drain(..excess);
+        }
+    }
+
+    /// Takes `len` samples, silence where the track has nothing.
+    fn pop(&mut self, len: usize, output: &mut Vec<f32>) {
+        let available = len.min(self.queue.len());
+        output.extend(self.queue.drain(..available));
+        output.resize(output.len() + len - available, 0.0);
+    }
+}
+
+/// Records the local input and the remote sources of one voice channel.
+///
+/// Tracks are written on our clock, a track that has nothing is silent.
+pub struct Recording {
+    pub id: usize,
+    format: RecordingFormat,
+    /// The file of a mixed recording, the directory of a multitrack one.
+    path: PathBuf,
+    multitrack: bool,
+    start: Instant,
+    /// Samples per channel that were written.
+    written: u64,
+    input: Track,
+    sources: HashMap<usize, Track>,
+    /// Only used for mixed recordings.
+    mixed: Option<Box<dyn Writer>>,
+    samples: Vec<f32>,
+    mix: Vec<f32>,
+}
+
+impl Recording {
+    pub fn new(
+        id: usize,
+        path: PathBuf,
+        format: RecordingFormat,
+        multitrack: bool,
+        sources: Vec<(usize, String)>,
+    ) -> Result<Self, String> {
+        let mut recording = Self {
+            id,
+            format,
+            multitrack,
+            start: Instant::now(),
+            written: 0,
+            input: Track::new("local".into()),
+            sources: HashMap::new(),
+            mixed: None,
+            samples: Vec::new(),
+            mix: Vec::new(),
+            path,
+        };
+
+        if multitrack {
+            std::fs::create_dir_all(&recording.path).map_err(|error| error.to_string())?;
+            recording.input.writer = Some(recording.track_writer("local")?);
+        } else {
+            if let Some(parent) = recording.path.parent() {
+                std::fs::create_dir_all(parent).map_err(|error| error.to_string())?;
+            }
+            recording.mixed = Some(create_writer(&recording.path, format)?);
+        }
+        recording.set_sources(sources)?;
+        Ok(recording)
+    }
+
+    fn track_writer(&self, name: &str) -> Result<Box<dyn Writer>, String> {
+        create_writer(
+            &self
+                .path
+                .join(format!("{name}.{}", self.format.extension())),
+            self.format,
+        )
+    }
+
+    /// Sources that joined get a track, the tracks of sources that left are closed.
+    pub fn set_sources(&mut self, sources: Vec<(usize, String)>) -> Result<(), String> {
+        let left = self
+            .sources
+            .keys()
+            .filter(|id| !sources.iter().any(|(source, _)| source == *id))
+            .copied()
+            .collect::<Vec<usize>>();
+        for id in left {
+            if let Some(writer) = self.sources.remove(&id).and_then(|track| track.writer) {
+                writer.finish()?;
+            }
+        }
+
+        for (id, name) in sources {
+            if self.sources.contains_key(&id) {
+                continue;
+            }
+            let mut track = Track::new(name);
+            if self.multitrack {
+                // Start where the others are, so the tracks line up.
+                let mut writer = self.track_writer(&format!("{}-{id}", track.name))?;
+                let silence = vec![0.0; RECORD_SAMPLE_RATE as usize * RECORD_CHANNELS];
+                let mut left = self.written as usize * RECORD_CHANNELS;
+                while left > 0 {
+                    let len = left.min(silence.len());
+                    writer.write(&silence[..len])?;
+                    left -= len;
+                }
+                track.writer = Some(writer);
+            }
+            self.sources.insert(id, track);
+        }
+        Ok(())
+    }
+
+    pub fn push_input(&mut self, samples: &[f32], sample_rate: u32, channels: usize) {
+        self.input.push(samples, sample_rate, channels);
+    }
+
+    pub fn push_source(&mut self, id: usize, samples: &[f32], sample_rate: u32, channels: usize) {
+        if let Some(track) = self.sources.get_mut(&id) {
+            track.push(samples, sample_rate, channels);
+        }
+    }
+
+    /// Writes every track up to [`RECORD_DELAY`] behind now.
+    pub fn process(&mut self) -> Result<(), String> {
+        let Some(elapsed) = self.start.elapsed().checked_sub(RECORD_DELAY) else {
+            return Ok(());
+        };
+        let due = (elapsed.as_secs_f64() * RECORD_SAMPLE_RATE as f64) as u64;
+        if due <= self.written {
+            return Ok(());
+        }
+        let len = (due - self.written) as usize * RECORD_CHANNELS;
+        self.written = due;
+
+        self.mix.clear();
+        self.mix.resize(len, 0.0);
+        for track in std::iter::once(&mut self.input).chain(self.sources.values_mut()) {
+            self.samples.clear();
+            track.pop(len, &mut self.samples);
+            if let Some(writer) = &mut track.writer {
+                writer.write(&self.samples)?;
+            }
+            for (mix, sample) in self.mix.iter_mut().zip(self.samples.iter()) {
+                *mix += sample;
+            }
+        }
+        if let Some(mixed) = &mut self.mixed {
+            mixed.write(&self.mix)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        let mut result = Ok(());
+        for track in std::iter::once(self.input).chain(self.sources.into_values()) {
+            if let Some(writer) = track.writer {
+                result = result.and(writer.finish());
+            }
+        }
+        if let Some(mixed) = self.mixed {
+            result = result.and(mixed.finish());
+        }
+        result
+    }
+}
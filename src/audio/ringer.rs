@@ -0,0 +1,54 @@
+use std::f32::consts::TAU;
+
+/// The two tones of the ring, summed.
+const TONES: [f32; 2] = [440.0, 480.0];
+const GAIN: f32 = 0.1;
+/// Rings this many seconds of every cadence, then is silent for the rest.
+const RING_SECONDS: u32 = 2;
+const CADENCE_SECONDS: u32 = 4;
+/// Fade at both ends of a ring, so it does not click.
+const FADE_MS: u32 = 10;
+
+/// Plays the ring of an incoming call into the mix.
+pub struct Ringer {
+    sample_rate: u32,
+    /// Samples per channel into the current cadence.
+    position: u32,
+}
+
+impl Ringer {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            position: 0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.position = 0;
+    }
+
+    /// Adds the ring to interleaved `output` of `channels`.
+    pub fn add(&mut self, output: &mut [f32], channels: usize) {
+        let ring = self.sample_rate * RING_SECONDS;
+        let fade = (self.sample_rate * FADE_MS / 1000).max(1);
+        for frame in output.chunks_mut(channels.max(1)) {
+            if self.position < ring {
+                let time = self.position as f32 / self.sample_rate as f32;
+                let envelope =
+                    (self.position.min(ring - self.position) as f32 / fade as f32).min(1.0);
+                let sample = TONES
+                    .iter()
+                    .map(|frequency| (TAU * frequency * time).sin())
+                    .sum::<f32>()
+                    * GAIN
+                    * envelope;
+                for out in frame {
+                    *out += sample;
+                }
+            }
+            self.position = (self.position + 1) % (self.sample_rate * CADENCE_SECONDS);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Name of the host that has the virtual devices, next to the cpal ones.
+pub const VIRTUAL_HOST: &str = "Virtual";
+/// Sample rate of the generators and of written files.
+const VIRTUAL_SAMPLE_RATE: u32 = 48000;
+/// How often the virtual devices move samples, like a device callback.
+const PERIOD: Duration = Duration::from_millis(10);
+
+/// Captures from a generator or a WAV file instead of a microphone.
+///
+/// Names are `Silence`, `Sine <frequency> Hz` and `WAV <path>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VirtualInput {
+    Silence,
+    Sine {
+        frequency: f32,
+    },
+    /// Played in a loop.
+    Wav(PathBuf),
+}
+
+impl VirtualInput {
+    /// Devices listed for the virtual host, WAV files have to be named.
+    pub fn presets() -> Vec<VirtualInput> {
+        vec![
+            VirtualInput::Silence,
+            VirtualInput::Sine { frequency: 440.0 },
+        ]
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        if name == "Silence" {
+            return Some(VirtualInput::Silence);
+        }
+        if let Some(path) = name.strip_prefix("WAV ") {
+            return Some(VirtualInput::Wav(path.into()));
+        }
+        let frequency = name.strip_prefix("Sine ")?.strip_suffix(" Hz")?;
+        Some(VirtualInput::Sine {
+            frequency: frequency.trim().parse().ok()?,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            VirtualInput::Silence => "Silence".into(),
+            VirtualInput::Sine { frequency } => format!("Sine {frequency} Hz"),
+            VirtualInput::Wav(path) => format!("WAV {}", path.display()),
+        }
+    }
+}
+
+/// Plays into a WAV file or nowhere instead of a speaker.
+///
+/// Names are `Null` and `WAV <path>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VirtualOutput {
+    Null,
+    Wav(PathBuf),
+}
+
+impl VirtualOutput {
+    pub fn presets() -> Vec<VirtualOutput> {
+        vec![VirtualOutput::Null]
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        if name == "Null" {
+            return Some(VirtualOutput::Null);
+        }
+        name.strip_prefix("WAV ")
+            .map(|path| VirtualOutput::Wav(path.into()))
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            VirtualOutput::Null => "Null".into(),
+            VirtualOutput::Wav(path) => format!("WAV {}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VirtualDevice {
+    Input(VirtualInput),
+    Output(VirtualOutput),
+}
+
+impl VirtualDevice {
+    pub fn name(&self) -> String {
+        match self {
+            VirtualDevice::Input(input) => input.name(),
+            VirtualDevice::Output(output) => output.name(),
+        }
+    }
+
+    /// Sample rate and channels the device runs at, a WAV input at the ones of the file.
+    pub fn config(&self) -> Result<(u32, u16), String> {
+        match self {
+            VirtualDevice::Input(VirtualInput::Wav(path)) => {
+                let reader = hound::WavReader::open(path).map_err(|error| error.to_string())?;
+                let spec = reader.spec();
+                Ok((spec.sample_rate, spec.channels))
+            }
+            VirtualDevice::Input(_) => Ok((VIRTUAL_SAMPLE_RATE, 1)),
+            VirtualDevice::Output(_) => Ok((VIRTUAL_SAMPLE_RATE, 2)),
+        }
+    }
+
+    /// Captures into `producer` from a thread, paced by the wall clock.
+    pub fn open_input(
+        &self,
+        mut producer: rtrb::Producer<f32>,
+        sample_rate: u32,
+        channels: usize,
+    ) -> Result<VirtualStream, String> {
+        let VirtualDevice::Input(input) = self else {
+            return Err("Not an input device".into());
+        };
+        let mut generator = Generator::new(input, sample_rate, channels)?;
+        Ok(VirtualStream::spawn(sample_rate, move |frames| {
+            // Only whole frames, what does not fit is dropped.
+            let len = (frames * channels).min(producer.slots() / channels * channels);
+            if let Ok(chunk) = producer.write_chunk_uninit(len) {
+                chunk.fill_from_iter(std::iter::from_fn(|| Some(generator.next())));
+            }
+            Ok(())
+        }))
+    }
+
+    /// Plays from `consumer` on a thread, paced by the wall clock.
+    ///
+    /// A WAV output gets silence where `consumer` ran dry, so it stays in time.
+    pub fn open_output(
+        &self,
+        mut consumer: rtrb::Consumer<f32>,
+        sample_rate: u32,
+        channels: usize,
+        error_callback: impl FnMut(String) + Send + 'static,
+    ) -> Result<VirtualStream, String> {
+        let VirtualDevice::Output(output) = self else {
+            return Err("Not an output device".into());
+        };
+        let mut writer = match output {
+            VirtualOutput::Null => None,
+            VirtualOutput::Wav(path) => Some(
+                hound::WavWriter::create(
+                    path,
+                    hound::WavSpec {
+                        channels: channels as u16,
+                        sample_rate,
+                        bits_per_sample: 32,
+                        sample_format: hound::SampleFormat::Float,
+                    },
+                )
+                .map_err(|error| error.to_string())?,
+            ),
+        };
+        let mut error_callback = error_callback;
+        // The writer is finalized when the thread drops it.
+        Ok(VirtualStream::spawn(sample_rate, move |frames| {
+            let len = frames * channels;
+            let available = len.min(consumer.slots());
+            let mut samples = Vec::with_capacity(len);
+            if let Ok(chunk) = consumer.read_chunk(available) {
+                samples.extend(chunk);
+            }
+            samples.resize(len, 0.0);
+            if let Some(writer) = &mut writer {
+                for sample in samples {
+                    writer.write_sample(sample).map_err(|error| {
+                        error_callback(error.to_string());
+                    })?;
+                }
+            }
+            Ok(())
+        }))
+    }
+}
+
+/// Samples of a [`VirtualInput`], interleaved.
+enum Generator {
+    Silence,
+    Sine {
+        phase: f32,
+        step: f32,
+        channels: usize,
+        channel: usize,
+    },
+    Wav {
+        samples: Vec<f32>,
+        position: usize,
+    },
+}
+
+impl Generator {
+    fn new(input: &VirtualInput, sample_rate: u32, channels: usize) -> Result<Self, String> {
+        Ok(match input {
+            VirtualInput::Silence => Generator::Silence,
+            VirtualInput::Sine { frequency } => Generator::Sine {
+                phase: 0.0,
+                step: std::f32::consts::TAU * frequency / sample_rate.max(1) as f32,
+                channels: channels.max(1),
+                channel: 0,
+            },
+            VirtualInput::Wav(path) => {
+                let mut reader = hound::WavReader::open(path).map_err(|error| error.to_string())?;
+                let spec = reader.spec();
+                let samples = match spec.sample_format {
+                    hound::SampleFormat::Float => {
+                        reader.samples::<f32>().collect::<Result<Vec<f32>, _>>()
+                    }
+                    hound::SampleFormat::Int => {
+                        let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+                        reader
+                            .samples::<i32>()
+                            .map(|sample| sample.map(|sample| sample as f32 * scale))
+                            .collect::<Result<Vec<f32>, _>>()
+                    }
+                }
+                .map_err(|error| error.to_string())?;
+                if samples.is_empty() {
+                    Generator::Silence
+                } else {
+                    Generator::Wav {
+                        samples,
+                        position: 0,
+                    }
+                }
+            }
+        })
+    }
+
+    fn next(&mut self) -> f32 {
+        match self {
+            Generator::Silence => 0.0,
+            Generator::Sine {
+                phase,
+                step,
+                channels,
+                channel,
+            } => {
+                let sample = phase.sin() * 0.5;
+                *channel += 1;
+                if *channel == *channels {
+                    *channel = 0;
+                    *phase = (*phase + *step) % std::f32::consts::TAU;
+                }
+                sample
+            }
+            Generator::Wav { samples, position } => {
+                let sample = samples[*position];
+                *position = (*position + 1) % samples.len();
+                sample
+            }
+        }
+    }
+}
+
+/// A thread standing in for a device callback, stopped when dropped.
+pub struct VirtualStream {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl VirtualStream {
+    /// Calls `tick` with the samples per channel that are due, until it fails or is stopped.
+    fn spawn(
+        sample_rate: u32,
+        mut tick: impl FnMut(usize) -> Result<(), ()> + Send + 'static,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = std::thread::spawn(move || {
+            let start = Instant::now();
+            let mut done = 0;
+            while !stopped.load(Ordering::Relaxed) {
+                std::thread::sleep(PERIOD);
+                let due = (start.elapsed().as_secs_f64() * sample_rate as f64) as usize;
+                if tick(due - done).is_err() {
+                    break;
+                }
+                done = due;
+            }
+        });
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for VirtualStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -1,4 +1,11 @@
-use std::{collections::HashMap, io::Write, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
 
 use egui::epaint::ahash::HashSet;
 use libp2p::{
@@ -7,15 +14,41 @@ use libp2p::{
     Multiaddr, PeerId,
 };
 
+use the_man::{
+    network::{packet::CallAnswer, CallEnd, KeySource},
+    Atom,
+};
+
 use crate::{
-    logic::message::Message,
-    save_state::{Account, ChannelType, Friend, TheManSaveState},
+    logic::message::{
+        AudioDevices, AudioMessage, CodecCapabilities, Level, Message, PlaybackState, VoiceMessage,
+        VoiceStats,
+    },
+    save_state::{
+        Account, CaptureMode, Channel, Friend, PeerVoice, TheManSaveState, VoiceSettings,
+    },
     state::PeerStatus,
 };
 
 mod tabs;
 use tabs::*;
 
+/// Statistics reports kept per channel, ten minutes at one a second.
+pub const STATS_HISTORY: usize = 600;
+
+/// Receive statistics reports of a channel with when they arrived, oldest first.
+pub type StatsHistory = VecDeque<(DateTime<Local>, HashMap<PeerId, VoiceStats>)>;
+
+/// Where a direct call is, shown in its window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallStage {
+    Calling,
+    Incoming,
+    /// Since when.
+    Active(Instant),
+    Ended(CallEnd),
+}
+
 pub struct TheManGuiState {
     pub kademlia_status: Option<libp2p::swarm::NetworkInfo>,
     pub save: Option<Option<TheManSaveState>>,
@@ -38,13 +71,49 @@ pub struct TheManGuiState {
     pub friends: Vec<Friend>,
     pub register_names: HashMap<PeerId, String>,
     pub bootstraping: bool,
-    pub channels: Vec<(String, ChannelType)>,
+    pub channels: Vec<Channel>,
+    pub audio_devices: Option<AudioDevices>,
+    pub voice: VoiceSettings,
+    pub push_to_talk: bool,
+    /// Volume, mute and pan of peers in voice channels, friends start with their saved ones.
+    pub peer_voices: HashMap<PeerId, PeerVoice>,
+    /// Capture processing settings, empty until the audio thread sent them.
+    pub dsp: Vec<(String, Atom)>,
+    /// Registered codecs in order of preference, empty until the audio thread sent them.
+    pub codecs: Vec<CodecCapabilities>,
+    pub input_level: Level,
+    /// The microphone was sent in the last level report.
+    pub transmitting: bool,
+    pub peer_levels: HashMap<PeerId, Level>,
+    /// Where every channel we record is written.
+    pub recordings: HashMap<String, PathBuf>,
+    /// The last finished recording of a channel, with the error that stopped it.
+    pub recorded: HashMap<String, (PathBuf, Option<String>)>,
+    /// Peers that told us they record a channel.
+    pub peer_recordings: HashMap<String, HashSet<PeerId>>,
+    /// Files played into the voice channels, by player id.
+    pub playback: HashMap<usize, PlaybackState>,
+    /// Why the last file of a player could not be played.
+    pub playback_errors: HashMap<usize, String>,
+    /// Receive statistics of every channel, the last [`STATS_HISTORY`] reports.
+    pub voice_stats: HashMap<String, StatsHistory>,
+    /// Where the key of every protected voice channel comes from, with its rotation.
+    pub channel_keys: HashMap<String, (KeySource, u32)>,
+    /// Direct calls by id with the other side, ended ones until they are dismissed.
+    pub calls: HashMap<String, (PeerId, CallStage)>,
 }
 
 impl TheManGuiState {
     pub fn send(&mut self, message: Message) {
         let _ = self.sender.try_send(message);
     }
+
+    /// Whether a call rings or is running, there is only one at a time.
+    pub fn in_call(&self) -> bool {
+        self.calls
+            .values()
+            .any(|(_, stage)| !matches!(stage, CallStage::Ended(_)))
+    }
 }
 
 pub struct TheMan {
@@ -75,6 +144,7 @@ impl TheMan {
         tab_manager.register::<TabFriends>(); // 12
         tab_manager.register::<TabAbout>(); //13
         tab_manager.register::<TabPeer>(); // 14
+        tab_manager.register::<TabAudio>(); // 15
 
         tab_manager.execute("o13;");
 
@@ -101,6 +171,23 @@ impl TheMan {
                 register_names: HashMap::new(),
                 channels: vec![],
                 account_id: None,
+                audio_devices: None,
+                voice: VoiceSettings::default(),
+                push_to_talk: false,
+                peer_voices: HashMap::new(),
+                dsp: Vec::new(),
+                codecs: Vec::new(),
+                input_level: Level::default(),
+                transmitting: false,
+                peer_levels: HashMap::new(),
+                recordings: HashMap::new(),
+                recorded: HashMap::new(),
+                peer_recordings: HashMap::new(),
+                playback: HashMap::new(),
+                playback_errors: HashMap::new(),
+                voice_stats: HashMap::new(),
+                channel_keys: HashMap::new(),
+                calls: HashMap::new(),
             },
             should_close: false,
             one_time: false,
@@ -130,6 +217,7 @@ impl TheMan {
                     self.state.voice_connected.clear();
                     self.state.peers.clear();
                     self.state.adresses.clear();
+                    self.state.calls.clear();
                     if let Some(account) = self.state.accounts.get(account_index) {
                         self.state.name = Some(account.name.clone());
                         self.state.channels = account.channels.clone();
@@ -138,6 +226,23 @@ impl TheMan {
                     self.state.account_id = Some(account_index)
                 }
                 Message::Accounts(accounts) => self.state.accounts = accounts,
+                Message::Audio(AudioMessage::Devices(devices)) => {
+                    self.state.audio_devices = Some(devices)
+                }
+                Message::Audio(AudioMessage::SetVoice(voice)) => self.state.voice = voice,
+                Message::Audio(AudioMessage::Dsp(settings)) => self.state.dsp = settings,
+                Message::Audio(AudioMessage::CodecCapabilities(codecs)) => {
+                    self.state.codecs = codecs
+                }
+                Message::Audio(AudioMessage::Playback { id, state }) => {
+                    self.state.playback.insert(id, state);
+                }
+                Message::Audio(AudioMessage::PlaybackStopped { id, error }) => {
+                    self.state.playback.remove(&id);
+                    if let Some(error) = error {
+                        self.state.playback_errors.insert(id, error);
+                    }
+                }
                 Message::Adresses(adresses) => self.state.adresses = adresses,
                 Message::ResSearchForKey(key, query_id) => {
                     self.state.query_id_for_key.insert(key, query_id);
@@ -178,24 +283,115 @@ impl TheMan {
                         self.state.voice_connected.insert(channel, hash);
                     }
                 }
+                Message::Voice(crate::logic::message::VoiceMessage::AutoAccepted(
+                    channel,
+                    peer_id,
+                )) => {
+                    self.state
+                        .voice_connected
+                        .entry(channel)
+                        .or_default()
+                        .insert(peer_id, true);
+                }
                 Message::Voice(crate::logic::message::VoiceMessage::UnRequest(
                     channel,
                     peer_id,
                 )) => {
+                    if let Some(recording) = self.state.peer_recordings.get_mut(&channel) {
+                        recording.remove(&peer_id);
+                    }
                     if let Some(channel) = self.state.voice_connected.get_mut(&channel) {
                         channel.retain(|peer, _| *peer != peer_id);
                     }
                 }
+                Message::Voice(crate::logic::message::VoiceMessage::Levels {
+                    input,
+                    transmitting,
+                    peers,
+                }) => {
+                    self.state.input_level = input;
+                    self.state.transmitting = transmitting;
+                    self.state.peer_levels = peers;
+                }
                 Message::Voice(crate::logic::message::VoiceMessage::Disconnected(peer_id)) => {
                     for (_, channel) in self.state.voice_connected.iter_mut() {
                         channel.retain(|peer, _| *peer != peer_id);
                     }
+                    for (_, recording) in self.state.peer_recordings.iter_mut() {
+                        recording.remove(&peer_id);
+                    }
+                }
+                Message::Voice(crate::logic::message::VoiceMessage::RecordingStarted(
+                    channel,
+                    path,
+                )) => {
+                    self.state.recorded.remove(&channel);
+                    self.state.recordings.insert(channel, path);
+                }
+                Message::Voice(crate::logic::message::VoiceMessage::RecordingStopped(
+                    channel,
+                    error,
+                )) => {
+                    if let Some(path) = self.state.recordings.remove(&channel) {
+                        self.state.recorded.insert(channel, (path, error));
+                    }
+                }
+                Message::Voice(crate::logic::message::VoiceMessage::Stats(channels)) => {
+                    let now = Local::now();
+                    for (channel, peers) in channels {
+                        let history = self.state.voice_stats.entry(channel).or_default();
+                        if history.len() >= STATS_HISTORY {
+                            history.pop_front();
+                        }
+                        history.push_back((now, peers));
+                    }
+                }
+                Message::Voice(crate::logic::message::VoiceMessage::ChannelKey(channel, key)) => {
+                    match key {
+                        Some(key) => self.state.channel_keys.insert(channel, key),
+                        None => self.state.channel_keys.remove(&channel),
+                    };
+                }
+                Message::Voice(crate::logic::message::VoiceMessage::PeerRecording(
+                    channel,
+                    peer_id,
+                    active,
+                )) => {
+                    let recording = self.state.peer_recordings.entry(channel).or_default();
+                    if active {
+                        recording.insert(peer_id);
+                    } else {
+                        recording.remove(&peer_id);
+                    }
+                }
+                Message::Voice(VoiceMessage::Calling(call, peer_id)) => {
+                    self.state.calls.insert(call, (peer_id, CallStage::Calling));
+                }
+                Message::Voice(VoiceMessage::IncomingCall(call, peer_id)) => {
+                    self.state
+                        .calls
+                        .insert(call, (peer_id, CallStage::Incoming));
+                }
+                Message::Voice(VoiceMessage::CallStarted(call, peer_id)) => {
+                    self.state
+                        .calls
+                        .insert(call.clone(), (peer_id, CallStage::Active(Instant::now())));
+                    self.tab_manager.open(11, Some(call));
+                }
+                Message::Voice(VoiceMessage::CallEnded(call, peer_id, reason)) => {
+                    self.state
+                        .calls
+                        .insert(call, (peer_id, CallStage::Ended(reason)));
                 }
                 Message::Gui(crate::logic::message::GuiMessage::Friends(friends)) => {
                     for friend in friends.iter() {
                         self.state
                             .register_names
                             .insert(friend.peer_id, friend.name.clone());
+                        self.state
+                            .peer_voices
+                            .entry(friend.peer_id)
+                            .or_insert_with(|| friend.voice.clone());
                     }
                     self.state.friends = friends;
                 }
@@ -214,7 +410,75 @@ impl TheMan {
 
         self.process_events();
 
+        if self.state.voice.mode == CaptureMode::PushToTalk {
+            // Typing in a text field should not open the microphone.
+            let pressed = !ctx.wants_keyboard_input()
+                && ctx.input(|input| input.key_down(self.state.voice.push_to_talk_key));
+            if pressed != self.state.push_to_talk {
+                self.state.push_to_talk = pressed;
+                self.state
+                    .send(Message::Audio(AudioMessage::PushToTalk(pressed)));
+            }
+        }
+
         self.tab_manager.ui(ctx, &mut self.state);
+        self.call_windows(ctx);
+    }
+
+    /// A window for every call, to answer it or hang up, and to dismiss it once it ended.
+    fn call_windows(&mut self, ctx: &egui::Context) {
+        let mut dismissed = Vec::new();
+        for (call, (peer_id, stage)) in self.state.calls.iter() {
+            let name = self
+                .state
+                .register_names
+                .get(peer_id)
+                .cloned()
+                .unwrap_or_else(|| peer_id.to_string());
+            let send = |message: VoiceMessage| {
+                let _ = self.state.sender.try_send(Message::Voice(message));
+            };
+            egui::Window::new(format!("Call with {name}"))
+                .id(egui::Id::new(call))
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| match stage {
+                    CallStage::Calling => {
+                        ui.label("Ringing...");
+                        if ui.button("Hang up").clicked() {
+                            send(VoiceMessage::HangUp(call.clone()));
+                        }
+                    }
+                    CallStage::Incoming => {
+                        ui.label("Is calling you");
+                        ui.horizontal(|ui| {
+                            if ui.button("Accept").clicked() {
+                                send(VoiceMessage::AnswerCall(call.clone(), CallAnswer::Accept));
+                            }
+                            if ui.button("Decline").clicked() {
+                                send(VoiceMessage::AnswerCall(call.clone(), CallAnswer::Decline));
+                            }
+                        });
+                    }
+                    CallStage::Active(since) => {
+                        let seconds = since.elapsed().as_secs();
+                        ui.label(format!("In call {}:{:02}", seconds / 60, seconds % 60));
+                        if ui.button("Hang up").clicked() {
+                            send(VoiceMessage::HangUp(call.clone()));
+                        }
+                        ctx.request_repaint_after(Duration::from_secs(1));
+                    }
+                    CallStage::Ended(reason) => {
+                        ui.label(format!("Call ended: {}", reason.name()));
+                        if ui.button("Dismiss").clicked() {
+                            dismissed.push(call.clone());
+                        }
+                    }
+                });
+        }
+        for call in dismissed {
+            self.state.calls.remove(&call);
+        }
     }
 
     pub fn save(&mut self) {
@@ -240,7 +504,7 @@ impl TheMan {
 
         let dir = dirs::data_local_dir().unwrap().join("theman");
         if let Some(save) = save_state {
-            std::fs::create_dir_all(dir.clone());
+            let _ = std::fs::create_dir_all(dir.clone());
             if let Ok(mut file) = std::fs::File::options()
                 .write(true)
                 .truncate(true)
@@ -24,13 +24,15 @@ impl Tab for TabAccount {
     ) -> Option<String> {
         if self.name.is_empty() && self.peer_id.is_empty() {
             if let Some(account) = state.accounts.get(self.account_id) {
-                let Ok(private) = Keypair::from_protobuf_encoding(&account.private) else{return None};
+                let Ok(private) = Keypair::from_protobuf_encoding(&account.private) else {
+                    return None;
+                };
                 self.peer_id = PeerId::from(private.public()).to_string();
                 self.name = account.name.clone();
             }
         }
 
-        #[allow(clippy::blocks_in_if_conditions)]
+        #[allow(clippy::blocks_in_conditions)]
         if ui
             .selectable_label(false, format!("Id: {}", self.peer_id))
             .on_hover_ui(|ui| {
@@ -66,7 +68,9 @@ impl Tab for TabAccount {
 
         if ui.button("Load").clicked() {
             if let Some(account) = state.accounts.get(self.account_id) {
-                let Ok(private) = Keypair::from_protobuf_encoding(&account.private) else{return None};
+                let Ok(private) = Keypair::from_protobuf_encoding(&account.private) else {
+                    return None;
+                };
                 self.peer_id = PeerId::from(private.public()).to_string();
                 self.name = account.name.clone();
             }
@@ -101,7 +105,7 @@ impl Tab for TabAccount {
     }
 
     fn recive(&mut self, message: String) {
-        let Ok(num) = message.parse() else {return};
+        let Ok(num) = message.parse() else { return };
         self.account_id = num;
     }
 }
@@ -0,0 +1,257 @@
+use the_man::Atom;
+
+use crate::{
+    audio::virtual_backend::VIRTUAL_HOST,
+    logic::message::{AudioMessage, Message},
+    save_state::AudioSettings,
+};
+
+use super::Tab;
+
+#[derive(Default, Clone)]
+pub struct TabAudio {
+    id: usize,
+    init: bool,
+    /// Names typed for virtual devices, like `WAV <path>`.
+    virtual_input: String,
+    virtual_output: String,
+}
+
+impl Tab for TabAudio {
+    fn name(&self) -> &str {
+        "Audio"
+    }
+
+    fn update(
+        &mut self,
+        ui: &mut egui::Ui,
+        state: &mut crate::gui::TheManGuiState,
+    ) -> Option<String> {
+        if !self.init {
+            self.init = true;
+            state.send(Message::Audio(AudioMessage::GetDevices));
+            state.send(Message::Audio(AudioMessage::GetDsp));
+            state.send(Message::Audio(AudioMessage::GetCodecs));
+        }
+
+        if ui.button("Refresh").clicked() {
+            state.send(Message::Audio(AudioMessage::GetDevices));
+        }
+
+        let Some(devices) = &state.audio_devices else {
+            ui.spinner();
+            return None;
+        };
+
+        let current = AudioSettings {
+            host: Some(devices.host.clone()),
+            input_device: devices.input.clone(),
+            output_device: devices.output.clone(),
+        };
+        let mut settings = current.clone();
+
+        egui::ComboBox::from_label("Host")
+            .selected_text(devices.host.clone())
+            .show_ui(ui, |ui| {
+                for host in devices.hosts.iter() {
+                    ui.selectable_value(&mut settings.host, Some(host.clone()), host);
+                }
+            });
+
+        device_combo_box(ui, "Input", &devices.inputs, &mut settings.input_device);
+        device_combo_box(ui, "Output", &devices.outputs, &mut settings.output_device);
+
+        if devices.host == VIRTUAL_HOST {
+            ui.label(
+                "Inputs: Silence, Sine <frequency> Hz, WAV <path>. Outputs: Null, WAV <path>.",
+            );
+            virtual_device_edit(
+                ui,
+                "Input",
+                &mut self.virtual_input,
+                &mut settings.input_device,
+            );
+            virtual_device_edit(
+                ui,
+                "Output",
+                &mut self.virtual_output,
+                &mut settings.output_device,
+            );
+        }
+
+        if settings != current {
+            // Devices belong to a host, use the defaults of the new one.
+            if settings.host != current.host {
+                settings.input_device = None;
+                settings.output_device = None;
+            }
+            state.send(Message::Audio(AudioMessage::SetDevices(settings)));
+        }
+
+        ui.separator();
+        ui.label("Processing");
+        let mut changed = Vec::new();
+        egui::Grid::new("Processing").show(ui, |ui| {
+            for (key, value) in state.dsp.iter() {
+                ui.label(key);
+                let mut value = value.clone();
+                if atom_widget(ui, key, &mut value) {
+                    changed.push((key.clone(), value));
+                }
+                ui.end_row();
+            }
+        });
+        for (key, value) in changed {
+            state.send(Message::Audio(AudioMessage::SetDsp(key, value)));
+        }
+
+        ui.separator();
+        ui.label("Codecs, the first one every peer of a channel has is used");
+        let mut prefer = None;
+        egui::Grid::new("Codecs").striped(true).show(ui, |ui| {
+            ui.label("Name");
+            ui.label("Sample rates");
+            ui.label("Channels");
+            ui.label("Lossless");
+            ui.label("Loss concealment");
+            ui.label("");
+            ui.end_row();
+            for (i, codec) in state.codecs.iter().enumerate() {
+                ui.label(&codec.name).on_hover_text(&codec.description);
+                ui.label(join(&codec.sample_rates));
+                ui.label(join(&codec.channels));
+                ui.label(yes_no(codec.lossless));
+                ui.label(yes_no(codec.loss_concealment));
+                if i > 0 && ui.button("Prefer").clicked() {
+                    prefer = Some(i);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(i) = prefer {
+            let mut names = state
+                .codecs
+                .iter()
+                .map(|codec| codec.name.clone())
+                .collect::<Vec<String>>();
+            let name = names.remove(i);
+            names.insert(0, name);
+            state.send(Message::Audio(AudioMessage::SetCodecOrder(names)));
+        }
+
+        None
+    }
+
+    fn hidden(&self) -> bool {
+        false
+    }
+
+    fn clone_box(&self) -> Box<dyn Tab> {
+        Box::new(self.clone())
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn set_id(&mut self, id: usize) {
+        self.id = id;
+    }
+
+    fn recive(&mut self, _message: String) {}
+}
+
+fn device_combo_box(
+    ui: &mut egui::Ui,
+    label: &str,
+    devices: &[String],
+    selected: &mut Option<String>,
+) {
+    egui::ComboBox::from_label(label)
+        .selected_text(selected.clone().unwrap_or_else(|| "No device".into()))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(selected, None, "Default");
+            for device in devices.iter() {
+                ui.selectable_value(selected, Some(device.clone()), device);
+            }
+        });
+}
+
+/// Edits `value` in place, returns if it changed.
+fn atom_widget(ui: &mut egui::Ui, id: &str, value: &mut Atom) -> bool {
+    let previous = value.clone();
+    match value {
+        Atom::StringValues { value, values } => {
+            egui::ComboBox::from_id_source(id)
+                .selected_text(value.clone())
+                .show_ui(ui, |ui| {
+                    for option in values.iter() {
+                        ui.selectable_value(value, option.clone(), option);
+                    }
+                });
+        }
+        Atom::UnSignedValues { value, values } => {
+            egui::ComboBox::from_id_source(id)
+                .selected_text(value.to_string())
+                .show_ui(ui, |ui| {
+                    for option in values.iter() {
+                        ui.selectable_value(value, *option, option.to_string());
+                    }
+                });
+        }
+        Atom::SignedValues { value, values } => {
+            egui::ComboBox::from_id_source(id)
+                .selected_text(value.to_string())
+                .show_ui(ui, |ui| {
+                    for option in values.iter() {
+                        ui.selectable_value(value, *option, option.to_string());
+                    }
+                });
+        }
+        Atom::Signed { value, range } => {
+            ui.add(egui::Slider::new(value, range.start..=range.end - 1));
+        }
+        Atom::UnSigned { value, range } => {
+            ui.add(egui::Slider::new(value, range.start..=range.end - 1));
+        }
+        Atom::Float { value, range } => {
+            ui.add(egui::Slider::new(value, range.start..=range.end));
+        }
+        Atom::Text(text) => {
+            ui.text_edit_singleline(text);
+        }
+    }
+    *value != previous
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "Yes"
+    } else {
+        "No"
+    }
+}
+
+/// Selects a virtual device by name, files cannot be listed.
+fn virtual_device_edit(
+    ui: &mut egui::Ui,
+    label: &str,
+    name: &mut String,
+    selected: &mut Option<String>,
+) {
+    ui.horizontal(|ui| {
+        ui.label(label);
+        ui.text_edit_singleline(name);
+        if ui.button("Use").clicked() && !name.trim().is_empty() {
+            *selected = Some(name.trim().to_string());
+        }
+    });
+}
@@ -1,4 +1,4 @@
-use crate::save_state::ChannelType;
+use crate::save_state::{Channel, ChannelType};
 
 use super::Tab;
 
@@ -24,21 +24,21 @@ impl Tab for TabChannels {
 
         ui.label("Channels");
         for channel in state.channels.iter() {
-            match &channel.1 {
+            match &channel.kind {
                 ChannelType::Message => {
                     if ui
-                        .selectable_label(false, format!(" {}", channel.0))
+                        .selectable_label(false, format!(" {}", channel.name))
                         .clicked()
                     {
-                        script = format!("o7,{}", channel.0);
+                        script = format!("o7,{}", channel.name);
                     }
                 }
                 ChannelType::Voice => {
                     if ui
-                        .selectable_label(false, format!("響 {}", channel.0))
+                        .selectable_label(false, format!("響 {}", channel.name))
                         .clicked()
                     {
-                        script = format!("o11,{}", channel.0);
+                        script = format!("o11,{}", channel.name);
                     }
                 }
             }
@@ -61,9 +61,12 @@ impl Tab for TabChannels {
         });
 
         if ui.button("Add").clicked() {
-            state
-                .channels
-                .push((self.channel_name.clone(), self.channel_type.clone()));
+            state.channels.push(Channel {
+                name: self.channel_name.clone(),
+                kind: self.channel_type.clone(),
+                auto_accept: Default::default(),
+                membership: None,
+            });
         }
 
         if script.is_empty() {
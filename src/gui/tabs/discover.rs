@@ -237,7 +237,9 @@ impl Tab for TabDiscover {
         // Search by peer_id
         //
 
-        let Some(peer_id) = &self.waiting_for_key else{return None};
+        let Some(peer_id) = &self.waiting_for_key else {
+            return None;
+        };
         if let Some(query_id) = state.query_id_for_key.get(peer_id) {
             ui.label(format!("QueryId: {:?}", query_id));
             if let Some((res, stats, step)) = state.kademlia_query_progress.get(query_id) {
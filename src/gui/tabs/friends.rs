@@ -38,9 +38,12 @@ impl Tab for TabFriends {
                         self.peer_id.clear();
                         let name = self.name.clone();
                         self.name.clear();
-                        state
-                            .friends
-                            .push(crate::save_state::Friend { peer_id, name });
+                        let voice = state.peer_voices.get(&peer_id).cloned().unwrap_or_default();
+                        state.friends.push(crate::save_state::Friend {
+                            peer_id,
+                            name,
+                            voice,
+                        });
                         let _ = state.sender.try_send(crate::logic::message::Message::Gui(
                             crate::logic::message::GuiMessage::Friends(state.friends.clone()),
                         ));
@@ -49,22 +52,32 @@ impl Tab for TabFriends {
             })
         });
 
+        let in_call = state.in_call();
         egui::ScrollArea::both().show(ui, |ui| {
             for friend in state.friends.iter() {
-                if ui
-                    .selectable_label(
-                        false,
-                        format!(
-                            "PeerId: {}, Online: {}, Name: {}",
-                            friend.peer_id,
-                            state.peers.contains_key(&friend.peer_id),
-                            friend.name
-                        ),
-                    )
-                    .clicked()
-                {
-                    message = Some(format!("o14,{}", friend.peer_id))
-                }
+                ui.horizontal(|ui| {
+                    let online = state.peers.contains_key(&friend.peer_id);
+                    if ui
+                        .add_enabled(online && !in_call, egui::Button::new("Call"))
+                        .clicked()
+                    {
+                        let _ = state.sender.try_send(crate::logic::message::Message::Voice(
+                            crate::logic::message::VoiceMessage::Call(friend.peer_id),
+                        ));
+                    }
+                    if ui
+                        .selectable_label(
+                            false,
+                            format!(
+                                "PeerId: {}, Online: {}, Name: {}",
+                                friend.peer_id, online, friend.name
+                            ),
+                        )
+                        .clicked()
+                    {
+                        message = Some(format!("o14,{}", friend.peer_id))
+                    }
+                });
             }
         });
 
@@ -27,7 +27,8 @@ impl Tab for TabMessageChannel {
 
         let Some(topic) = self.topic.clone() else {
             ui.label("This Message Channel Has Not Topic");
-            return None};
+            return None;
+        };
         if !self.initializated {
             state.send(crate::logic::message::Message::SubscribeTopic(
                 topic.clone(),
@@ -95,7 +96,7 @@ impl Tab for TabMessageChannel {
                                                         if ui
                                                             .selectable_label(
                                                                 false,
-                                                                &from.to_string(),
+                                                                from.to_string(),
                                                             )
                                                             .clicked()
                                                         {
@@ -1,6 +1,7 @@
 mod about;
 mod account;
 mod accounts;
+mod audio;
 mod boot_nodes;
 mod channels;
 mod discover;
@@ -17,6 +18,7 @@ mod voice_channel;
 pub use about::TabAbout;
 pub use account::TabAccount;
 pub use accounts::TabAccounts;
+pub use audio::TabAudio;
 pub use boot_nodes::TabBootNodes;
 pub use channels::TabChannels;
 pub use discover::TabDiscover;
@@ -98,7 +100,7 @@ impl TabManager {
                 }
                 'f' => {
                     chars.reverse();
-                    let Ok(num) = String::from_iter(chars).parse::<usize>() else {
+                    let Ok(_num) = String::from_iter(chars).parse::<usize>() else {
                         eprintln!("After o should be a number like: f10");
                         continue;
                     };
@@ -118,11 +120,11 @@ impl TabManager {
                         eprintln!("{message}");
                         continue;
                     };
-                    let Ok(node) = node_str.parse::<usize>() else {
+                    let Ok(_node) = node_str.parse::<usize>() else {
                         eprintln!("{message}");
                         continue;
                     };
-                    let Ok(tab) = tab_str.parse::<usize>() else {
+                    let Ok(_tab) = tab_str.parse::<usize>() else {
                         eprintln!("{message}");
                         continue;
                     };
@@ -140,7 +142,7 @@ impl TabManager {
         };
         let mut used_ids = Vec::new();
         self.state
-            .iter()
+            .iter_main_surface_nodes()
             .flat_map(|node| {
                 if let egui_dock::Node::Leaf { tabs, .. } = node {
                     tabs.iter()
@@ -200,7 +202,7 @@ impl TabManager {
             self.execute(&message);
         }
 
-        for (tab, index) in added_tabs {
+        for (tab, _index) in added_tabs {
             // self.tabs.set_focused_node(index);
             self.open(tab, None)
         }
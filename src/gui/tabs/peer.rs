@@ -21,7 +21,10 @@ impl Tab for TabPeer {
         ui: &mut egui::Ui,
         state: &mut crate::gui::TheManGuiState,
     ) -> Option<String> {
-        let Some(peer_id) = &self.peer_id else{ui.label("No peer selected!");return None};
+        let Some(peer_id) = &self.peer_id else {
+            ui.label("No peer selected!");
+            return None;
+        };
         let mut is_friend = false;
         if let Some(name) = state.register_names.get(peer_id) {
             ui.label(format!("Saved name: {name}"));
@@ -120,6 +123,15 @@ impl Tab for TabPeer {
             }
         }
         ui.separator();
+        let online = state.peers.contains_key(peer_id);
+        if ui
+            .add_enabled(online && !state.in_call(), egui::Button::new("Call"))
+            .clicked()
+        {
+            let _ = state.sender.try_send(crate::logic::message::Message::Voice(
+                crate::logic::message::VoiceMessage::Call(*peer_id),
+            ));
+        }
         if !is_friend {
             ui.label("Add as friend!");
             ui.horizontal(|ui| {
@@ -128,6 +140,7 @@ impl Tab for TabPeer {
                     state.friends.push(crate::save_state::Friend {
                         peer_id: *peer_id,
                         name: self.name.clone(),
+                        voice: state.peer_voices.get(peer_id).cloned().unwrap_or_default(),
                     });
                     let _ = state.sender.try_send(crate::logic::message::Message::Gui(
                         crate::logic::message::GuiMessage::Friends(state.friends.clone()),
@@ -1,13 +1,66 @@
-use crate::logic::message::{Message, VoiceMessage};
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::TimeZone;
+use libp2p::{identity::Keypair, PeerId};
+use the_man::network::{
+    invite::{self, Invite},
+    AutoAccept, KeySource,
+};
+
+use crate::{
+    gui::{CallStage, StatsHistory},
+    logic::{
+        audio::channel_file_path,
+        message::{AudioMessage, Message, PlaybackMode, RecordingFormat, VoiceMessage},
+    },
+    save_state::{CaptureMode, Channel, ChannelType, Friend, Membership, PeerVoice},
+};
 
 use super::Tab;
 
+/// Quietest level the input meter shows, in dBFS.
+const METER_RANGE_DB: f32 = 60.0;
+/// How long a new invite is valid, in seconds.
+const INVITE_EXPIRIES: [(&str, Option<u64>); 4] = [
+    ("Never", None),
+    ("1 hour", Some(60 * 60)),
+    ("1 day", Some(60 * 60 * 24)),
+    ("1 week", Some(60 * 60 * 24 * 7)),
+];
+
 #[derive(Default)]
 pub struct TabVoiceChannel {
     id: usize,
     name: String,
     sender: Option<tokio::sync::mpsc::Sender<crate::logic::message::Message>>,
     init: bool,
+    /// Waiting for the key to bind push to talk to.
+    binding_key: bool,
+    recording_format: RecordingFormat,
+    /// Record every participant to their own file.
+    multitrack: bool,
+    /// File played into the channel, the tab id is the player id.
+    media_path: String,
+    media_mode: PlaybackMode,
+    media_loop: bool,
+    /// Position the seek slider is dragged to.
+    seeking: Option<f32>,
+    /// Where the statistics were exported to, or why they could not be.
+    stats_export: Option<Result<PathBuf, String>>,
+    passphrase: String,
+    /// Token pasted to join a channel with an owner.
+    invite_token: String,
+    /// Who the next invite is for, anyone when empty.
+    invite_peer: String,
+    invite_expires: Option<u64>,
+    membership_error: Option<String>,
+    /// The channel of a direct call, it ends with the call.
+    call: bool,
 }
 
 impl Tab for TabVoiceChannel {
@@ -28,6 +81,7 @@ impl Tab for TabVoiceChannel {
 
         if !self.init {
             self.init = true;
+            self.call = state.calls.contains_key(&self.name);
             let _ = state
                 .sender
                 .try_send(Message::Voice(VoiceMessage::Connect(self.name.clone())));
@@ -53,6 +107,31 @@ impl Tab for TabVoiceChannel {
 
         ui.separator();
 
+        if self.call && !matches!(state.calls.get(&self.name), Some((_, CallStage::Active(_)))) {
+            ui.label("The call ended.");
+            return None;
+        }
+
+        self.voice_controls(ui, state);
+        input_meter(ui, state);
+        self.recording_controls(ui, state);
+        self.media_controls(ui, state);
+        self.stats_panel(ui, state);
+        // Only the other side gets into a call, there is nobody to let in.
+        let allow_list = if self.call {
+            false
+        } else {
+            self.encryption_controls(ui, state);
+            self.membership_controls(ui, state);
+            auto_accept_controls(ui, &self.name, state)
+        };
+        let mut allow = None;
+        // Levels arrive without any input, keep the meters moving.
+        ui.ctx()
+            .request_repaint_after(crate::audio::LEVELS_INTERVAL);
+
+        ui.separator();
+
         ui.allocate_ui_with_layout(
             ui.available_size(),
             egui::Layout::left_to_right(egui::Align::LEFT),
@@ -82,7 +161,27 @@ impl Tab for TabVoiceChannel {
                                             } else {
                                                 format!("PeerId: {peer}")
                                             };
-                                        let res = ui.selectable_label(false, name);
+                                        let mut name = egui::RichText::new(name);
+                                        if state
+                                            .peer_levels
+                                            .get(peer)
+                                            .is_some_and(|level| level.speaking())
+                                        {
+                                            name = name.color(egui::Color32::GREEN);
+                                        }
+                                        let res = ui
+                                            .horizontal(|ui| {
+                                                let res = ui.selectable_label(false, name);
+                                                peer_controls(
+                                                    ui,
+                                                    peer,
+                                                    &mut state.peer_voices,
+                                                    &mut state.friends,
+                                                    &state.sender,
+                                                );
+                                                res
+                                            })
+                                            .inner;
                                         if res.clicked() {
                                             let _ = state.sender.try_send(Message::Voice(
                                                 VoiceMessage::Refuse(self.name.clone(), *peer),
@@ -119,7 +218,16 @@ impl Tab for TabVoiceChannel {
                                                 format!("PeerId: {peer}")
                                             };
 
-                                        let res = ui.selectable_label(false, name);
+                                        let res = ui
+                                            .horizontal(|ui| {
+                                                let res = ui.selectable_label(false, name);
+                                                if allow_list && ui.button("Always allow").clicked()
+                                                {
+                                                    allow = Some(*peer);
+                                                }
+                                                res
+                                            })
+                                            .inner;
                                         if res.clicked() {
                                             let _ = state.sender.try_send(Message::Voice(
                                                 VoiceMessage::Accept(self.name.clone(), *peer),
@@ -137,6 +245,13 @@ impl Tab for TabVoiceChannel {
                 }
             },
         );
+        if let Some(peer) = allow {
+            let mut policy = channel_auto_accept(&self.name, state);
+            if let AutoAccept::AllowList(peers) = &mut policy {
+                peers.push(peer);
+            }
+            set_auto_accept(&self.name, state, policy);
+        }
         message
     }
 
@@ -161,9 +276,756 @@ impl Tab for TabVoiceChannel {
     }
 }
 
+impl TabVoiceChannel {
+    fn voice_controls(&mut self, ui: &mut egui::Ui, state: &mut crate::gui::TheManGuiState) {
+        let mut voice = state.voice.clone();
+
+        ui.horizontal(|ui| {
+            ui.toggle_value(&mut voice.muted, "Mute");
+            ui.toggle_value(&mut voice.deafened, "Deafen");
+
+            ui.separator();
+
+            egui::ComboBox::from_id_source("Capture mode")
+                .selected_text(voice.mode.name())
+                .show_ui(ui, |ui| {
+                    for mode in CaptureMode::ALL {
+                        ui.selectable_value(&mut voice.mode, mode, mode.name());
+                    }
+                });
+
+            match voice.mode {
+                CaptureMode::AlwaysOn => {}
+                CaptureMode::PushToTalk => {
+                    if self.binding_key {
+                        ui.label("Press a key...");
+                        let key = ui.input(|input| {
+                            input.events.iter().find_map(|event| match event {
+                                egui::Event::Key {
+                                    key, pressed: true, ..
+                                } => Some(*key),
+                                _ => None,
+                            })
+                        });
+                        match key {
+                            Some(egui::Key::Escape) => self.binding_key = false,
+                            Some(key) => {
+                                voice.push_to_talk_key = key;
+                                self.binding_key = false;
+                            }
+                            None => {}
+                        }
+                    } else if ui
+                        .button(format!("Key: {}", voice.push_to_talk_key.name()))
+                        .clicked()
+                    {
+                        self.binding_key = true;
+                    }
+                }
+                CaptureMode::VoiceActivity => {
+                    ui.add(
+                        egui::Slider::new(&mut voice.vad_threshold, -90.0..=0.0)
+                            .text("Threshold")
+                            .suffix(" dB"),
+                    );
+                }
+            }
+        });
+
+        if voice != state.voice {
+            state.voice = voice.clone();
+            state.send(Message::Audio(AudioMessage::SetVoice(voice)));
+        }
+    }
+
+    fn media_controls(&mut self, ui: &mut egui::Ui, state: &mut crate::gui::TheManGuiState) {
+        let id = self.id;
+        ui.horizontal(|ui| {
+            let Some(playback) = state.playback.get(&id).cloned() else {
+                ui.label("Play file:");
+                ui.text_edit_singleline(&mut self.media_path);
+                egui::ComboBox::from_id_source("Playback mode")
+                    .selected_text(self.media_mode.name())
+                    .show_ui(ui, |ui| {
+                        for mode in PlaybackMode::ALL {
+                            ui.selectable_value(&mut self.media_mode, mode, mode.name());
+                        }
+                    });
+                ui.checkbox(&mut self.media_loop, "Loop");
+                if ui.button("Play").clicked() && !self.media_path.is_empty() {
+                    state.playback_errors.remove(&id);
+                    state.send(Message::Audio(AudioMessage::PlayFile {
+                        id,
+                        path: self.media_path.clone().into(),
+                        mode: self.media_mode,
+                        looping: self.media_loop,
+                    }));
+                }
+                if let Some(error) = state.playback_errors.get(&id) {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+                return;
+            };
+
+            let label = if playback.paused { "Resume" } else { "Pause" };
+            if ui.button(label).clicked() {
+                state.send(Message::Audio(AudioMessage::PausePlayback {
+                    id,
+                    paused: !playback.paused,
+                }));
+            }
+            if ui.button("Stop").clicked() {
+                state.send(Message::Audio(AudioMessage::StopPlayback { id }));
+            }
+            let mut looping = playback.looping;
+            if ui.checkbox(&mut looping, "Loop").changed() {
+                state.send(Message::Audio(AudioMessage::LoopPlayback { id, looping }));
+            }
+
+            let mut position = self.seeking.unwrap_or(playback.position.as_secs_f32());
+            match playback.length {
+                Some(length) => {
+                    let res = ui.add(
+                        egui::Slider::new(&mut position, 0.0..=length.as_secs_f32())
+                            .custom_formatter(|value, _| format_time(value as f32)),
+                    );
+                    // Seeking an Ogg file reads it from the start, only seek once released.
+                    if res.dragged() {
+                        self.seeking = Some(position);
+                    } else if res.changed() || res.drag_released() {
+                        self.seeking = None;
+                        state.send(Message::Audio(AudioMessage::SeekPlayback {
+                            id,
+                            position: Duration::from_secs_f32(position),
+                        }));
+                    }
+                }
+                None => {
+                    ui.label(format_time(position));
+                }
+            }
+            ui.label(playback.mode.name());
+        });
+    }
+
+    fn recording_controls(&mut self, ui: &mut egui::Ui, state: &mut crate::gui::TheManGuiState) {
+        ui.horizontal(|ui| {
+            if let Some(path) = state.recordings.get(&self.name) {
+                if ui.button("Stop recording").clicked() {
+                    let _ = state
+                        .sender
+                        .try_send(Message::Voice(VoiceMessage::StopRecording(
+                            self.name.clone(),
+                        )));
+                }
+                ui.colored_label(egui::Color32::RED, "Recording");
+                ui.label(path.display().to_string());
+                return;
+            }
+
+            egui::ComboBox::from_id_source("Recording format")
+                .selected_text(self.recording_format.name())
+                .show_ui(ui, |ui| {
+                    for format in RecordingFormat::ALL {
+                        ui.selectable_value(&mut self.recording_format, format, format.name());
+                    }
+                });
+            ui.checkbox(&mut self.multitrack, "Multitrack");
+            if ui.button("Record").clicked() {
+                let _ = state
+                    .sender
+                    .try_send(Message::Voice(VoiceMessage::StartRecording {
+                        channel: self.name.clone(),
+                        format: self.recording_format,
+                        multitrack: self.multitrack,
+                    }));
+            }
+            match state.recorded.get(&self.name) {
+                Some((path, None)) => {
+                    ui.label(format!("Saved to {}", path.display()));
+                }
+                Some((_, Some(error))) => {
+                    ui.colored_label(egui::Color32::RED, format!("Recording failed: {error}"));
+                }
+                None => {}
+            }
+        });
+
+        // Everybody has to know when they are recorded.
+        let recording = state
+            .peer_recordings
+            .get(&self.name)
+            .map(|peers| {
+                peers
+                    .iter()
+                    .map(|peer| {
+                        state
+                            .register_names
+                            .get(peer)
+                            .cloned()
+                            .unwrap_or_else(|| format!("PeerId: {peer}"))
+                    })
+                    .collect::<Vec<String>>()
+            })
+            .unwrap_or_default();
+        if !recording.is_empty() {
+            ui.colored_label(
+                egui::Color32::RED,
+                format!("Recorded by: {}", recording.join(", ")),
+            );
+        }
+    }
+}
+
+impl TabVoiceChannel {
+    fn stats_panel(&mut self, ui: &mut egui::Ui, state: &mut crate::gui::TheManGuiState) {
+        egui::CollapsingHeader::new("Statistics")
+            .id_source("Statistics")
+            .show(ui, |ui| {
+                let history = state.voice_stats.get(&self.name);
+                let Some((_, peers)) = history.and_then(|history| history.back()) else {
+                    ui.label("Nothing received yet");
+                    return;
+                };
+                egui::Grid::new("Statistics grid")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for header in [
+                            "Peer", "Received", "Lost", "Late", "Jitter", "Bitrate", "Buffered",
+                            "Delay",
+                        ] {
+                            ui.label(header);
+                        }
+                        ui.end_row();
+                        for (peer, stats) in peers.iter() {
+                            ui.label(
+                                state
+                                    .register_names
+                                    .get(peer)
+                                    .cloned()
+                                    .unwrap_or_else(|| format!("PeerId: {peer}")),
+                            );
+                            ui.label(stats.received.to_string());
+                            ui.label(format!("{} ({:.1}%)", stats.lost, stats.loss() * 100.0));
+                            ui.label(stats.late.to_string());
+                            ui.label(format!("{:.1} ms", milliseconds(stats.jitter)));
+                            ui.label(format!("{:.1} kbit/s", stats.bitrate as f32 / 1000.0));
+                            ui.label(format!("{:.0} ms", milliseconds(stats.buffered)));
+                            ui.label(stats.delay.map_or("No ping".into(), |delay| {
+                                format!("{:.0} ms", milliseconds(delay))
+                            }));
+                            ui.end_row();
+                        }
+                    });
+
+                ui.horizontal(|ui| {
+                    if ui.button("Export CSV").clicked() {
+                        if let Some(history) = history {
+                            let path = channel_file_path("stats", &self.name).with_extension("csv");
+                            self.stats_export = Some(
+                                export_stats(&path, history, &state.register_names)
+                                    .map(|_| path)
+                                    .map_err(|error| error.to_string()),
+                            );
+                        }
+                    }
+                    match &self.stats_export {
+                        Some(Ok(path)) => {
+                            ui.label(format!("Exported to {}", path.display()));
+                        }
+                        Some(Err(error)) => {
+                            ui.colored_label(egui::Color32::RED, format!("Export failed: {error}"));
+                        }
+                        None => {}
+                    }
+                });
+            });
+    }
+
+    /// Protects the voice with a passphrase or a key we hand out, peers without it are refused.
+    fn encryption_controls(&mut self, ui: &mut egui::Ui, state: &mut crate::gui::TheManGuiState) {
+        let key = state.channel_keys.get(&self.name).cloned();
+        ui.horizontal(|ui| {
+            ui.label("Encryption:");
+            match &key {
+                None => ui.label("Connection only"),
+                Some((KeySource::Passphrase, _)) => ui.label("Passphrase"),
+                Some((KeySource::Created, epoch)) => {
+                    ui.label(format!("Our key, rotated {epoch} times"))
+                }
+                Some((KeySource::Received(peer), epoch)) => {
+                    let name = state
+                        .register_names
+                        .get(peer)
+                        .cloned()
+                        .unwrap_or_else(|| format!("PeerId: {peer}"));
+                    ui.label(format!("Key of {name}, rotated {epoch} times"))
+                }
+            };
+        });
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut self.passphrase)
+                    .password(true)
+                    .hint_text("Passphrase"),
+            );
+            if ui
+                .add_enabled(
+                    !self.passphrase.is_empty(),
+                    egui::Button::new("Set passphrase"),
+                )
+                .clicked()
+            {
+                state.send(Message::Voice(VoiceMessage::SetPassphrase(
+                    self.name.clone(),
+                    std::mem::take(&mut self.passphrase),
+                )));
+            }
+            if ui
+                .button("Create key")
+                .on_hover_text("Peers you accept get it, it changes when one is removed")
+                .clicked()
+            {
+                state.send(Message::Voice(VoiceMessage::CreateKey(self.name.clone())));
+            }
+            if key.is_some() && ui.button("Remove").clicked() {
+                state.send(Message::Voice(VoiceMessage::RemoveKey(self.name.clone())));
+            }
+        });
+    }
+
+    /// Owning the channel, inviting peers or joining with an invite.
+    fn membership_controls(&mut self, ui: &mut egui::Ui, state: &mut crate::gui::TheManGuiState) {
+        let Some(peer_id) = state.peer_id else {
+            return;
+        };
+        let current = channel_membership(&self.name, state);
+        let mut membership = current.clone();
+        egui::CollapsingHeader::new("Membership")
+            .id_source("Membership")
+            .show(ui, |ui| {
+                match &mut membership {
+                    None => {
+                        ui.label("Anyone that knows the name can ask to join");
+                        if ui
+                            .button("Own this channel")
+                            .on_hover_text("Only the peers you invite get in")
+                            .clicked()
+                        {
+                            match own_channel(&self.name, peer_id) {
+                                Ok(owned) => membership = Some(owned),
+                                Err(error) => self.membership_error = Some(error),
+                            }
+                        }
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                egui::TextEdit::singleline(&mut self.invite_token)
+                                    .hint_text("Invite token"),
+                            );
+                            if ui
+                                .add_enabled(
+                                    !self.invite_token.is_empty(),
+                                    egui::Button::new("Join with invite"),
+                                )
+                                .clicked()
+                            {
+                                match join_with_invite(&self.name, peer_id, &self.invite_token) {
+                                    Ok(invite) => {
+                                        self.invite_token.clear();
+                                        membership = Some(Membership {
+                                            owner: None,
+                                            invite,
+                                            invites: Vec::new(),
+                                        });
+                                    }
+                                    Err(error) => self.membership_error = Some(error),
+                                }
+                            }
+                        });
+                    }
+                    Some(owned) if owned.owner.is_some() => {
+                        ui.label("You own this channel, only the peers you invite get in");
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                egui::TextEdit::singleline(&mut self.invite_peer)
+                                    .hint_text("PeerId, anyone when empty"),
+                            );
+                            egui::ComboBox::from_id_source("Invite expires")
+                                .selected_text(
+                                    INVITE_EXPIRIES
+                                        .iter()
+                                        .find(|(_, expires)| *expires == self.invite_expires)
+                                        .map(|(name, _)| *name)
+                                        .unwrap_or_default(),
+                                )
+                                .show_ui(ui, |ui| {
+                                    for (name, expires) in INVITE_EXPIRIES {
+                                        ui.selectable_value(
+                                            &mut self.invite_expires,
+                                            expires,
+                                            name,
+                                        );
+                                    }
+                                });
+                            if ui.button("Invite").clicked() {
+                                match self.sign_invite(owned) {
+                                    Ok(invite) => {
+                                        self.invite_peer.clear();
+                                        owned.invites.push(invite);
+                                    }
+                                    Err(error) => self.membership_error = Some(error),
+                                }
+                            }
+                        });
+                        let mut removed = None;
+                        for (index, invite) in owned.invites.iter().enumerate() {
+                            ui.horizontal(|ui| {
+                                let peer = match invite.peer() {
+                                    Some(peer) => state
+                                        .register_names
+                                        .get(&peer)
+                                        .cloned()
+                                        .unwrap_or_else(|| format!("PeerId: {peer}")),
+                                    None => "Anyone".into(),
+                                };
+                                ui.label(format!("{peer}, {}", format_expires(invite.expires)));
+                                if ui.button("Copy token").clicked() {
+                                    ui.output_mut(|out| out.copied_text = invite.to_token());
+                                }
+                                if ui
+                                    .button("✖")
+                                    .on_hover_text(
+                                        "Forget it, a shared token stays valid until it expires",
+                                    )
+                                    .clicked()
+                                {
+                                    removed = Some(index);
+                                }
+                            });
+                        }
+                        if let Some(index) = removed {
+                            owned.invites.remove(index);
+                        }
+                    }
+                    Some(_) => {
+                        ui.label("Only the peers the owner invited get in");
+                    }
+                }
+                if membership.is_some() && ui.button("Open to everyone").clicked() {
+                    membership = None;
+                }
+                if let Some(error) = &self.membership_error {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+            });
+        if membership != current {
+            self.membership_error = None;
+            set_membership(&self.name, state, membership);
+        }
+    }
+
+    fn sign_invite(&self, membership: &Membership) -> Result<Invite, String> {
+        let owner = membership
+            .owner
+            .as_ref()
+            .ok_or("Only the owner can invite")?;
+        let keypair = Keypair::from_protobuf_encoding(owner).map_err(|error| error.to_string())?;
+        let peer = match self.invite_peer.trim() {
+            "" => None,
+            peer => Some(
+                peer.parse::<PeerId>()
+                    .map_err(|error| format!("Invalid PeerId: {error}"))?,
+            ),
+        };
+        let expires = self
+            .invite_expires
+            .map(|seconds| invite::unix_now() + seconds);
+        Invite::sign(&keypair, self.name.clone(), peer, expires).map_err(|error| error.to_string())
+    }
+}
+
+/// A new owner keypair, with the invite that lets us in.
+fn own_channel(channel: &str, peer_id: PeerId) -> Result<Membership, String> {
+    let keypair = Keypair::generate_ed25519();
+    let invite = Invite::sign(&keypair, channel.to_string(), Some(peer_id), None)
+        .map_err(|error| error.to_string())?;
+    Ok(Membership {
+        owner: Some(
+            keypair
+                .to_protobuf_encoding()
+                .map_err(|error| error.to_string())?,
+        ),
+        invite,
+        invites: Vec::new(),
+    })
+}
+
+/// Checks the token before it is saved, the other peers would refuse us anyway.
+fn join_with_invite(channel: &str, peer_id: PeerId, token: &str) -> Result<Invite, String> {
+    let invite = Invite::from_token(token).map_err(|error| error.to_string())?;
+    let owner = invite.owner().map_err(|error| error.to_string())?;
+    invite
+        .verify(channel, &owner, &peer_id, invite::unix_now())
+        .map_err(|error| error.to_string())?;
+    Ok(invite)
+}
+
+fn format_expires(expires: Option<u64>) -> String {
+    match expires.and_then(|expires| chrono::Utc.timestamp_opt(expires as i64, 0).single()) {
+        Some(expires) => format!(
+            "expires {}",
+            expires
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+        ),
+        None => "never expires".into(),
+    }
+}
+
+fn channel_membership(channel: &str, state: &crate::gui::TheManGuiState) -> Option<Membership> {
+    state
+        .channels
+        .iter()
+        .find(|saved| saved.name == channel && saved.kind == ChannelType::Voice)
+        .and_then(|saved| saved.membership.clone())
+}
+
+/// Keeps the membership with the channel, which is added to the list when missing,
+/// and applies it when our invite changed.
+fn set_membership(
+    channel: &str,
+    state: &mut crate::gui::TheManGuiState,
+    membership: Option<Membership>,
+) {
+    let invite = membership
+        .as_ref()
+        .map(|membership| membership.invite.clone());
+    let previous = channel_membership(channel, state).map(|membership| membership.invite);
+    match state
+        .channels
+        .iter_mut()
+        .find(|saved| saved.name == channel && saved.kind == ChannelType::Voice)
+    {
+        Some(saved) => saved.membership = membership,
+        None => state.channels.push(Channel {
+            name: channel.to_string(),
+            kind: ChannelType::Voice,
+            auto_accept: AutoAccept::default(),
+            membership,
+        }),
+    }
+    if invite != previous {
+        state.send(Message::Voice(VoiceMessage::SetMembership(
+            channel.to_string(),
+            invite,
+        )));
+    }
+}
+
+/// Who joins without a request, returns if peers can be added to an allow list.
+fn auto_accept_controls(
+    ui: &mut egui::Ui,
+    channel: &str,
+    state: &mut crate::gui::TheManGuiState,
+) -> bool {
+    let current = channel_auto_accept(channel, state);
+    let mut policy = current.clone();
+    ui.horizontal(|ui| {
+        ui.label("Auto accept:");
+        egui::ComboBox::from_id_source("Auto accept")
+            .selected_text(policy.name())
+            .show_ui(ui, |ui| {
+                let allow_list = match &current {
+                    AutoAccept::AllowList(peers) => AutoAccept::AllowList(peers.clone()),
+                    _ => AutoAccept::AllowList(Vec::new()),
+                };
+                for option in [
+                    AutoAccept::Manual,
+                    AutoAccept::Friends,
+                    AutoAccept::Everyone,
+                    allow_list,
+                ] {
+                    let name = option.name();
+                    ui.selectable_value(&mut policy, option, name);
+                }
+            });
+    });
+    if let AutoAccept::AllowList(peers) = &mut policy {
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Allowed:");
+            if peers.is_empty() {
+                ui.label("Nobody yet, allow peers from their requests");
+            }
+            let mut removed = None;
+            for (index, peer) in peers.iter().enumerate() {
+                let name = state
+                    .register_names
+                    .get(peer)
+                    .cloned()
+                    .unwrap_or_else(|| format!("PeerId: {peer}"));
+                if ui
+                    .button(format!("{name} ✖"))
+                    .on_hover_text("Remove")
+                    .clicked()
+                {
+                    removed = Some(index);
+                }
+            }
+            if let Some(index) = removed {
+                peers.remove(index);
+            }
+        });
+    }
+    let allow_list = matches!(policy, AutoAccept::AllowList(_));
+    if policy != current {
+        set_auto_accept(channel, state, policy);
+    }
+    allow_list
+}
+
+fn channel_auto_accept(channel: &str, state: &crate::gui::TheManGuiState) -> AutoAccept {
+    state
+        .channels
+        .iter()
+        .find(|saved| saved.name == channel && saved.kind == ChannelType::Voice)
+        .map(|saved| saved.auto_accept.clone())
+        .unwrap_or_default()
+}
+
+/// Applies the policy and keeps it with the channel, which is added to the list when missing.
+fn set_auto_accept(channel: &str, state: &mut crate::gui::TheManGuiState, policy: AutoAccept) {
+    match state
+        .channels
+        .iter_mut()
+        .find(|saved| saved.name == channel && saved.kind == ChannelType::Voice)
+    {
+        Some(saved) => saved.auto_accept = policy.clone(),
+        None => state.channels.push(Channel {
+            name: channel.to_string(),
+            kind: ChannelType::Voice,
+            auto_accept: policy.clone(),
+            membership: None,
+        }),
+    }
+    state.send(Message::Voice(VoiceMessage::SetAutoAccept(
+        channel.to_string(),
+        policy,
+    )));
+}
+
+/// Writes every kept report as CSV, one row per peer and report.
+fn export_stats(
+    path: &Path,
+    history: &StatsHistory,
+    names: &HashMap<PeerId, String>,
+) -> std::io::Result<()> {
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(
+        file,
+        "time,peer,name,received,lost,loss_percent,late,jitter_ms,bitrate,buffered_ms,delay_ms"
+    )?;
+    for (time, peers) in history.iter() {
+        for (peer, stats) in peers.iter() {
+            writeln!(
+                file,
+                "{},{peer},{},{},{},{:.2},{},{:.2},{},{:.1},{}",
+                time.to_rfc3339(),
+                csv_field(names.get(peer).map_or("", String::as_str)),
+                stats.received,
+                stats.lost,
+                stats.loss() * 100.0,
+                stats.late,
+                milliseconds(stats.jitter),
+                stats.bitrate,
+                milliseconds(stats.buffered),
+                stats
+                    .delay
+                    .map(|delay| format!("{:.1}", milliseconds(delay)))
+                    .unwrap_or_default(),
+            )?;
+        }
+    }
+    file.flush()
+}
+
+/// Quoted when it would break the row.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn format_time(seconds: f32) -> String {
+    let seconds = seconds as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Level of the microphone after processing, and if it is sent.
+fn input_meter(ui: &mut egui::Ui, state: &crate::gui::TheManGuiState) {
+    ui.horizontal(|ui| {
+        ui.label("Input:");
+        let level = state.input_level;
+        let text = if level.rms.is_finite() {
+            format!("{:.0} dB", level.rms)
+        } else {
+            "Silence".into()
+        };
+        ui.add(
+            egui::ProgressBar::new(((level.rms + METER_RANGE_DB) / METER_RANGE_DB).clamp(0.0, 1.0))
+                .desired_width(200.0)
+                .text(text),
+        );
+        if state.transmitting {
+            ui.colored_label(egui::Color32::GREEN, "Transmitting");
+        }
+    });
+}
+
+/// Mute, volume and pan of one connected peer.
+fn peer_controls(
+    ui: &mut egui::Ui,
+    peer: &PeerId,
+    voices: &mut HashMap<PeerId, PeerVoice>,
+    friends: &mut [Friend],
+    sender: &tokio::sync::mpsc::Sender<Message>,
+) {
+    let current = voices.get(peer).cloned().unwrap_or_default();
+    let mut voice = current.clone();
+
+    ui.toggle_value(&mut voice.muted, "Mute");
+    ui.add(
+        egui::Slider::new(&mut voice.volume, 0.0..=2.0)
+            .text("Volume")
+            .custom_formatter(|value, _| format!("{:.0}%", value * 100.0)),
+    );
+    ui.add(egui::Slider::new(&mut voice.pan, -1.0..=1.0).text("Pan"));
+
+    if voice != current {
+        // Friends keep their settings across sessions.
+        if let Some(friend) = friends.iter_mut().find(|friend| friend.peer_id == *peer) {
+            friend.voice = voice.clone();
+        }
+        let _ = sender.try_send(Message::Voice(VoiceMessage::SetPeerVoice(
+            *peer,
+            voice.clone(),
+        )));
+        voices.insert(*peer, voice);
+    }
+}
+
 impl Drop for TabVoiceChannel {
     fn drop(&mut self) {
         if let Some(sender) = &mut self.sender {
+            let _ = sender.try_send(Message::Audio(AudioMessage::StopPlayback { id: self.id }));
             let _ = sender.try_send(Message::Voice(VoiceMessage::Disconnect(self.name.clone())));
         }
     }
@@ -1,6 +1,6 @@
 pub mod network;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Atom {
     SignedValues {
         value: isize,
@@ -30,6 +30,14 @@ pub enum Atom {
 }
 
 impl Atom {
+    /// A switch, `"On"` or `"Off"`.
+    pub fn on_off(value: bool) -> Self {
+        Atom::StringValues {
+            value: if value { "On".into() } else { "Off".into() },
+            values: vec!["On".into(), "Off".into()],
+        }
+    }
+
     pub fn valid(&self) -> bool {
         match self {
             Atom::Signed { value, range } => range.contains(value),
@@ -1,5 +1,12 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use libp2p::PeerId;
+use tokio::sync::mpsc::Sender;
+
+use crate::state::PingOk;
+
 use super::{
-    message::{AudioMessage, Message},
+    message::{AudioMessage, Level, Message, VoiceMessage, VoiceStats},
     TheManLogic,
 };
 
@@ -12,17 +19,142 @@ impl TheManLogic {
             Message::Audio(AudioMessage::ResCreateOutputChannel(id, error)) => {
                 println!("Audio created output: Id: {id}, Error: {error}");
             }
-            Message::Audio(AudioMessage::InputData { data, .. }) => {
+            Message::Audio(AudioMessage::Devices(devices)) => {
+                let _ = self
+                    .sender
+                    .try_send(Message::Audio(AudioMessage::Devices(devices)));
+            }
+            Message::Audio(AudioMessage::Levels {
+                input,
+                transmitting,
+                outputs,
+            }) => {
+                let mut peers = HashMap::<PeerId, Level>::new();
+                if let Some(account) = &self.state.account {
+                    for channel in account.voice_channels.values() {
+                        for (peer, id) in channel.iter() {
+                            let Some((_, level)) = outputs.iter().find(|(output, _)| output == id)
+                            else {
+                                continue;
+                            };
+                            // A peer can be in more than one channel with us.
+                            let entry = peers.entry(*peer).or_insert(*level);
+                            if level.rms > entry.rms {
+                                *entry = *level;
+                            }
+                        }
+                    }
+                }
+                let _ = self.sender.try_send(Message::Voice(VoiceMessage::Levels {
+                    input,
+                    transmitting,
+                    peers,
+                }));
+            }
+            Message::Audio(AudioMessage::Stats(outputs)) => {
+                let Some(account) = &self.state.account else {
+                    return;
+                };
+                let mut channels = HashMap::<String, HashMap<PeerId, VoiceStats>>::new();
+                for (channel, peers) in account.voice_channels.iter() {
+                    for (peer, id) in peers.iter() {
+                        let Some((_, stats)) = outputs.iter().find(|(output, _)| output == id)
+                        else {
+                            continue;
+                        };
+                        let mut stats = *stats;
+                        // Half the round trip is the best guess for one way.
+                        if let Some(Some(Ok(PingOk::Ping(_, rtt)))) =
+                            self.state.peers.get(peer).map(|status| &status.ping)
+                        {
+                            stats.delay = Some(*rtt / 2 + stats.buffered);
+                        }
+                        channels
+                            .entry(channel.clone())
+                            .or_default()
+                            .insert(*peer, stats);
+                    }
+                }
+                let _ = self
+                    .sender
+                    .try_send(Message::Voice(VoiceMessage::Stats(channels)));
+            }
+            Message::Audio(AudioMessage::RecordingStopped { id, error }) => {
+                let Some(channel) = self
+                    .recordings
+                    .iter()
+                    .find_map(|(channel, recording)| (*recording == id).then(|| channel.clone()))
+                else {
+                    return;
+                };
+                self.recordings.remove(&channel);
+                if let Some(error) = &error {
+                    eprintln!("Logic: Recording of {channel} stopped: {error}");
+                }
                 if let Some(account) = &mut self.state.account {
                     account
                         .swarm
                         .behaviour_mut()
                         .the_man
-                        .audio_packet("opus".into(), data)
+                        .set_recording(channel.clone(), false);
+                }
+                let _ = self
+                    .sender
+                    .try_send(Message::Voice(VoiceMessage::RecordingStopped(
+                        channel, error,
+                    )));
+            }
+            Message::Audio(message @ AudioMessage::Playback { .. })
+            | Message::Audio(message @ AudioMessage::PlaybackStopped { .. }) => {
+                let _ = self.sender.try_send(Message::Audio(message));
+            }
+            Message::Audio(AudioMessage::Dsp(settings)) => {
+                let _ = self
+                    .sender
+                    .try_send(Message::Audio(AudioMessage::Dsp(settings)));
+            }
+            Message::Audio(AudioMessage::CodecCapabilities(codecs)) => {
+                let _ = self
+                    .sender
+                    .try_send(Message::Audio(AudioMessage::CodecCapabilities(codecs)));
+            }
+            Message::Audio(AudioMessage::Codecs(codecs)) => {
+                self.codecs = codecs;
+                if let Some(account) = &mut self.state.account {
+                    account
+                        .swarm
+                        .behaviour_mut()
+                        .the_man
+                        .set_codecs(self.codecs.clone());
+                }
+            }
+            Message::Audio(AudioMessage::InputData {
+                id,
+                sequence,
+                timestamp,
+                data,
+            }) => {
+                let Some(codec) = self
+                    .input_channels
+                    .iter()
+                    .find_map(|(codec, input)| (*input == id).then_some(codec))
+                else {
+                    return;
+                };
+                if let Some(account) = &mut self.state.account {
+                    account.swarm.behaviour_mut().the_man.audio_packet(
+                        codec.clone(),
+                        sequence,
+                        timestamp,
+                        data,
+                    )
                 }
             }
             Message::Audio(AudioMessage::OutputError { id, error }) => {
                 eprintln!("Logic: AudioOutput id: {id}, has error: {error}");
+                let Some(codec) = self.output_codecs.get(&id).cloned() else {
+                    return;
+                };
                 let _ = self
                     .audio_sender
                     .send(Message::Audio(AudioMessage::DestroyOuputChannel { id }))
@@ -31,12 +163,19 @@ impl TheManLogic {
                     .audio_sender
                     .send(Message::Audio(AudioMessage::CreateOutputChannel {
                         id,
-                        codec: "opus".to_string(),
+                        codec,
                     }))
                     .await;
             }
             Message::Audio(AudioMessage::InputError { id, error }) => {
                 eprintln!("Logic: AudioInput id: {id}, has error: {error}");
+                let Some(codec) = self
+                    .input_channels
+                    .iter()
+                    .find_map(|(codec, input)| (*input == id).then(|| codec.clone()))
+                else {
+                    return;
+                };
                 let _ = self
                     .audio_sender
                     .send(Message::Audio(AudioMessage::DestroyInputChannel { id }))
@@ -45,7 +184,7 @@ impl TheManLogic {
                     .audio_sender
                     .send(Message::Audio(AudioMessage::CreateInputChannel {
                         id,
-                        codec: "opus".to_string(),
+                        codec,
                     }))
                     .await;
             }
@@ -53,3 +192,58 @@ impl TheManLogic {
         }
     }
 }
+
+/// Where a recording of `channel` started now is written, without extension.
+pub fn recording_path(channel: &str) -> PathBuf {
+    channel_file_path("recordings", channel)
+}
+
+/// A file for `channel` named after it and the current time, in `directory` of the app data.
+pub fn channel_file_path(directory: &str, channel: &str) -> PathBuf {
+    let channel = channel
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    dirs::data_local_dir()
+        .unwrap()
+        .join("theman")
+        .join(directory)
+        .join(format!(
+            "{channel}-{}",
+            chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")
+        ))
+}
+
+/// Output channels of `channel`, named by their peer.
+pub fn recording_sources(
+    voice_channels: &HashMap<String, HashMap<PeerId, usize>>,
+    channel: &str,
+) -> Vec<(usize, String)> {
+    voice_channels
+        .get(channel)
+        .into_iter()
+        .flatten()
+        .map(|(peer, id)| (*id, peer.to_string()))
+        .collect()
+}
+
+/// Gives the recording of `channel`, if it is recorded, its current output channels.
+pub fn update_recording(
+    recordings: &HashMap<String, usize>,
+    voice_channels: &HashMap<String, HashMap<PeerId, usize>>,
+    audio_sender: &Sender<Message>,
+    channel: &str,
+) {
+    if let Some(id) = recordings.get(channel) {
+        let _ = audio_sender.try_send(Message::Audio(AudioMessage::SetRecordingSources {
+            id: *id,
+            sources: recording_sources(voice_channels, channel),
+        }));
+    }
+}
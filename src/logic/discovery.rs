@@ -0,0 +1,121 @@
+use libp2p::kad::{record::Key, GetProvidersOk, QueryId, QueryResult, QueryStats};
+
+use crate::save_state::ChannelType;
+
+use super::TheManLogic;
+
+/// How often the providers of joined channels are looked up again,
+/// to find the peers that joined after us.
+pub const DISCOVERY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The DHT key everyone in a channel provides.
+pub fn channel_key(kind: ChannelType, channel: &str) -> Key {
+    let kind = match kind {
+        ChannelType::Message => "message",
+        ChannelType::Voice => "voice",
+    };
+    Key::new(&format!("/the-man/{kind}/{channel}"))
+}
+
+impl TheManLogic {
+    /// Announces that we are in the channel and dials the peers that already are.
+    pub fn provide_channel(&mut self, kind: ChannelType, channel: &str) {
+        let key = channel_key(kind, channel);
+        self.provided.insert(key.clone(), false);
+        self.start_providing(key.clone());
+        self.find_providers(key);
+    }
+
+    pub fn stop_providing_channel(&mut self, kind: ChannelType, channel: &str) {
+        let key = channel_key(kind, channel);
+        self.provided.remove(&key);
+        if let Some(account) = &mut self.state.account {
+            account.swarm.behaviour_mut().kademlia.stop_providing(&key);
+        }
+    }
+
+    /// Looks up the providers of every joined channel again,
+    /// and provides the ones that did not reach any peer the last time.
+    pub fn refresh_discovery(&mut self) {
+        let provided = self
+            .provided
+            .iter()
+            .map(|(key, published)| (key.clone(), *published))
+            .collect::<Vec<(Key, bool)>>();
+        for (key, published) in provided {
+            if !published {
+                self.start_providing(key.clone());
+            }
+            self.find_providers(key);
+        }
+    }
+
+    fn start_providing(&mut self, key: Key) {
+        let Some(account) = &mut self.state.account else {
+            return;
+        };
+        match account
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .start_providing(key.clone())
+        {
+            Ok(id) => {
+                self.provider_queries.insert(id, key);
+            }
+            Err(error) => eprintln!("Cannot provide channel: {error:?}"),
+        }
+    }
+
+    fn find_providers(&mut self, key: Key) {
+        let Some(account) = &mut self.state.account else {
+            return;
+        };
+        let id = account
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .get_providers(key.clone());
+        self.provider_queries.insert(id, key);
+    }
+
+    /// Handles the progress of a query made by the channel discovery.
+    ///
+    /// Returns `false` when the query is not one of ours.
+    pub fn on_discovery_query(
+        &mut self,
+        id: QueryId,
+        result: &QueryResult,
+        stats: &QueryStats,
+        last: bool,
+    ) -> bool {
+        let Some(key) = self.provider_queries.get(&id) else {
+            return false;
+        };
+        match result {
+            QueryResult::StartProviding(result) => {
+                if let Some(published) = self.provided.get_mut(key) {
+                    *published = result.is_ok() && stats.num_successes() > 0;
+                }
+            }
+            QueryResult::GetProviders(Ok(GetProvidersOk::FoundProviders { providers, .. })) => {
+                if let Some(account) = &mut self.state.account {
+                    for peer_id in providers {
+                        if *peer_id == account.peer_id || account.swarm.is_connected(peer_id) {
+                            continue;
+                        }
+                        // Addresses come from the routing table and from the providers query.
+                        if let Err(error) = account.swarm.dial(*peer_id) {
+                            log::debug!("Cannot dial channel provider {peer_id}: {error}");
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        if last {
+            self.provider_queries.remove(&id);
+        }
+        true
+    }
+}
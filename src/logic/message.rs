@@ -1,4 +1,8 @@
-use std::{collections::HashMap, time::Instant};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use chrono::Utc;
 use egui::epaint::ahash::HashSet;
@@ -9,12 +13,26 @@ use libp2p::{
     Multiaddr, PeerId,
 };
 
+use the_man::{
+    network::{
+        invite::Invite,
+        packet::{CallAnswer, CodecInfo},
+        AutoAccept, CallEnd, KeySource,
+    },
+    Atom,
+};
+
 use crate::{
-    save_state::{Account, Friend, TheManSaveState},
+    save_state::{
+        Account, AudioSettings, ChannelType, Friend, PeerVoice, TheManSaveState, VoiceSettings,
+    },
     state::PeerStatus,
 };
 
-use super::TheManLogic;
+use super::{
+    audio::{recording_path, recording_sources, update_recording},
+    TheManLogic,
+};
 
 #[derive(Debug)]
 pub enum GuiMessage {
@@ -24,16 +42,261 @@ pub enum GuiMessage {
 
 #[derive(Debug)]
 pub enum AudioMessage {
-    CreateInputChannel { id: usize, codec: String },
-    CreateOutputChannel { id: usize, codec: String },
+    CreateInputChannel {
+        id: usize,
+        codec: String,
+    },
+    CreateOutputChannel {
+        id: usize,
+        codec: CodecInfo,
+    },
     ResCreateInputChannel(usize, String),
     ResCreateOutputChannel(usize, String),
-    DestroyInputChannel { id: usize },
-    DestroyOuputChannel { id: usize },
-    InputData { id: usize, data: Vec<u8> },
-    OutputData { id: usize, data: Vec<u8> },
-    InputError { id: usize, error: String },
-    OutputError { id: usize, error: String },
+    DestroyInputChannel {
+        id: usize,
+    },
+    DestroyOuputChannel {
+        id: usize,
+    },
+    InputData {
+        id: usize,
+        sequence: u32,
+        timestamp: u32,
+        data: Vec<u8>,
+    },
+    OutputData {
+        id: usize,
+        sequence: u32,
+        timestamp: u32,
+        data: Vec<u8>,
+    },
+    InputError {
+        id: usize,
+        error: String,
+    },
+    OutputError {
+        id: usize,
+        error: String,
+    },
+    GetCodecs,
+    /// What every codec encodes with, in order of preference.
+    Codecs(Vec<CodecInfo>),
+    /// Every registered codec, in order of preference, for the gui.
+    CodecCapabilities(Vec<CodecCapabilities>),
+    /// Codecs to prefer, first the most, the ones not named keep their order after them.
+    SetCodecOrder(Vec<String>),
+    GetDevices,
+    Devices(AudioDevices),
+    SetDevices(AudioSettings),
+    /// Sent to the audio thread on changes and to the gui with the saved settings.
+    SetVoice(VoiceSettings),
+    /// The push to talk key was pressed or released.
+    PushToTalk(bool),
+    SetOutputVoice {
+        id: usize,
+        voice: PeerVoice,
+    },
+    /// Sent by the audio thread every [`crate::audio::LEVELS_INTERVAL`].
+    Levels {
+        input: Level,
+        /// The capture gate was open, what was captured went out.
+        transmitting: bool,
+        outputs: Vec<(usize, Level)>,
+    },
+    /// Sent by the audio thread every [`crate::audio::STATS_INTERVAL`].
+    Stats(Vec<(usize, VoiceStats)>),
+    /// Records the input and `sources`, named by the peer they play.
+    ///
+    /// `path` is a file, or a directory with one file per track when `multitrack`.
+    StartRecording {
+        id: usize,
+        path: PathBuf,
+        format: RecordingFormat,
+        multitrack: bool,
+        sources: Vec<(usize, String)>,
+    },
+    SetRecordingSources {
+        id: usize,
+        sources: Vec<(usize, String)>,
+    },
+    StopRecording {
+        id: usize,
+    },
+    /// The recording was closed, because it was stopped or writing it failed.
+    RecordingStopped {
+        id: usize,
+        error: Option<String>,
+    },
+    /// Plays a file into every input channel, `id` is chosen by the caller.
+    PlayFile {
+        id: usize,
+        path: PathBuf,
+        mode: PlaybackMode,
+        looping: bool,
+    },
+    PausePlayback {
+        id: usize,
+        paused: bool,
+    },
+    SeekPlayback {
+        id: usize,
+        position: Duration,
+    },
+    LoopPlayback {
+        id: usize,
+        looping: bool,
+    },
+    StopPlayback {
+        id: usize,
+    },
+    /// Sent with the levels for every file that is played.
+    Playback {
+        id: usize,
+        state: PlaybackState,
+    },
+    /// The file ended, was stopped or could not be read.
+    PlaybackStopped {
+        id: usize,
+        error: Option<String>,
+    },
+    GetDsp,
+    /// Capture processing settings with their values.
+    Dsp(Vec<(String, Atom)>),
+    SetDsp(String, Atom),
+    /// Plays the ring of an incoming call until it is answered.
+    Ring(bool),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecordingFormat {
+    #[default]
+    OggOpus,
+    Wav,
+}
+
+impl RecordingFormat {
+    pub const ALL: [RecordingFormat; 2] = [RecordingFormat::OggOpus, RecordingFormat::Wav];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordingFormat::OggOpus => "Ogg Opus",
+            RecordingFormat::Wav => "WAV",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordingFormat::OggOpus => "opus",
+            RecordingFormat::Wav => "wav",
+        }
+    }
+}
+
+/// How a played file is combined with the microphone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlaybackMode {
+    #[default]
+    Mix,
+    Replace,
+}
+
+impl PlaybackMode {
+    pub const ALL: [PlaybackMode; 2] = [PlaybackMode::Mix, PlaybackMode::Replace];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlaybackMode::Mix => "Mix with microphone",
+            PlaybackMode::Replace => "Replace microphone",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackState {
+    pub position: Duration,
+    /// Unknown for files that do not say.
+    pub length: Option<Duration>,
+    pub paused: bool,
+    pub looping: bool,
+    pub mode: PlaybackMode,
+}
+
+/// RMS and peak level in dBFS.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    pub rms: f32,
+    pub peak: f32,
+}
+
+impl Level {
+    /// Louder than this counts as talking.
+    pub const SPEAKING_DB: f32 = -50.0;
+
+    pub fn speaking(&self) -> bool {
+        self.rms > Self::SPEAKING_DB
+    }
+}
+
+impl Default for Level {
+    /// Silence.
+    fn default() -> Self {
+        Self {
+            rms: f32::NEG_INFINITY,
+            peak: f32::NEG_INFINITY,
+        }
+    }
+}
+
+/// What a registered codec can do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecCapabilities {
+    pub name: String,
+    pub description: String,
+    pub sample_rates: Vec<u32>,
+    pub channels: Vec<u16>,
+    pub lossless: bool,
+    /// Lost packets are concealed instead of played as silence.
+    pub loss_concealment: bool,
+}
+
+/// What was received from a peer in a voice channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VoiceStats {
+    pub received: u64,
+    /// Never arrived in time and were concealed.
+    pub lost: u64,
+    /// Arrived after they should have played, or dropped to catch up.
+    pub late: u64,
+    /// Interarrival jitter as in RFC 3550.
+    pub jitter: Duration,
+    /// Of what was received since the last stats, in bits per second.
+    pub bitrate: u32,
+    /// Waiting in the jitter buffer and the output queue.
+    pub buffered: Duration,
+    /// Half the ping plus `buffered`, unknown until the peer was pinged.
+    pub delay: Option<Duration>,
+}
+
+impl VoiceStats {
+    /// Lost packets out of every expected one, from `0.0` to `1.0`.
+    pub fn loss(&self) -> f32 {
+        let expected = self.received + self.lost;
+        if expected == 0 {
+            return 0.0;
+        }
+        self.lost as f32 / expected as f32
+    }
+}
+
+/// Hosts and devices that can be selected, with what is in use now.
+#[derive(Debug, Clone, Default)]
+pub struct AudioDevices {
+    pub hosts: Vec<String>,
+    pub host: String,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub input: Option<String>,
+    pub output: Option<String>,
 }
 
 #[derive(Debug)]
@@ -45,6 +308,49 @@ pub enum VoiceMessage {
     Disconnected(PeerId),
     Accept(String, PeerId),
     Refuse(String, PeerId),
+    /// Volume, mute and pan of a peer in every voice channel.
+    SetPeerVoice(PeerId, PeerVoice),
+    /// Levels of the microphone and of every peer that is played.
+    Levels {
+        input: Level,
+        transmitting: bool,
+        peers: HashMap<PeerId, Level>,
+    },
+    StartRecording {
+        channel: String,
+        format: RecordingFormat,
+        multitrack: bool,
+    },
+    StopRecording(String),
+    RecordingStarted(String, PathBuf),
+    RecordingStopped(String, Option<String>),
+    /// A peer in the channel started or stopped recording it.
+    PeerRecording(String, PeerId, bool),
+    /// Who joins the channel without a request, saved with the channel by the gui.
+    SetAutoAccept(String, AutoAccept),
+    /// The channel policy let a peer in without a request.
+    AutoAccepted(String, PeerId),
+    /// Receive statistics of every peer played, by channel.
+    Stats(HashMap<String, HashMap<PeerId, VoiceStats>>),
+    /// Protects the channel with a key derived from the passphrase.
+    SetPassphrase(String, String),
+    /// Protects the channel with a random key that accepted peers get.
+    CreateKey(String),
+    RemoveKey(String),
+    /// Where the channel key comes from and how often it was rotated, `None` when unprotected.
+    ChannelKey(String, Option<(KeySource, u32)>),
+    /// Lets only invited peers into the channel, with our own invite, saved with the channel by the gui.
+    SetMembership(String, Option<Invite>),
+    /// Rings the peer, answered with `Calling` and the call id.
+    Call(PeerId),
+    Calling(String, PeerId),
+    IncomingCall(String, PeerId),
+    AnswerCall(String, CallAnswer),
+    /// Ends the call, or stops ringing.
+    HangUp(String),
+    /// The call talks in the voice channel named by its id.
+    CallStarted(String, PeerId),
+    CallEnded(String, PeerId, CallEnd),
 }
 
 #[derive(Debug)]
@@ -100,9 +406,7 @@ impl TheManLogic {
                                         nodes.push(adress.clone());
                                     } else {
                                         let mut adress = adress.clone();
-                                        adress.push(Protocol::P2p(
-                                            (*peer.node.key.preimage()).into(),
-                                        ));
+                                        adress.push(Protocol::P2p(*peer.node.key.preimage()));
                                     }
                                 }
                             }
@@ -120,6 +424,10 @@ impl TheManLogic {
                         TheManSaveState {
                             bootnodes: nodes,
                             accounts: self.state.accounts.clone(),
+                            audio: self.state.audio.clone(),
+                            voice: self.state.voice.clone(),
+                            dsp: self.state.dsp.clone(),
+                            codec_order: self.state.codec_order.clone(),
                         }
                     };
                     let _ = self
@@ -168,6 +476,12 @@ impl TheManLogic {
                 self.registration_query = None;
                 self.registration_step_1_query = None;
                 self.state.peers.clear();
+                self.provided.clear();
+                self.provider_queries.clear();
+                // Calls end with the swarm of the account.
+                let _ = self
+                    .audio_sender
+                    .try_send(Message::Audio(AudioMessage::Ring(false)));
                 // Cleanup channels!
                 if let Some(account) = &mut self.state.account {
                     for (_, hash) in account.voice_channels.iter() {
@@ -181,6 +495,30 @@ impl TheManLogic {
                 self.state.set_account(account_index);
 
                 if let Some(account) = &mut self.state.account {
+                    let the_man = &mut account.swarm.behaviour_mut().the_man;
+                    the_man.set_codecs(self.codecs.clone());
+                    the_man.set_friends(account.friends.iter().map(|friend| friend.peer_id));
+                    if let Some(saved) = self.state.accounts.get(account_index) {
+                        for channel in saved.channels.iter() {
+                            if channel.kind == ChannelType::Voice {
+                                the_man.set_auto_accept(
+                                    channel.name.clone(),
+                                    channel.auto_accept.clone(),
+                                );
+                                if let Some(membership) = &channel.membership {
+                                    if let Err(error) =
+                                        the_man.set_membership(membership.invite.clone())
+                                    {
+                                        eprintln!(
+                                            "Cannot join {} with its invite: {error}",
+                                            channel.name
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                    }
+
                     let _ = self
                         .sender
                         .try_send(Message::SwarmStatus(account.swarm.network_info()));
@@ -261,11 +599,13 @@ impl TheManLogic {
                     let _ = account.swarm.behaviour_mut().gossipsub.subscribe(&topic);
                     self.subscribed.push(topic.hash());
                 }
+                self.provide_channel(ChannelType::Message, &topic.to_string());
             }
             Message::UnsubscibeTopic(topic) => {
                 if let Some(account) = &mut self.state.account {
                     let _ = account.swarm.behaviour_mut().gossipsub.unsubscribe(&topic);
                 }
+                self.stop_providing_channel(ChannelType::Message, &topic.to_string());
             }
             Message::SendMessage(topic, message) => {
                 if let Some(account) = &mut self.state.account {
@@ -289,11 +629,45 @@ impl TheManLogic {
                 }
             }
             Message::Voice(VoiceMessage::Connect(channel)) => {
+                let mut call = false;
                 if let Some(account) = &mut self.state.account {
-                    account.swarm.behaviour_mut().the_man.connect(channel);
+                    let the_man = &mut account.swarm.behaviour_mut().the_man;
+                    the_man.connect(channel.clone());
+                    call = the_man.is_call(&channel);
+                }
+                // The channel of a call is private.
+                if !call {
+                    self.provide_channel(ChannelType::Voice, &channel);
+                }
+            }
+            Message::Voice(VoiceMessage::Call(peer_id)) => {
+                if let Some(account) = &mut self.state.account {
+                    let call = account.swarm.behaviour_mut().the_man.call(peer_id);
+                    let _ = self
+                        .sender
+                        .try_send(Message::Voice(VoiceMessage::Calling(call, peer_id)));
+                }
+            }
+            Message::Voice(VoiceMessage::AnswerCall(call, answer)) => {
+                if let Some(account) = &mut self.state.account {
+                    account
+                        .swarm
+                        .behaviour_mut()
+                        .the_man
+                        .answer_call(&call, answer);
+                }
+            }
+            Message::Voice(VoiceMessage::HangUp(call)) => {
+                if let Some(account) = &mut self.state.account {
+                    account.swarm.behaviour_mut().the_man.hang_up(&call);
                 }
             }
             Message::Voice(VoiceMessage::Disconnect(channel)) => {
+                if let Some(id) = self.recordings.get(&channel) {
+                    let _ = self
+                        .audio_sender
+                        .try_send(Message::Audio(AudioMessage::StopRecording { id: *id }));
+                }
                 if let Some(account) = &mut self.state.account {
                     if let Some(channel) = account.voice_channels.remove(&channel) {
                         for (_, id) in channel {
@@ -302,8 +676,17 @@ impl TheManLogic {
                                 .try_send(Message::Audio(AudioMessage::DestroyOuputChannel { id }));
                         }
                     }
-                    account.swarm.behaviour_mut().the_man.disconnect(channel);
+                    account
+                        .swarm
+                        .behaviour_mut()
+                        .the_man
+                        .disconnect(channel.clone());
                 }
+                self.update_input_channels();
+                self.stop_providing_channel(ChannelType::Voice, &channel);
+                let _ = self
+                    .sender
+                    .try_send(Message::Voice(VoiceMessage::ChannelKey(channel, None)));
             }
             Message::Voice(VoiceMessage::Accept(channel, peer_id)) => {
                 if let Some(account) = &mut self.state.account {
@@ -314,6 +697,73 @@ impl TheManLogic {
                         .accept(channel, peer_id);
                 }
             }
+            Message::Voice(VoiceMessage::SetPeerVoice(peer_id, voice)) => {
+                if let Some(account) = &mut self.state.account {
+                    if let Some(friend) = account
+                        .friends
+                        .iter_mut()
+                        .find(|friend| friend.peer_id == peer_id)
+                    {
+                        friend.voice = voice.clone();
+                    }
+                    for channel in account.voice_channels.values() {
+                        if let Some(id) = channel.get(&peer_id) {
+                            let _ = self.audio_sender.try_send(Message::Audio(
+                                AudioMessage::SetOutputVoice {
+                                    id: *id,
+                                    voice: voice.clone(),
+                                },
+                            ));
+                        }
+                    }
+                }
+                self.peer_voices.insert(peer_id, voice);
+            }
+            Message::Voice(VoiceMessage::StartRecording {
+                channel,
+                format,
+                multitrack,
+            }) => {
+                if self.recordings.contains_key(&channel) {
+                    return;
+                }
+                if let Some(account) = &mut self.state.account {
+                    let id = self.audio_counter;
+                    self.audio_counter += 1;
+                    let mut path = recording_path(&channel);
+                    if !multitrack {
+                        path.set_extension(format.extension());
+                    }
+                    let _ =
+                        self.audio_sender
+                            .try_send(Message::Audio(AudioMessage::StartRecording {
+                                id,
+                                path: path.clone(),
+                                format,
+                                multitrack,
+                                sources: recording_sources(&account.voice_channels, &channel),
+                            }));
+                    self.recordings.insert(channel.clone(), id);
+                    account
+                        .swarm
+                        .behaviour_mut()
+                        .the_man
+                        .set_recording(channel.clone(), true);
+                    let _ = self
+                        .sender
+                        .try_send(Message::Voice(VoiceMessage::RecordingStarted(
+                            channel, path,
+                        )));
+                }
+            }
+            Message::Voice(VoiceMessage::StopRecording(channel)) => {
+                // The audio thread answers with `RecordingStopped` once the files are closed.
+                if let Some(id) = self.recordings.get(&channel) {
+                    let _ = self
+                        .audio_sender
+                        .try_send(Message::Audio(AudioMessage::StopRecording { id: *id }));
+                }
+            }
             Message::Voice(VoiceMessage::Refuse(channel, peer_id)) => {
                 if let Some(account) = &mut self.state.account {
                     if let Some(channel) = account.voice_channels.get_mut(&channel) {
@@ -323,6 +773,12 @@ impl TheManLogic {
                                 .try_send(Message::Audio(AudioMessage::DestroyOuputChannel { id }));
                         }
                     }
+                    update_recording(
+                        &self.recordings,
+                        &account.voice_channels,
+                        &self.audio_sender,
+                        &channel,
+                    );
                     account
                         .swarm
                         .behaviour_mut()
@@ -330,14 +786,121 @@ impl TheManLogic {
                         .refuse(channel, peer_id);
                 }
             }
+            Message::Voice(VoiceMessage::SetPassphrase(channel, passphrase)) => {
+                if let Some(account) = &mut self.state.account {
+                    account
+                        .swarm
+                        .behaviour_mut()
+                        .the_man
+                        .set_passphrase(channel, &passphrase);
+                }
+            }
+            Message::Voice(VoiceMessage::CreateKey(channel)) => {
+                if let Some(account) = &mut self.state.account {
+                    account.swarm.behaviour_mut().the_man.create_key(channel);
+                }
+            }
+            Message::Voice(VoiceMessage::RemoveKey(channel)) => {
+                if let Some(account) = &mut self.state.account {
+                    account
+                        .swarm
+                        .behaviour_mut()
+                        .the_man
+                        .remove_key(channel.clone());
+                }
+                let _ = self
+                    .sender
+                    .try_send(Message::Voice(VoiceMessage::ChannelKey(channel, None)));
+            }
+            Message::Voice(VoiceMessage::SetMembership(channel, invite)) => {
+                if let Some(account) = &mut self.state.account {
+                    let the_man = &mut account.swarm.behaviour_mut().the_man;
+                    match invite {
+                        Some(invite) => {
+                            if let Err(error) = the_man.set_membership(invite) {
+                                eprintln!("Cannot join {channel} with its invite: {error}");
+                            }
+                        }
+                        None => the_man.remove_membership(&channel),
+                    }
+                }
+            }
+            Message::Voice(VoiceMessage::SetAutoAccept(channel, policy)) => {
+                if let Some(account) = &mut self.state.account {
+                    account
+                        .swarm
+                        .behaviour_mut()
+                        .the_man
+                        .set_auto_accept(channel, policy);
+                }
+            }
             Message::Gui(GuiMessage::Friends(friends)) => {
                 if let Some(account) = &mut self.state.account {
+                    account
+                        .swarm
+                        .behaviour_mut()
+                        .the_man
+                        .set_friends(friends.iter().map(|friend| friend.peer_id));
                     account.friends = friends.clone();
                     let _ = self
                         .sender
                         .try_send(Message::Gui(GuiMessage::Friends(friends)));
                 }
             }
+            Message::Audio(AudioMessage::GetDevices) => {
+                let _ = self
+                    .audio_sender
+                    .try_send(Message::Audio(AudioMessage::GetDevices));
+            }
+            Message::Audio(AudioMessage::GetCodecs) => {
+                let _ = self
+                    .audio_sender
+                    .try_send(Message::Audio(AudioMessage::GetCodecs));
+            }
+            Message::Audio(
+                message @ (AudioMessage::PlayFile { .. }
+                | AudioMessage::PausePlayback { .. }
+                | AudioMessage::SeekPlayback { .. }
+                | AudioMessage::LoopPlayback { .. }
+                | AudioMessage::StopPlayback { .. }),
+            ) => {
+                let _ = self.audio_sender.try_send(Message::Audio(message));
+            }
+            Message::Audio(AudioMessage::SetDevices(settings)) => {
+                self.state.audio = settings.clone();
+                let _ = self
+                    .audio_sender
+                    .try_send(Message::Audio(AudioMessage::SetDevices(settings)));
+            }
+            Message::Audio(AudioMessage::SetVoice(settings)) => {
+                self.state.voice = settings.clone();
+                let _ = self
+                    .audio_sender
+                    .try_send(Message::Audio(AudioMessage::SetVoice(settings)));
+            }
+            Message::Audio(AudioMessage::GetDsp) => {
+                let _ = self
+                    .audio_sender
+                    .try_send(Message::Audio(AudioMessage::GetDsp));
+            }
+            Message::Audio(AudioMessage::SetDsp(key, value)) => {
+                self.state.dsp.retain(|(setting, _)| *setting != key);
+                self.state.dsp.push((key.clone(), value.clone()));
+                let _ = self
+                    .audio_sender
+                    .try_send(Message::Audio(AudioMessage::SetDsp(key, value)));
+            }
+            Message::Audio(AudioMessage::SetCodecOrder(names)) => {
+                self.state.codec_order.clone_from(&names);
+                let _ = self
+                    .audio_sender
+                    .try_send(Message::Audio(AudioMessage::SetCodecOrder(names)));
+            }
+            Message::Audio(AudioMessage::PushToTalk(pressed)) => {
+                let _ = self
+                    .audio_sender
+                    .try_send(Message::Audio(AudioMessage::PushToTalk(pressed)));
+            }
             Message::Gui(GuiMessage::RefreshFriends) => {
                 if let Some(account) = &mut self.state.account {
                     for friend in account.friends.iter() {
@@ -1,12 +1,14 @@
-use std::time::Instant;
+use std::{collections::HashMap, time::Instant};
 
-use crate::state::TheManState;
-use libp2p::{futures::StreamExt, gossipsub::TopicHash};
+use crate::{save_state::PeerVoice, state::TheManState};
+use libp2p::{futures::StreamExt, gossipsub::TopicHash, PeerId};
+use the_man::network::packet::CodecInfo;
 use tokio::sync::mpsc::{Receiver, Sender};
 
 use self::message::Message;
 
 pub mod audio;
+pub mod discovery;
 pub mod message;
 pub mod network;
 
@@ -22,6 +24,20 @@ pub struct TheManLogic {
     pub registration_query: Option<(libp2p::kad::QueryId, Instant)>,
     pub registration_step_1_query: Option<(libp2p::kad::QueryId, Vec<u8>)>,
     pub audio_counter: usize,
+    /// Codecs the audio thread can use, in order of preference.
+    pub codecs: Vec<CodecInfo>,
+    /// Input channel id for every codec that is encoded.
+    pub input_channels: HashMap<String, usize>,
+    /// Codec every output channel was created with.
+    pub output_codecs: HashMap<usize, CodecInfo>,
+    /// Volume, mute and pan set for peers in this session.
+    pub peer_voices: HashMap<PeerId, PeerVoice>,
+    /// Recording id of every channel that is recorded.
+    pub recordings: HashMap<String, usize>,
+    /// Keys of the joined channels, and if providing them reached a peer.
+    pub provided: HashMap<libp2p::kad::record::Key, bool>,
+    /// Queries of the channel discovery, with the channel key they are for.
+    pub provider_queries: HashMap<libp2p::kad::QueryId, libp2p::kad::record::Key>,
 }
 
 impl TheManLogic {
@@ -44,6 +60,13 @@ impl TheManLogic {
             audio_receiver,
             bootstraping: true,
             audio_counter: 0,
+            codecs: Vec::new(),
+            input_channels: HashMap::new(),
+            output_codecs: HashMap::new(),
+            peer_voices: HashMap::new(),
+            recordings: HashMap::new(),
+            provided: HashMap::new(),
+            provider_queries: HashMap::new(),
         }
     }
 
@@ -53,6 +76,35 @@ impl TheManLogic {
             .send(Message::Accounts(self.state.accounts.clone()))
             .await;
 
+        let _ = self
+            .audio_sender
+            .send(Message::Audio(message::AudioMessage::SetDevices(
+                self.state.audio.clone(),
+            )))
+            .await;
+
+        let _ = self
+            .audio_sender
+            .send(Message::Audio(message::AudioMessage::SetVoice(
+                self.state.voice.clone(),
+            )))
+            .await;
+        let _ = self
+            .sender
+            .send(Message::Audio(message::AudioMessage::SetVoice(
+                self.state.voice.clone(),
+            )))
+            .await;
+        for (key, value) in self.state.dsp.iter() {
+            let _ = self
+                .audio_sender
+                .send(Message::Audio(message::AudioMessage::SetDsp(
+                    key.clone(),
+                    value.clone(),
+                )))
+                .await;
+        }
+
         let _ = self
             .audio_sender
             .send(Message::Audio(message::AudioMessage::CreateInputChannel {
@@ -60,9 +112,18 @@ impl TheManLogic {
                 codec: "opus".into(),
             }))
             .await;
-
+        self.input_channels.insert("opus".into(), 0);
         self.audio_counter += 1;
+
+        let _ = self
+            .audio_sender
+            .send(Message::Audio(message::AudioMessage::SetCodecOrder(
+                self.state.codec_order.clone(),
+            )))
+            .await;
+
         let mut renew_account = None;
+        let mut discovery = tokio::time::interval(discovery::DISCOVERY_INTERVAL);
 
         loop {
             if let Some(account) = &mut self.state.account {
@@ -86,6 +147,9 @@ impl TheManLogic {
                     event = account.swarm.select_next_some() => {
                         self.on_event(event).await;
                     }
+                    _ = discovery.tick() => {
+                        self.refresh_discovery();
+                    }
                     _ = tokio::time::sleep_until(renew) => {
                         if account.auto_renew{
                         if self.registration_step_1_query.is_some() && self.registration_step_1_query.is_some(){continue}
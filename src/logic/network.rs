@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use libp2p::swarm::SwarmEvent;
 
@@ -23,6 +23,9 @@ impl TheManLogic {
                                 result,
                                 stats,
                             } => {
+                                if self.on_discovery_query(id, &result, &stats, step.last) {
+                                    return;
+                                }
                                 if let Some(account) = &mut self.state.account {
                                     if id == self.bootstrap.unwrap() {
                                         if step.last && self.bootstraping {
@@ -129,7 +132,7 @@ impl TheManLogic {
                     TheManBehaviourEvent::Identify(event) => {
                         match event {
                             libp2p::identify::Event::Received { peer_id, info } => {
-                                if let Some(account) = &mut self.state.account {
+                                if let Some(_account) = &mut self.state.account {
                                     // account
                                     //     .swarm
                                     //     .add_external_address(info.observed_addr.clone())
@@ -215,17 +218,39 @@ impl TheManLogic {
                             match event {
                                 the_man::network::event::BehaviourEvent::VoicePacket {
                                     from,
-                                    codec: _,
+                                    codec,
+                                    sequence,
+                                    timestamp,
                                     data,
                                     channel,
                                 } => {
                                     let mut id = None;
+                                    let mut created = false;
                                     if let Some(hash) = account.voice_channels.get(&channel) {
                                         if let Some(tmp_id) = hash.get(&from) {
                                             id = Some(*tmp_id);
                                         }
                                     }
 
+                                    // The remote switched codec, the decoder has to be recreated.
+                                    if let Some(id) = id {
+                                        if self.output_codecs.get(&id) != Some(&codec) {
+                                            let _ = self.audio_sender.try_send(Message::Audio(
+                                                super::message::AudioMessage::DestroyOuputChannel {
+                                                    id,
+                                                },
+                                            ));
+                                            let _ = self.audio_sender.try_send(Message::Audio(
+                                                super::message::AudioMessage::CreateOutputChannel {
+                                                    id,
+                                                    codec: codec.clone(),
+                                                },
+                                            ));
+                                            self.output_codecs.insert(id, codec.clone());
+                                            created = true;
+                                        }
+                                    }
+
                                     let id = if let Some(id) = id {
                                         id
                                     } else {
@@ -234,9 +259,10 @@ impl TheManLogic {
                                         let _ = self.audio_sender.try_send(Message::Audio(
                                             super::message::AudioMessage::CreateOutputChannel {
                                                 id,
-                                                codec: "opus".into(),
+                                                codec: codec.clone(),
                                             },
                                         ));
+                                        self.output_codecs.insert(id, codec);
                                         if let Some(channel) =
                                             account.voice_channels.get_mut(&channel)
                                         {
@@ -246,11 +272,44 @@ impl TheManLogic {
                                             hash.insert(from, id);
                                             account.voice_channels.insert(channel.clone(), hash);
                                         }
+                                        created = true;
                                         id
                                     };
 
+                                    if created {
+                                        super::audio::update_recording(
+                                            &self.recordings,
+                                            &account.voice_channels,
+                                            &self.audio_sender,
+                                            &channel,
+                                        );
+                                        let voice = self
+                                            .peer_voices
+                                            .get(&from)
+                                            .or_else(|| {
+                                                account
+                                                    .friends
+                                                    .iter()
+                                                    .find(|friend| friend.peer_id == from)
+                                                    .map(|friend| &friend.voice)
+                                            })
+                                            .cloned()
+                                            .unwrap_or_default();
+                                        let _ = self.audio_sender.try_send(Message::Audio(
+                                            super::message::AudioMessage::SetOutputVoice {
+                                                id,
+                                                voice,
+                                            },
+                                        ));
+                                    }
+
                                     let _ = self.audio_sender.try_send(Message::Audio(
-                                        super::message::AudioMessage::OutputData { id, data },
+                                        super::message::AudioMessage::OutputData {
+                                            id,
+                                            sequence,
+                                            timestamp,
+                                            data,
+                                        },
                                     ));
                                 }
                                 the_man::network::event::BehaviourEvent::Request {
@@ -262,9 +321,24 @@ impl TheManLogic {
                                         crate::logic::message::VoiceMessage::Request(channel, from),
                                     ));
                                 }
+                                the_man::network::event::BehaviourEvent::Accepted {
+                                    channel,
+                                    from,
+                                } => {
+                                    println!("Voice: accepted: channel: {channel}, from: {from}");
+                                    let _ = self.sender.try_send(Message::Voice(
+                                        crate::logic::message::VoiceMessage::AutoAccepted(
+                                            channel, from,
+                                        ),
+                                    ));
+                                }
                                 the_man::network::event::BehaviourEvent::Disconnected {
                                     channel,
                                     from,
+                                }
+                                | the_man::network::event::BehaviourEvent::Refused {
+                                    channel,
+                                    from,
                                 } => {
                                     println!(
                                         "Voice: Disconnect:  channel: {channel}, from: {from}"
@@ -275,6 +349,12 @@ impl TheManLogic {
                                             let _ = self.audio_sender.try_send(Message::Audio(crate::logic::message::AudioMessage::DestroyOuputChannel { id }));
                                         }
                                     }
+                                    super::audio::update_recording(
+                                        &self.recordings,
+                                        &account.voice_channels,
+                                        &self.audio_sender,
+                                        &channel,
+                                    );
                                     let _ = self.sender.try_send(Message::Voice(
                                         crate::logic::message::VoiceMessage::UnRequest(
                                             channel, from,
@@ -289,6 +369,14 @@ impl TheManLogic {
                                             let _ = self.audio_sender.try_send(Message::Audio(crate::logic::message::AudioMessage::DestroyOuputChannel { id }));
                                         }
                                     }
+                                    for channel in self.recordings.keys() {
+                                        super::audio::update_recording(
+                                            &self.recordings,
+                                            &account.voice_channels,
+                                            &self.audio_sender,
+                                            channel,
+                                        );
+                                    }
                                     let _ = self.sender.try_send(Message::Voice(
                                         crate::logic::message::VoiceMessage::Disconnected(from),
                                     ));
@@ -301,6 +389,95 @@ impl TheManLogic {
                                 } => {
                                     println!("VoiceErrorConnection: to: {to}, codec: {codec}, channel: {channel}, error: {error}");
                                 }
+                                the_man::network::event::BehaviourEvent::Recording {
+                                    channel,
+                                    from,
+                                    active,
+                                } => {
+                                    let _ = self.sender.try_send(Message::Voice(
+                                        crate::logic::message::VoiceMessage::PeerRecording(
+                                            channel, from, active,
+                                        ),
+                                    ));
+                                }
+                                the_man::network::event::BehaviourEvent::ChannelKey {
+                                    channel,
+                                    epoch,
+                                    source,
+                                } => {
+                                    println!("Voice: channel: {channel}, key: {source:?}, epoch: {epoch}");
+                                    let _ = self.sender.try_send(Message::Voice(
+                                        crate::logic::message::VoiceMessage::ChannelKey(
+                                            channel,
+                                            Some((source, epoch)),
+                                        ),
+                                    ));
+                                }
+                                the_man::network::event::BehaviourEvent::IncomingCall {
+                                    call,
+                                    from,
+                                } => {
+                                    println!("Voice: incoming call: {call}, from: {from}");
+                                    let _ = self.audio_sender.try_send(Message::Audio(
+                                        super::message::AudioMessage::Ring(true),
+                                    ));
+                                    let _ = self.sender.try_send(Message::Voice(
+                                        crate::logic::message::VoiceMessage::IncomingCall(
+                                            call, from,
+                                        ),
+                                    ));
+                                }
+                                the_man::network::event::BehaviourEvent::CallStarted {
+                                    call,
+                                    with,
+                                } => {
+                                    println!("Voice: call started: {call}, with: {with}");
+                                    let _ = self.audio_sender.try_send(Message::Audio(
+                                        super::message::AudioMessage::Ring(false),
+                                    ));
+                                    let _ = self.sender.try_send(Message::Voice(
+                                        crate::logic::message::VoiceMessage::CallStarted(
+                                            call, with,
+                                        ),
+                                    ));
+                                }
+                                the_man::network::event::BehaviourEvent::CallEnded {
+                                    call,
+                                    with,
+                                    reason,
+                                } => {
+                                    println!("Voice: call ended: {call}, with: {with}, {reason:?}");
+                                    let _ = self.audio_sender.try_send(Message::Audio(
+                                        super::message::AudioMessage::Ring(false),
+                                    ));
+                                    if let Some(id) = self.recordings.get(&call) {
+                                        let _ = self.audio_sender.try_send(Message::Audio(
+                                            super::message::AudioMessage::StopRecording { id: *id },
+                                        ));
+                                    }
+                                    if let Some(channel) = account.voice_channels.remove(&call) {
+                                        for (_, id) in channel {
+                                            let _ = self.audio_sender.try_send(Message::Audio(
+                                                super::message::AudioMessage::DestroyOuputChannel {
+                                                    id,
+                                                },
+                                            ));
+                                        }
+                                    }
+                                    let _ = self.sender.try_send(Message::Voice(
+                                        crate::logic::message::VoiceMessage::CallEnded(
+                                            call, with, reason,
+                                        ),
+                                    ));
+                                    self.update_input_channels();
+                                }
+                                the_man::network::event::BehaviourEvent::ChannelCodec {
+                                    channel,
+                                    codec,
+                                } => {
+                                    println!("Voice: channel: {channel}, codec: {codec:?}");
+                                    self.update_input_channels();
+                                }
                             }
                         }
                     }
@@ -338,4 +515,39 @@ impl TheManLogic {
                 .try_send(Message::SwarmStatus(account.swarm.network_info()));
         }
     }
+
+    /// Captures for every codec a channel sends with, and stops capturing for codecs no channel uses.
+    pub fn update_input_channels(&mut self) {
+        let Some(account) = &self.state.account else {
+            return;
+        };
+        let codecs = account
+            .swarm
+            .behaviour()
+            .the_man
+            .channel_codecs()
+            .map(|codec| codec.name.clone())
+            .collect::<HashSet<String>>();
+
+        self.input_channels.retain(|codec, id| {
+            let used = codecs.contains(codec);
+            if !used {
+                let _ = self.audio_sender.try_send(Message::Audio(
+                    super::message::AudioMessage::DestroyInputChannel { id: *id },
+                ));
+            }
+            used
+        });
+        for codec in codecs {
+            if self.input_channels.contains_key(&codec) {
+                continue;
+            }
+            let id = self.audio_counter;
+            self.audio_counter += 1;
+            self.input_channels.insert(codec.clone(), id);
+            let _ = self.audio_sender.try_send(Message::Audio(
+                super::message::AudioMessage::CreateInputChannel { id, codec },
+            ));
+        }
+    }
 }
@@ -105,6 +105,10 @@ async fn main() {
                 renew: false,
             }],
             bootnodes: vec![],
+            audio: Default::default(),
+            voice: Default::default(),
+            dsp: Vec::new(),
+            codec_order: Vec::new(),
         }
     };
 
@@ -164,19 +168,19 @@ async fn main() {
 
         let mut repaint_after = None;
 
-        event_loop.run_return(move |event, event_loop, control_flow| {
+        event_loop.run_return(move |event, _event_loop, control_flow| {
             //
             match event {
                 winit::event::Event::NewEvents(event) => match event {
                     winit::event::StartCause::ResumeTimeReached {
-                        start,
-                        requested_resume,
+                        start: _,
+                        requested_resume: _,
                     } => {
                         window.request_redraw();
                         repaint_after = None;
                     }
                     winit::event::StartCause::WaitCancelled {
-                        start,
+                        start: _,
                         requested_resume,
                     } => {
                         if let Some(requested_resume) = requested_resume {
@@ -188,7 +192,10 @@ async fn main() {
                         control_flow.set_wait();
                     }
                 },
-                winit::event::Event::WindowEvent { window_id, event } => {
+                winit::event::Event::WindowEvent {
+                    window_id: _,
+                    event,
+                } => {
                     let res = egui_state.on_event(&egui_context, &event);
                     if !res.consumed {
                         match event {
@@ -207,14 +214,17 @@ async fn main() {
                         window.request_redraw()
                     }
                 }
-                winit::event::Event::DeviceEvent { device_id, event } => {}
+                winit::event::Event::DeviceEvent {
+                    device_id: _,
+                    event: _,
+                } => {}
                 winit::event::Event::UserEvent(_) => {}
                 winit::event::Event::Suspended => {}
                 winit::event::Event::Resumed => {}
                 winit::event::Event::MainEventsCleared => {
                     // app.process_events();
                 }
-                winit::event::Event::RedrawRequested(window_id) => {
+                winit::event::Event::RedrawRequested(_window_id) => {
                     unsafe {
                         gl.clear_color(0.0, 0.0, 0.0, 1.0);
                         gl.clear(glow::COLOR_BUFFER_BIT);
@@ -239,7 +249,7 @@ async fn main() {
                         &primitives,
                         &output.textures_delta,
                     );
-                    surface.swap_buffers(&context);
+                    let _ = surface.swap_buffers(&context);
                     egui_state.handle_platform_output(
                         &window,
                         &egui_context,
@@ -0,0 +1,242 @@
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use libp2p::PeerId;
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const EPOCH_SIZE: usize = 4;
+/// PBKDF2-HMAC-SHA256 rounds for a passphrase, slow enough to make guessing it expensive.
+const PASSPHRASE_ROUNDS: u32 = 100_000;
+
+/// The key voice frames of a protected channel are sealed with.
+///
+/// Every rotation increments `epoch`, sealed frames carry it so the previous key
+/// can still open the frames that were in flight.
+#[derive(Clone)]
+pub struct ChannelKey {
+    epoch: u32,
+    key: [u8; KEY_SIZE],
+}
+
+impl std::fmt::Debug for ChannelKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChannelKey")
+            .field("epoch", &self.epoch)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Shows that the sender of a `VoiceConnect` has the channel key, without revealing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyProof {
+    pub epoch: u32,
+    pub proof: Vec<u8>,
+}
+
+impl ChannelKey {
+    /// Everyone that knows the passphrase derives the same key, its epoch is always 0.
+    pub fn from_passphrase(channel: &str, passphrase: &str) -> Self {
+        let salt = format!("the-man voice channel {channel}");
+        let mut key = [0; KEY_SIZE];
+        pbkdf2::pbkdf2_hmac::<Sha256>(
+            passphrase.as_bytes(),
+            salt.as_bytes(),
+            PASSPHRASE_ROUNDS,
+            &mut key,
+        );
+        Self { epoch: 0, key }
+    }
+
+    /// A random key, for the channel creator to hand out.
+    pub fn generate(epoch: u32) -> Self {
+        let mut key = [0; KEY_SIZE];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self { epoch, key }
+    }
+
+    pub fn from_bytes(epoch: u32, bytes: &[u8]) -> Option<Self> {
+        Some(Self {
+            epoch,
+            key: bytes.try_into().ok()?,
+        })
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Binds the proof to the sender, the connection authenticates it so it cannot be replayed by another peer.
+    pub fn proof(&self, channel: &str, peer_id: &PeerId) -> KeyProof {
+        KeyProof {
+            epoch: self.epoch,
+            proof: self
+                .proof_mac(channel, peer_id)
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+        }
+    }
+
+    pub fn verify(&self, channel: &str, peer_id: &PeerId, proof: &KeyProof) -> bool {
+        proof.epoch == self.epoch
+            && self
+                .proof_mac(channel, peer_id)
+                .verify_slice(&proof.proof)
+                .is_ok()
+    }
+
+    fn proof_mac(&self, channel: &str, peer_id: &PeerId) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(b"the-man voice connect");
+        mac.update(&self.epoch.to_be_bytes());
+        mac.update(channel.as_bytes());
+        mac.update(&peer_id.to_bytes());
+        mac
+    }
+
+    /// Encrypts one frame, `header` is authenticated but not encrypted.
+    ///
+    /// The result is the epoch, a random nonce and the ciphertext.
+    pub fn seal(&self, header: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut nonce = [0; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let epoch = self.epoch.to_be_bytes();
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: data,
+                    aad: &[header, &epoch].concat(),
+                },
+            )
+            .map_err(|_| "Cannot seal voice frame".to_string())?;
+
+        let mut sealed = Vec::with_capacity(EPOCH_SIZE + NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&epoch);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts a frame from [`ChannelKey::seal`], `None` when it was not sealed with this key or was tampered with.
+    pub fn open(&self, header: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed_epoch(sealed)? != self.epoch {
+            return None;
+        }
+        let (epoch, rest) = sealed.split_at(EPOCH_SIZE);
+        let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+        self.cipher()
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &[header, epoch].concat(),
+                },
+            )
+            .ok()
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+    }
+}
+
+/// The epoch of the key a frame was sealed with.
+pub fn sealed_epoch(sealed: &[u8]) -> Option<u32> {
+    if sealed.len() < EPOCH_SIZE + NONCE_SIZE {
+        return None;
+    }
+    Some(u32::from_be_bytes(sealed[..EPOCH_SIZE].try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &[u8] = b"lobby\0opus\0";
+
+    #[test]
+    fn seal_then_open() {
+        let key = ChannelKey::generate(3);
+        let sealed = key.seal(HEADER, b"voice").unwrap();
+        assert_eq!(sealed_epoch(&sealed), Some(3));
+        assert_eq!(key.open(HEADER, &sealed).as_deref(), Some(&b"voice"[..]));
+    }
+
+    #[test]
+    fn open_rejects_tampering() {
+        let key = ChannelKey::generate(0);
+        let sealed = key.seal(HEADER, b"voice").unwrap();
+        for index in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            assert!(key.open(HEADER, &tampered).is_none(), "byte {index}");
+        }
+        assert!(key.open(b"other\0opus\0", &sealed).is_none());
+        assert!(key.open(HEADER, &sealed[..sealed.len() - 1]).is_none());
+        assert!(key.open(HEADER, &sealed[..EPOCH_SIZE]).is_none());
+    }
+
+    #[test]
+    fn open_needs_the_same_key() {
+        let sealed = ChannelKey::generate(0).seal(HEADER, b"voice").unwrap();
+        assert!(ChannelKey::generate(0).open(HEADER, &sealed).is_none());
+    }
+
+    #[test]
+    fn proof_verifies() {
+        let key = ChannelKey::generate(1);
+        let peer_id = PeerId::random();
+        let proof = key.proof("lobby", &peer_id);
+        assert!(key.verify("lobby", &peer_id, &proof));
+
+        assert!(!key.verify("other", &peer_id, &proof));
+        assert!(!key.verify("lobby", &PeerId::random(), &proof));
+        assert!(!ChannelKey::generate(1).verify("lobby", &peer_id, &proof));
+        let mut wrong_epoch = proof.clone();
+        wrong_epoch.epoch = 2;
+        assert!(!key.verify("lobby", &peer_id, &wrong_epoch));
+        let mut tampered = proof;
+        tampered.proof[0] ^= 1;
+        assert!(!key.verify("lobby", &peer_id, &tampered));
+    }
+
+    #[test]
+    fn passphrase_derives_per_channel() {
+        let key = ChannelKey::from_passphrase("lobby", "hunter2");
+        assert_eq!(
+            key.bytes(),
+            ChannelKey::from_passphrase("lobby", "hunter2").bytes()
+        );
+        assert_ne!(
+            key.bytes(),
+            ChannelKey::from_passphrase("other", "hunter2").bytes()
+        );
+        assert_ne!(
+            key.bytes(),
+            ChannelKey::from_passphrase("lobby", "hunter3").bytes()
+        );
+        assert_eq!(key.epoch(), 0);
+    }
+
+    #[test]
+    fn from_bytes_needs_a_whole_key() {
+        let key = ChannelKey::generate(0);
+        let copy = ChannelKey::from_bytes(0, key.bytes()).unwrap();
+        let sealed = key.seal(HEADER, b"voice").unwrap();
+        assert!(copy.open(HEADER, &sealed).is_some());
+        assert!(ChannelKey::from_bytes(0, &key.bytes()[1..]).is_none());
+    }
+}
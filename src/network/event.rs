@@ -1,10 +1,14 @@
 use libp2p::PeerId;
 
+use super::{packet::CodecInfo, CallEnd, KeySource};
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum BehaviourEvent {
     VoicePacket {
         from: PeerId,
-        codec: String,
+        codec: CodecInfo,
+        sequence: u32,
+        timestamp: u32,
         data: Vec<u8>,
         channel: String,
     },
@@ -12,6 +16,11 @@ pub enum BehaviourEvent {
         channel: String,
         from: PeerId,
     },
+    /// The channel policy let the peer in without a request.
+    Accepted {
+        channel: String,
+        from: PeerId,
+    },
     Disconnected {
         channel: String,
         from: PeerId,
@@ -25,4 +34,40 @@ pub enum BehaviourEvent {
         channel: String,
         error: String,
     },
+    ChannelCodec {
+        channel: String,
+        codec: CodecInfo,
+    },
+    Recording {
+        channel: String,
+        from: PeerId,
+        active: bool,
+    },
+    /// The peer has neither the key nor the invite the channel asks for.
+    Refused {
+        channel: String,
+        from: PeerId,
+    },
+    /// The key of a protected channel was set, received or rotated.
+    ChannelKey {
+        channel: String,
+        epoch: u32,
+        source: KeySource,
+    },
+    /// The peer rings us, answer with [`super::TheManBehaviour::answer_call`].
+    IncomingCall {
+        call: String,
+        from: PeerId,
+    },
+    /// Both sides joined the private channel of the call, named `call`.
+    CallStarted {
+        call: String,
+        with: PeerId,
+    },
+    /// The call ended, or never started.
+    CallEnded {
+        call: String,
+        with: PeerId,
+        reason: CallEnd,
+    },
 }
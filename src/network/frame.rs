@@ -0,0 +1,329 @@
+//! Framing for the `/the-man` substreams.
+//!
+//! Every [`Packet`] is sent as one frame:
+//!
+//! ```text
+//! +---------+-------------+------------------+-----------------+
+//! | version | packet_type | length (u32, le) | payload         |
+//! | 1 byte  | 1 byte      | 4 bytes          | `length` bytes  |
+//! +---------+-------------+------------------+-----------------+
+//! ```
+//!
+//! `packet_type` is the variant index of [`Packet`] and the payload is the
+//! `bytes_kman` encoding of the variant fields. Frames with a `packet_type`
+//! this build does not know are skipped, so new packets can be added without
+//! breaking older clients. A different `version` is a breaking change and is
+//! rejected.
+
+use bytes_kman::TBytes;
+
+use super::packet::Packet;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 6;
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub packet_type: u8,
+    pub length: u32,
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let length = self.length.to_le_bytes();
+        [
+            self.version,
+            self.packet_type,
+            length[0],
+            length[1],
+            length[2],
+            length[3],
+        ]
+    }
+
+    /// Returns `None` when there are not enough bytes for a header yet.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Option<Self>, FrameError> {
+        if bytes.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let header = Self {
+            version: bytes[0],
+            packet_type: bytes[1],
+            length: u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]),
+        };
+
+        if header.version != PROTOCOL_VERSION {
+            return Err(FrameError::UnsupportedVersion(header.version));
+        }
+        if header.length as usize > MAX_FRAME_SIZE {
+            return Err(FrameError::TooLarge(header.length as usize));
+        }
+
+        Ok(Some(header))
+    }
+}
+
+pub fn encode(packet: &Packet) -> Result<Vec<u8>, FrameError> {
+    let mut payload = packet.to_bytes();
+    // The derived encoding starts with the variant index, that is what the
+    // header carries as `packet_type`.
+    let packet_type = usize::from_bytes(&mut payload).ok_or(FrameError::Malformed {
+        packet_type: u8::MAX,
+    })?;
+
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge(payload.len()));
+    }
+
+    let header = Header {
+        version: PROTOCOL_VERSION,
+        packet_type: packet_type as u8,
+        length: payload.len() as u32,
+    };
+
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&header.to_bytes());
+    frame.append(&mut payload);
+    Ok(frame)
+}
+
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the next complete packet, `Ok(None)` if more bytes are needed.
+    ///
+    /// After an error the stream cannot be resynchronized and should be closed.
+    pub fn decode(&mut self) -> Result<Option<Packet>, FrameError> {
+        loop {
+            let Some(header) = Header::from_bytes(&self.buffer)? else {
+                return Ok(None);
+            };
+
+            let frame_size = HEADER_SIZE + header.length as usize;
+            if self.buffer.len() < frame_size {
+                return Ok(None);
+            }
+
+            let mut payload = self.buffer.drain(..frame_size).skip(HEADER_SIZE).collect();
+
+            if header.packet_type >= Packet::TYPES {
+                log::debug!(
+                    "Skipping unknown packet type {} of {} bytes",
+                    header.packet_type,
+                    header.length
+                );
+                continue;
+            }
+
+            let mut bytes = (header.packet_type as usize).to_bytes();
+            bytes.append(&mut payload);
+
+            let packet = Packet::from_bytes(&mut bytes);
+            return match packet {
+                Some(packet) if bytes.is_empty() => Ok(Some(packet)),
+                _ => Err(FrameError::Malformed {
+                    packet_type: header.packet_type,
+                }),
+            };
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    UnsupportedVersion(u8),
+    TooLarge(usize),
+    Malformed { packet_type: u8 },
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported protocol version: {version}, expected: {PROTOCOL_VERSION}"
+            ),
+            FrameError::TooLarge(size) => {
+                write!(
+                    f,
+                    "Frame of {size} bytes is over the {MAX_FRAME_SIZE} limit"
+                )
+            }
+            FrameError::Malformed { packet_type } => {
+                write!(f, "Malformed payload for packet type: {packet_type}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disconnect(channel: &str) -> Packet {
+        Packet::VoiceDisconnect {
+            channel: channel.into(),
+        }
+    }
+
+    fn decoded_channel(packet: Option<Packet>) -> String {
+        match packet {
+            Some(Packet::VoiceDisconnect { channel }) => channel,
+            _ => panic!("expected a VoiceDisconnect"),
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header {
+            version: PROTOCOL_VERSION,
+            packet_type: 3,
+            length: 1234,
+        };
+        assert_eq!(Header::from_bytes(&header.to_bytes()), Ok(Some(header)));
+    }
+
+    #[test]
+    fn encode_then_decode() {
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&encode(&disconnect("lobby")).unwrap());
+        assert_eq!(decoded_channel(decoder.decode().unwrap()), "lobby");
+        assert!(decoder.decode().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_input_waits_for_more() {
+        let frame = encode(&disconnect("lobby")).unwrap();
+        let mut decoder = FrameDecoder::default();
+
+        decoder.extend(&frame[..HEADER_SIZE - 1]);
+        assert!(decoder.decode().unwrap().is_none());
+        decoder.extend(&frame[HEADER_SIZE - 1..frame.len() - 1]);
+        assert!(decoder.decode().unwrap().is_none());
+        decoder.extend(&frame[frame.len() - 1..]);
+        assert_eq!(decoded_channel(decoder.decode().unwrap()), "lobby");
+    }
+
+    #[test]
+    fn byte_by_byte() {
+        let mut stream = encode(&disconnect("first")).unwrap();
+        stream.extend(encode(&disconnect("second")).unwrap());
+
+        let mut decoder = FrameDecoder::default();
+        let mut channels = Vec::new();
+        for byte in stream {
+            decoder.extend(&[byte]);
+            while let Some(packet) = decoder.decode().unwrap() {
+                channels.push(decoded_channel(Some(packet)));
+            }
+        }
+        assert_eq!(channels, ["first", "second"]);
+    }
+
+    #[test]
+    fn several_frames_in_one_read() {
+        let mut stream = encode(&disconnect("first")).unwrap();
+        stream.extend(encode(&disconnect("second")).unwrap());
+
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&stream);
+        assert_eq!(decoded_channel(decoder.decode().unwrap()), "first");
+        assert_eq!(decoded_channel(decoder.decode().unwrap()), "second");
+        assert!(decoder.decode().unwrap().is_none());
+    }
+
+    #[test]
+    fn unknown_packet_type_is_skipped() {
+        let unknown = Header {
+            version: PROTOCOL_VERSION,
+            packet_type: u8::MAX,
+            length: 3,
+        };
+        let mut stream = unknown.to_bytes().to_vec();
+        stream.extend([1, 2, 3]);
+        stream.extend(encode(&disconnect("lobby")).unwrap());
+
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&stream);
+        assert_eq!(decoded_channel(decoder.decode().unwrap()), "lobby");
+    }
+
+    #[test]
+    fn unknown_packet_type_waits_for_its_payload() {
+        let unknown = Header {
+            version: PROTOCOL_VERSION,
+            packet_type: u8::MAX,
+            length: 4,
+        };
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&unknown.to_bytes());
+        decoder.extend(&[0, 0]);
+        assert!(decoder.decode().unwrap().is_none());
+        decoder.extend(&[0, 0]);
+        decoder.extend(&encode(&disconnect("lobby")).unwrap());
+        assert_eq!(decoded_channel(decoder.decode().unwrap()), "lobby");
+    }
+
+    #[test]
+    fn wrong_version_is_rejected() {
+        let mut frame = encode(&disconnect("lobby")).unwrap();
+        frame[0] = PROTOCOL_VERSION + 1;
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&frame);
+        assert_eq!(
+            decoder.decode().err(),
+            Some(FrameError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected_from_the_header() {
+        let header = Header {
+            version: PROTOCOL_VERSION,
+            packet_type: 0,
+            length: MAX_FRAME_SIZE as u32 + 1,
+        };
+        let mut decoder = FrameDecoder::default();
+        // Only the header arrived, the payload is never waited for.
+        decoder.extend(&header.to_bytes());
+        assert_eq!(
+            decoder.decode().err(),
+            Some(FrameError::TooLarge(MAX_FRAME_SIZE + 1))
+        );
+    }
+
+    #[test]
+    fn oversized_packet_is_not_encoded() {
+        let packet = disconnect(&"a".repeat(MAX_FRAME_SIZE));
+        assert!(matches!(encode(&packet), Err(FrameError::TooLarge(_))));
+    }
+
+    #[test]
+    fn malformed_payload_is_rejected() {
+        let mut frame = encode(&disconnect("lobby")).unwrap();
+        // One byte more than the string takes.
+        frame.push(0);
+        let length = (frame.len() - HEADER_SIZE) as u32;
+        frame[2..HEADER_SIZE].copy_from_slice(&length.to_le_bytes());
+
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&frame);
+        let packet_type = frame[1];
+        assert_eq!(
+            decoder.decode().err(),
+            Some(FrameError::Malformed { packet_type })
+        );
+    }
+}
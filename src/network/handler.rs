@@ -1,63 +1,156 @@
-use std::collections::{HashSet, VecDeque};
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    time::Duration,
+};
 
-use bytes_kman::TBytes;
 use libp2p::{
-    core::{muxing::SubstreamBox, upgrade::ReadyUpgrade, Negotiated},
-    futures::{future::BoxFuture, AsyncReadExt, AsyncWriteExt, FutureExt},
-    swarm::{ConnectionHandler, ConnectionHandlerEvent, SubstreamProtocol},
+    core::upgrade::ReadyUpgrade,
+    futures::{future::BoxFuture, AsyncReadExt, AsyncWriteExt, Future, FutureExt},
+    swarm::{ConnectionHandler, ConnectionHandlerEvent, StreamUpgradeError, SubstreamProtocol},
     Stream,
 };
 
-use super::{packet::Packet, Failure, TheManBehaviour};
+use super::{
+    crypto::{ChannelKey, KeyProof},
+    frame::{self, FrameDecoder},
+    invite::Invite,
+    packet::{CallAnswer, CodecInfo, Packet},
+    Failure, TheManBehaviour, PROTOCOL_NAME,
+};
+
+/// First delay before reopening a failed outbound substream, doubled on every failed attempt.
+const REOPEN_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const REOPEN_BACKOFF_MAX: Duration = Duration::from_secs(30);
 
 pub struct Connection {
     init: bool,
+    reopen: Option<Pin<Box<tokio::time::Sleep>>>,
+    reopen_attempts: u32,
     inbound: Stage,
     outbound: Stage,
     connected: bool,
-    initial_connections: HashSet<String>,
+    /// Channels announced when the outbound stream opens.
+    initial_connections: HashMap<String, Credentials>,
+    codecs: Vec<CodecInfo>,
     events: VecDeque<InputEvent>,
-    out_events:
-        VecDeque<ConnectionHandlerEvent<ReadyUpgrade<&'static str>, String, OutputEvent, Failure>>,
+    out_events: VecDeque<StageEvent>,
 }
 
 impl Connection {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(
-        initial_connected: HashSet<String>,
+        initial_connected: HashMap<String, Credentials>,
+        codecs: Vec<CodecInfo>,
     ) -> Result<libp2p::swarm::THandler<TheManBehaviour>, libp2p::swarm::ConnectionDenied> {
         Ok(Self {
             init: false,
+            reopen: None,
+            reopen_attempts: 0,
             inbound: Stage::None,
             outbound: Stage::None,
             connected: false,
             initial_connections: initial_connected,
+            codecs,
             events: VecDeque::new(),
             out_events: VecDeque::new(),
         })
     }
+
+    fn schedule_reopen(&mut self) {
+        let backoff = REOPEN_BACKOFF_MIN
+            .saturating_mul(2u32.saturating_pow(self.reopen_attempts))
+            .min(REOPEN_BACKOFF_MAX);
+        self.reopen_attempts = self.reopen_attempts.saturating_add(1);
+        log::debug!("TheMan reopening outbound stream in {backoff:?}");
+        self.reopen = Some(Box::pin(tokio::time::sleep(backoff)));
+    }
+
+    fn on_stream_error(&mut self, error: Failure) {
+        self.out_events
+            .push_back(ConnectionHandlerEvent::NotifyBehaviour(
+                OutputEvent::StreamError(error),
+            ));
+    }
+}
+
+/// One step of a direct call, in both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallSignal {
+    Ring,
+    Answer(CallAnswer),
+    HangUp,
+}
+
+/// What a channel announcement carries to let us in.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    /// For a protected channel, that we have its key.
+    pub proof: Option<KeyProof>,
+    /// For a channel with an owner, that the owner let us in.
+    pub invite: Option<Invite>,
 }
 
 #[derive(Debug)]
 pub enum InputEvent {
     VoicePacket {
         codec: String,
+        sequence: u32,
+        timestamp: u32,
         data: Vec<u8>,
         channel: String,
     },
-    Connect(String),
+    Connect {
+        channel: String,
+        codecs: Vec<CodecInfo>,
+        credentials: Credentials,
+    },
     Disconnect(String),
+    Recording {
+        channel: String,
+        active: bool,
+    },
+    Key {
+        channel: String,
+        key: ChannelKey,
+    },
+    Call {
+        call: String,
+        signal: CallSignal,
+    },
 }
 
 #[derive(Debug)]
 pub enum OutputEvent {
     VoicePacket {
         codec: String,
+        sequence: u32,
+        timestamp: u32,
         data: Vec<u8>,
         channel: String,
     },
-    Connected(String),
+    Connected {
+        channel: String,
+        codecs: Vec<CodecInfo>,
+        proof: Option<KeyProof>,
+    },
     Disconnected(String),
+    Recording {
+        channel: String,
+        active: bool,
+    },
+    Key {
+        channel: String,
+        epoch: u32,
+        key: Vec<u8>,
+    },
+    Invite(Invite),
+    Call {
+        call: String,
+        signal: CallSignal,
+    },
     SuccesfulyConnect,
+    StreamError(Failure),
 }
 
 impl ConnectionHandler for Connection {
@@ -78,7 +171,7 @@ impl ConnectionHandler for Connection {
     fn listen_protocol(
         &self,
     ) -> libp2p::swarm::SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
-        SubstreamProtocol::new(ReadyUpgrade::new("/the-man/1.0.0"), "Test".into())
+        SubstreamProtocol::new(ReadyUpgrade::new(PROTOCOL_NAME), "Test".into())
     }
 
     fn connection_keep_alive(&self) -> libp2p::swarm::KeepAlive {
@@ -96,13 +189,20 @@ impl ConnectionHandler for Connection {
             Self::Error,
         >,
     > {
-        if !self.init {
+        let reopen = if let Some(reopen) = &mut self.reopen {
+            reopen.as_mut().poll(cx).is_ready()
+        } else {
+            false
+        };
+
+        if !self.init || reopen {
             self.init = true;
+            self.reopen = None;
 
             return std::task::Poll::Ready(
                 libp2p::swarm::ConnectionHandlerEvent::OutboundSubstreamRequest {
                     protocol: SubstreamProtocol::new(
-                        ReadyUpgrade::new("/the-man/1.0.0"),
+                        ReadyUpgrade::new(PROTOCOL_NAME),
                         "Test".into(),
                     ),
                 },
@@ -124,71 +224,133 @@ impl ConnectionHandler for Connection {
             Stage::None => {}
             Stage::Initial(stream) => {
                 self.inbound = Stage::RunningInitial(async { stream }.boxed());
+                // The new future has not been polled yet, so nothing would wake us for it.
+                cx.waker().wake_by_ref();
             }
             Stage::RunningInitial(mut future) => match future.poll_unpin(cx) {
                 std::task::Poll::Ready(stream) => {
-                    self.inbound = Stage::RunningBase(async { (stream, None, Vec::new()) }.boxed());
+                    self.inbound = Stage::RunningBase(
+                        async { Ok((stream, None, FrameDecoder::default())) }.boxed(),
+                    );
+                    cx.waker().wake_by_ref();
                 }
                 std::task::Poll::Pending => {
                     self.inbound = Stage::RunningInitial(future);
                 }
             },
             Stage::RunningBase(mut future) => match future.poll_unpin(cx) {
-                std::task::Poll::Ready((mut stream, event, mut buffer)) => {
+                std::task::Poll::Ready(Ok((mut stream, event, mut decoder))) => {
                     if let Some(event) = event {
                         self.out_events.push_back(event);
                     }
                     self.inbound = Stage::RunningBase(
                         async {
-                            let packet = Packet::from_bytes(&mut buffer);
-                            if let Some(packet) = packet {
-                                match packet {
-                                    Packet::VoicePacket {
+                            loop {
+                                let Some(packet) = decoder.decode()? else {
+                                    let mut tmp_buffer = [0; 1024 * 16];
+                                    let len = stream.read(&mut tmp_buffer).await?;
+                                    if len == 0 {
+                                        return Err(Failure::Closed);
+                                    }
+                                    decoder.extend(&tmp_buffer[..len]);
+                                    continue;
+                                };
+                                let event = match packet {
+                                    // Without a sequence the jitter buffer cannot place it.
+                                    Packet::VoicePacket { channel, .. } => {
+                                        log::debug!(
+                                            "TheMan dropping unsequenced voice on {channel}"
+                                        );
+                                        continue;
+                                    }
+                                    Packet::VoiceFrame {
                                         codec,
+                                        sequence,
+                                        timestamp,
                                         data,
                                         channel,
-                                    } => {
-                                        return (
-                                            stream,
-                                            Some(ConnectionHandlerEvent::NotifyBehaviour(
-                                                OutputEvent::VoicePacket {
-                                                    codec,
-                                                    data,
-                                                    channel,
-                                                },
-                                            )),
-                                            buffer,
-                                        )
-                                    }
+                                    } => OutputEvent::VoicePacket {
+                                        codec,
+                                        sequence,
+                                        timestamp,
+                                        data: data.0,
+                                        channel,
+                                    },
                                     Packet::VoiceDisconnect { channel } => {
-                                        return (
-                                            stream,
-                                            Some(ConnectionHandlerEvent::NotifyBehaviour(
-                                                OutputEvent::Disconnected(channel),
-                                            )),
-                                            buffer,
-                                        )
+                                        OutputEvent::Disconnected(channel)
                                     }
+                                    // Older peers advertise no codecs, we agree on none with them.
                                     Packet::VoiceConnect { channel } => {
                                         println!("Recv channel: {channel}");
-                                        return (
-                                            stream,
-                                            Some(ConnectionHandlerEvent::NotifyBehaviour(
-                                                OutputEvent::Connected(channel),
-                                            )),
-                                            buffer,
-                                        );
+                                        OutputEvent::Connected {
+                                            channel,
+                                            codecs: Vec::new(),
+                                            proof: None,
+                                        }
                                     }
-                                }
-                            } else {
-                                let mut tmp_buffer = [0; 1024 * 16];
-                                let len = stream.read(&mut tmp_buffer).await.unwrap();
-                                buffer.append(&mut tmp_buffer[..len].to_vec());
+                                    Packet::VoiceRecording { channel, active } => {
+                                        OutputEvent::Recording { channel, active }
+                                    }
+                                    Packet::VoiceKeyedConnect {
+                                        channel,
+                                        codecs,
+                                        epoch,
+                                        proof,
+                                    } => OutputEvent::Connected {
+                                        channel,
+                                        codecs: codecs.0,
+                                        proof: Some(KeyProof {
+                                            epoch,
+                                            proof: proof.0,
+                                        }),
+                                    },
+                                    Packet::VoiceKey {
+                                        channel,
+                                        epoch,
+                                        key,
+                                    } => OutputEvent::Key {
+                                        channel,
+                                        epoch,
+                                        key: key.0,
+                                    },
+                                    Packet::VoiceInvite { invite } => OutputEvent::Invite(invite),
+                                    Packet::CallRing { call } => OutputEvent::Call {
+                                        call,
+                                        signal: CallSignal::Ring,
+                                    },
+                                    Packet::CallAnswer { call, answer } => OutputEvent::Call {
+                                        call,
+                                        signal: CallSignal::Answer(answer),
+                                    },
+                                    Packet::CallHangUp { call } => OutputEvent::Call {
+                                        call,
+                                        signal: CallSignal::HangUp,
+                                    },
+                                    Packet::VoiceCodecsConnect { channel, codecs } => {
+                                        println!("Recv channel: {channel}");
+                                        OutputEvent::Connected {
+                                            channel,
+                                            codecs: codecs.0,
+                                            proof: None,
+                                        }
+                                    }
+                                };
+                                return Ok((
+                                    stream,
+                                    Some(ConnectionHandlerEvent::NotifyBehaviour(event)),
+                                    decoder,
+                                ));
                             }
-                            (stream, None, buffer)
                         }
                         .boxed(),
                     );
+                    // The new future has not been polled yet, so nothing would wake us for it.
+                    cx.waker().wake_by_ref();
+                }
+                std::task::Poll::Ready(Err(error)) => {
+                    // The remote reopens its outbound stream, we only wait for the new inbound one.
+                    eprintln!("TheMan closing inbound stream: {error}");
+                    self.on_stream_error(error);
                 }
                 std::task::Poll::Pending => {
                     self.inbound = Stage::RunningBase(future);
@@ -200,63 +362,99 @@ impl ConnectionHandler for Connection {
             Stage::None => {}
             Stage::Initial(stream) => {
                 self.outbound = Stage::RunningInitial(async { stream }.boxed());
+                cx.waker().wake_by_ref();
             }
             Stage::RunningInitial(mut future) => match future.poll_unpin(cx) {
                 std::task::Poll::Ready(mut stream) => {
                     let channels = self.initial_connections.clone();
+                    let codecs = self.codecs.clone();
                     self.outbound = Stage::RunningBase(
-                        async {
-                            for channel in channels {
-                                stream
-                                    .write_all(&Packet::VoiceConnect { channel }.to_bytes())
-                                    .await
-                                    .unwrap();
+                        async move {
+                            for (channel, credentials) in channels {
+                                for packet in connect_packets(channel, codecs.clone(), credentials)
+                                {
+                                    stream.write_all(&frame::encode(&packet)?).await?;
+                                }
                             }
-                            (stream, None, Vec::new())
+                            Ok((stream, None, FrameDecoder::default()))
                         }
                         .boxed(),
                     );
+                    cx.waker().wake_by_ref();
                 }
                 std::task::Poll::Pending => {
                     self.outbound = Stage::RunningInitial(future);
                 }
             },
             Stage::RunningBase(mut future) => match future.poll_unpin(cx) {
-                std::task::Poll::Ready((mut stream, _event, buffer)) => {
+                std::task::Poll::Ready(Ok((mut stream, _event, decoder))) => {
                     if let Some(event) = self.events.pop_front() {
+                        let packets = match event {
+                            InputEvent::VoicePacket {
+                                codec,
+                                sequence,
+                                timestamp,
+                                data,
+                                channel,
+                            } => vec![Packet::VoiceFrame {
+                                codec,
+                                sequence,
+                                timestamp,
+                                data: data.into(),
+                                channel,
+                            }],
+                            InputEvent::Connect {
+                                channel,
+                                codecs,
+                                credentials,
+                            } => connect_packets(channel, codecs, credentials),
+                            InputEvent::Disconnect(channel) => {
+                                vec![Packet::VoiceDisconnect { channel }]
+                            }
+                            InputEvent::Recording { channel, active } => {
+                                vec![Packet::VoiceRecording { channel, active }]
+                            }
+                            InputEvent::Key { channel, key } => vec![Packet::VoiceKey {
+                                channel,
+                                epoch: key.epoch(),
+                                key: key.bytes().to_vec().into(),
+                            }],
+                            InputEvent::Call { call, signal } => vec![match signal {
+                                CallSignal::Ring => Packet::CallRing { call },
+                                CallSignal::Answer(answer) => Packet::CallAnswer { call, answer },
+                                CallSignal::HangUp => Packet::CallHangUp { call },
+                            }],
+                        };
+                        let frames = packets.iter().map(frame::encode).collect::<Vec<_>>();
                         self.outbound = Stage::RunningBase(
-                            async {
-                                let _ = stream
-                                    .write_all(
-                                        &match event {
-                                            InputEvent::VoicePacket {
-                                                codec,
-                                                data,
-                                                channel,
-                                            } => Packet::VoicePacket {
-                                                codec,
-                                                data,
-                                                channel,
-                                            },
-                                            InputEvent::Connect(channel) => {
-                                                Packet::VoiceConnect { channel }
-                                            }
-                                            InputEvent::Disconnect(channel) => {
-                                                Packet::VoiceDisconnect { channel }
-                                            }
-                                        }
-                                        .to_bytes(),
-                                    )
-                                    .await;
-                                (stream, None, buffer)
+                            async move {
+                                for frame in frames {
+                                    match frame {
+                                        Ok(frame) => stream.write_all(&frame).await?,
+                                        Err(error) => eprintln!("TheMan dropping packet: {error}"),
+                                    }
+                                }
+                                Ok((stream, None, decoder))
                             }
                             .boxed(),
                         );
+                        cx.waker().wake_by_ref();
                     } else {
+                        // Waking here would spin while idle, the next event comes through
+                        // `on_behaviour_event` and the connection polls us after it.
                         self.outbound =
-                            Stage::RunningBase(async { (stream, None, Vec::new()) }.boxed());
+                            Stage::RunningBase(async { Ok((stream, None, decoder)) }.boxed());
                     }
                 }
+                std::task::Poll::Ready(Err(error)) => {
+                    eprintln!("TheMan closing outbound stream: {error}");
+                    // Queued voice is stale by the time the stream is back.
+                    self.events
+                        .retain(|event| !matches!(event, InputEvent::VoicePacket { .. }));
+                    self.on_stream_error(error);
+                    self.schedule_reopen();
+                    cx.waker().wake_by_ref();
+                }
                 std::task::Poll::Pending => {
                     self.outbound = Stage::RunningBase(future);
                 }
@@ -266,6 +464,22 @@ impl ConnectionHandler for Connection {
     }
 
     fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        match &event {
+            InputEvent::VoicePacket { .. } if !self.outbound.initial() => return,
+            InputEvent::Connect {
+                channel,
+                codecs,
+                credentials,
+            } => {
+                self.initial_connections
+                    .insert(channel.clone(), credentials.clone());
+                self.codecs.clone_from(codecs);
+            }
+            InputEvent::Disconnect(channel) => {
+                self.initial_connections.remove(channel);
+            }
+            _ => {}
+        }
         self.events.push_back(event);
     }
 
@@ -285,34 +499,56 @@ impl ConnectionHandler for Connection {
             }
             libp2p::swarm::handler::ConnectionEvent::FullyNegotiatedOutbound(event) => {
                 println!("Outbound: {:?}", event.protocol);
+                self.reopen_attempts = 0;
                 self.outbound = Stage::Initial(event.protocol)
             }
+            libp2p::swarm::handler::ConnectionEvent::DialUpgradeError(event) => {
+                if let StreamUpgradeError::NegotiationFailed = event.error {
+                    log::debug!("TheMan protocol is not supported by the remote");
+                    return;
+                }
+                eprintln!("TheMan cannot open outbound stream: {}", event.error);
+                self.schedule_reopen();
+            }
             _ => {}
         }
     }
 }
 
+/// A `VoiceCodecsConnect`, keyed when the channel is protected and after the invite when it has an owner.
+fn connect_packets(
+    channel: String,
+    codecs: Vec<CodecInfo>,
+    credentials: Credentials,
+) -> Vec<Packet> {
+    let mut packets = Vec::new();
+    if let Some(invite) = credentials.invite {
+        packets.push(Packet::VoiceInvite { invite });
+    }
+    packets.push(match credentials.proof {
+        Some(KeyProof { epoch, proof }) => Packet::VoiceKeyedConnect {
+            channel,
+            codecs: codecs.into(),
+            epoch,
+            proof: proof.into(),
+        },
+        None => Packet::VoiceCodecsConnect {
+            channel,
+            codecs: codecs.into(),
+        },
+    });
+    packets
+}
+
+type StageEvent = ConnectionHandlerEvent<ReadyUpgrade<&'static str>, String, OutputEvent, Failure>;
+
+type StageResult = Result<(Stream, Option<StageEvent>, FrameDecoder), Failure>;
+
 pub enum Stage {
     None,
     Initial(Stream),
     RunningInitial(BoxFuture<'static, Stream>),
-    RunningBase(
-        BoxFuture<
-            'static,
-            (
-                Stream,
-                Option<
-                    ConnectionHandlerEvent<
-                        ReadyUpgrade<&'static str>,
-                        String,
-                        OutputEvent,
-                        Failure,
-                    >,
-                >,
-                Vec<u8>,
-            ),
-        >,
-    ),
+    RunningBase(BoxFuture<'static, StageResult>),
 }
 
 impl Stage {
@@ -0,0 +1,251 @@
+use bytes_kman::prelude::*;
+use libp2p::{
+    identity::{Keypair, PublicKey, SigningError},
+    PeerId,
+};
+
+use super::packet::Bounded;
+
+/// Permission to join a channel, signed by the channel owner.
+///
+/// The owner signs one for itself too, so every member presents the same proof.
+/// Shared as a token, see [`Invite::to_token`].
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize, bytes_kman::Bytes)]
+pub struct Invite {
+    pub channel: String,
+    /// Protobuf encoding of the owner public key.
+    owner: Bounded<u8>,
+    /// The only peer it lets in, anyone that has it when `None`.
+    peer: Option<Bounded<u8>>,
+    /// Unix time in seconds after which it is refused, `None` never expires.
+    pub expires: Option<u64>,
+    signature: Bounded<u8>,
+}
+
+impl Invite {
+    pub fn sign(
+        owner: &Keypair,
+        channel: String,
+        peer: Option<PeerId>,
+        expires: Option<u64>,
+    ) -> Result<Self, SigningError> {
+        let mut invite = Self {
+            channel,
+            owner: owner.public().encode_protobuf().into(),
+            peer: peer.map(|peer| peer.to_bytes().into()),
+            expires,
+            signature: Bounded::default(),
+        };
+        invite.signature = owner.sign(&invite.signed())?.into();
+        Ok(invite)
+    }
+
+    /// What the signature covers, everything but the signature itself.
+    fn signed(&self) -> Vec<u8> {
+        let mut signed = b"the-man invite".to_vec();
+        signed.extend(
+            Self {
+                signature: Bounded::default(),
+                ..self.clone()
+            }
+            .to_bytes(),
+        );
+        signed
+    }
+
+    pub fn owner(&self) -> Result<PublicKey, InviteError> {
+        PublicKey::try_decode_protobuf(&self.owner.0).map_err(|_| InviteError::Owner)
+    }
+
+    pub fn peer(&self) -> Option<PeerId> {
+        self.peer
+            .as_ref()
+            .and_then(|peer| PeerId::from_bytes(&peer.0).ok())
+    }
+
+    /// Checks that the invite lets `peer_id` into `channel` of `owner` at `now`, in unix seconds.
+    pub fn verify(
+        &self,
+        channel: &str,
+        owner: &PublicKey,
+        peer_id: &PeerId,
+        now: u64,
+    ) -> Result<(), InviteError> {
+        if self.channel != channel {
+            return Err(InviteError::Channel);
+        }
+        if self.owner()? != *owner {
+            return Err(InviteError::Owner);
+        }
+        if !owner.verify(&self.signed(), &self.signature.0) {
+            return Err(InviteError::Signature);
+        }
+        if self.peer.is_some() && self.peer() != Some(*peer_id) {
+            return Err(InviteError::Peer);
+        }
+        if self.expires.is_some_and(|expires| expires < now) {
+            return Err(InviteError::Expired);
+        }
+        Ok(())
+    }
+
+    pub fn to_token(&self) -> String {
+        bs58::encode(self.to_bytes()).into_string()
+    }
+
+    pub fn from_token(token: &str) -> Result<Self, InviteError> {
+        let mut bytes = bs58::decode(token.trim())
+            .into_vec()
+            .map_err(|_| InviteError::Token)?;
+        match Self::from_bytes(&mut bytes) {
+            Some(invite) if bytes.is_empty() => Ok(invite),
+            _ => Err(InviteError::Token),
+        }
+    }
+}
+
+/// Seconds since the unix epoch, what [`Invite::expires`] is compared with.
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InviteError {
+    Missing,
+    Token,
+    Channel,
+    Owner,
+    Signature,
+    Peer,
+    Expired,
+}
+
+impl std::fmt::Display for InviteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InviteError::Missing => write!(f, "No invite"),
+            InviteError::Token => write!(f, "Not an invite token"),
+            InviteError::Channel => write!(f, "Invite is for another channel"),
+            InviteError::Owner => write!(f, "Invite is from another owner"),
+            InviteError::Signature => write!(f, "Invite signature is not valid"),
+            InviteError::Peer => write!(f, "Invite is for another peer"),
+            InviteError::Expired => write!(f, "Invite expired"),
+        }
+    }
+}
+
+impl std::error::Error for InviteError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_then_verify() {
+        let owner = Keypair::generate_ed25519();
+        let peer_id = PeerId::random();
+        let invite = Invite::sign(&owner, "lobby".into(), Some(peer_id), None).unwrap();
+        assert_eq!(invite.owner(), Ok(owner.public()));
+        assert_eq!(invite.peer(), Some(peer_id));
+        assert_eq!(invite.verify("lobby", &owner.public(), &peer_id, 0), Ok(()));
+
+        assert_eq!(
+            invite.verify("other", &owner.public(), &peer_id, 0),
+            Err(InviteError::Channel)
+        );
+        assert_eq!(
+            invite.verify("lobby", &Keypair::generate_ed25519().public(), &peer_id, 0),
+            Err(InviteError::Owner)
+        );
+        assert_eq!(
+            invite.verify("lobby", &owner.public(), &PeerId::random(), 0),
+            Err(InviteError::Peer)
+        );
+    }
+
+    #[test]
+    fn open_invite_lets_anyone_in() {
+        let owner = Keypair::generate_ed25519();
+        let invite = Invite::sign(&owner, "lobby".into(), None, None).unwrap();
+        assert_eq!(invite.peer(), None);
+        assert_eq!(
+            invite.verify("lobby", &owner.public(), &PeerId::random(), u64::MAX),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn changed_invite_fails_the_signature() {
+        let owner = Keypair::generate_ed25519();
+        let mut invite = Invite::sign(&owner, "lobby".into(), None, Some(100)).unwrap();
+        invite.expires = Some(200);
+        assert_eq!(
+            invite.verify("lobby", &owner.public(), &PeerId::random(), 0),
+            Err(InviteError::Signature)
+        );
+
+        // Signed by someone else in the owner's name.
+        let mut forged =
+            Invite::sign(&Keypair::generate_ed25519(), "lobby".into(), None, None).unwrap();
+        forged.owner = owner.public().encode_protobuf().into();
+        assert_eq!(
+            forged.verify("lobby", &owner.public(), &PeerId::random(), 0),
+            Err(InviteError::Signature)
+        );
+    }
+
+    #[test]
+    fn expires() {
+        let owner = Keypair::generate_ed25519();
+        let peer_id = PeerId::random();
+        let invite = Invite::sign(&owner, "lobby".into(), None, Some(100)).unwrap();
+        assert_eq!(
+            invite.verify("lobby", &owner.public(), &peer_id, 99),
+            Ok(())
+        );
+        assert_eq!(
+            invite.verify("lobby", &owner.public(), &peer_id, 100),
+            Ok(())
+        );
+        assert_eq!(
+            invite.verify("lobby", &owner.public(), &peer_id, 101),
+            Err(InviteError::Expired)
+        );
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let owner = Keypair::generate_ed25519();
+        let peer_id = PeerId::random();
+        let invite = Invite::sign(&owner, "lobby".into(), Some(peer_id), Some(100)).unwrap();
+        let token = invite.to_token();
+        let decoded = Invite::from_token(&format!(" {token}\n")).unwrap();
+        assert_eq!(decoded, invite);
+        assert_eq!(
+            decoded.verify("lobby", &owner.public(), &peer_id, 0),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn bad_tokens() {
+        let token = Invite::sign(&Keypair::generate_ed25519(), "lobby".into(), None, None)
+            .unwrap()
+            .to_token();
+        // 0 is not in the bs58 alphabet.
+        assert_eq!(Invite::from_token("0OIl"), Err(InviteError::Token));
+        assert_eq!(
+            Invite::from_token(&token[..token.len() / 2]),
+            Err(InviteError::Token)
+        );
+        let mut bytes = bs58::decode(&token).into_vec().unwrap();
+        bytes.push(0);
+        assert_eq!(
+            Invite::from_token(&bs58::encode(bytes).into_string()),
+            Err(InviteError::Token)
+        );
+    }
+}
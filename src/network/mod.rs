@@ -1,23 +1,174 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    pin::Pin,
+    time::Duration,
+};
 
 use libp2p::{
+    identity::PublicKey,
     swarm::{NetworkBehaviour, THandlerInEvent, ToSwarm},
     PeerId,
 };
+use rand::RngCore;
 
-use self::handler::Connection;
+use self::{
+    crypto::{ChannelKey, KeyProof},
+    handler::{CallSignal, Connection, Credentials},
+    invite::{Invite, InviteError},
+    packet::{CallAnswer, CodecInfo},
+};
 
+pub mod crypto;
 pub mod event;
+pub mod frame;
 pub mod handler;
+pub mod invite;
 pub mod packet;
 
+pub const PROTOCOL_NAME: &str = "/the-man/1.0.0";
+
 pub struct TheManBehaviour {
     peer_id: PeerId,
     events: VecDeque<ToSwarm<event::BehaviourEvent, handler::InputEvent>>,
     mesh: HashMap<String, HashMap<PeerId, Stage>>,
     peers: HashSet<PeerId>,
     connected: HashSet<String>,
-    auto_accept: bool,
+    /// Channels without a policy are [`AutoAccept::Manual`].
+    auto_accept: HashMap<String, AutoAccept>,
+    friends: HashSet<PeerId>,
+    codecs: Vec<CodecInfo>,
+    remote_codecs: HashMap<String, HashMap<PeerId, Vec<CodecInfo>>>,
+    channel_codecs: HashMap<String, CodecInfo>,
+    /// Channels we record, their peers are told so.
+    recording: HashSet<String>,
+    /// Keys of the protected channels.
+    keys: HashMap<String, ChannelKeys>,
+    /// Proofs for channels we have no key of yet, checked once the key arrives.
+    remote_proofs: HashMap<String, HashMap<PeerId, KeyProof>>,
+    /// Channels with an owner, only the peers it invited get in.
+    memberships: HashMap<String, Membership>,
+    /// Invites peers sent before announcing a channel we are in.
+    remote_invites: HashMap<String, HashMap<PeerId, Invite>>,
+    /// Direct calls by id, the id is also the private channel they talk in.
+    calls: HashMap<String, Call>,
+}
+
+struct Membership {
+    owner: PublicKey,
+    /// Presented when we announce the channel.
+    invite: Invite,
+}
+
+/// Where the key of a protected channel comes from.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum KeySource {
+    /// Derived from a passphrase everyone in the channel knows.
+    Passphrase,
+    /// We created it and hand it out to the peers we accept,
+    /// it is rotated when one of them is removed.
+    Created,
+    /// Handed out to us by the channel creator.
+    Received(PeerId),
+}
+
+struct ChannelKeys {
+    source: KeySource,
+    current: ChannelKey,
+    /// Opens the frames that were in flight during a rotation.
+    previous: Option<ChannelKey>,
+}
+
+impl ChannelKeys {
+    fn verify(&self, channel: &str, peer_id: &PeerId, proof: &KeyProof) -> bool {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .any(|key| key.verify(channel, peer_id, proof))
+    }
+
+    fn open(&self, header: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let epoch = crypto::sealed_epoch(sealed)?;
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .find(|key| key.epoch() == epoch)?
+            .open(header, sealed)
+    }
+}
+
+/// What a sealed voice frame is bound to besides its data.
+fn voice_header(channel: &str, codec: &str, sequence: u32, timestamp: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(channel.len() + codec.len() + 10);
+    header.extend_from_slice(channel.as_bytes());
+    header.push(0);
+    header.extend_from_slice(codec.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&sequence.to_be_bytes());
+    header.extend_from_slice(&timestamp.to_be_bytes());
+    header
+}
+
+/// Who joins a voice channel without being accepted by hand.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum AutoAccept {
+    #[default]
+    Manual,
+    Friends,
+    Everyone,
+    AllowList(Vec<PeerId>),
+}
+
+impl AutoAccept {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AutoAccept::Manual => "Manual",
+            AutoAccept::Friends => "Friends",
+            AutoAccept::Everyone => "Everyone",
+            AutoAccept::AllowList(_) => "Allow list",
+        }
+    }
+}
+
+/// How long a call rings before the caller gives up.
+pub const RING_TIMEOUT: Duration = Duration::from_secs(30);
+/// Every call id starts with it, so it cannot name a channel.
+const CALL_PREFIX: &str = "call/";
+
+/// Why a direct call ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum CallEnd {
+    Declined,
+    /// The callee was in another call.
+    Busy,
+    /// Nobody answered within [`RING_TIMEOUT`], or the caller gave up.
+    NoAnswer,
+    HungUp,
+    /// The connection to the peer closed, or there was none.
+    Disconnected,
+}
+
+impl CallEnd {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CallEnd::Declined => "Declined",
+            CallEnd::Busy => "Busy",
+            CallEnd::NoAnswer => "No answer",
+            CallEnd::HungUp => "Hung up",
+            CallEnd::Disconnected => "Disconnected",
+        }
+    }
+}
+
+struct Call {
+    peer: PeerId,
+    stage: CallStage,
+}
+
+enum CallStage {
+    /// We rang the peer, it has until the deadline to answer.
+    Ringing(Pin<Box<tokio::time::Sleep>>),
+    /// The peer rang us.
+    Incoming,
+    Active,
 }
 
 #[derive(Debug)]
@@ -33,35 +184,519 @@ impl TheManBehaviour {
             events: VecDeque::new(),
             mesh: HashMap::new(),
             connected: HashSet::new(),
-            auto_accept: false,
+            auto_accept: HashMap::new(),
+            friends: HashSet::new(),
             peers: HashSet::new(),
+            codecs: Vec::new(),
+            remote_codecs: HashMap::new(),
+            channel_codecs: HashMap::new(),
+            recording: HashSet::new(),
+            keys: HashMap::new(),
+            remote_proofs: HashMap::new(),
+            memberships: HashMap::new(),
+            remote_invites: HashMap::new(),
+            calls: HashMap::new(),
+        }
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Sets the codecs we can use, in order of preference, and advertises them again.
+    pub fn set_codecs(&mut self, codecs: Vec<CodecInfo>) {
+        self.codecs = codecs;
+        for channel in self.connected.clone() {
+            self.connect(channel);
         }
     }
 
+    /// Sets who joins `channel` without a request, waiting requests it allows are accepted.
+    pub fn set_auto_accept(&mut self, channel: String, policy: AutoAccept) {
+        self.auto_accept.insert(channel.clone(), policy);
+        let waiting = self
+            .mesh
+            .get(&channel)
+            .into_iter()
+            .flatten()
+            .filter(|(peer_id, stage)| {
+                matches!(stage, Stage::Requested) && self.auto_accepts(&channel, peer_id)
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect::<Vec<PeerId>>();
+        for peer_id in waiting {
+            self.auto_accept_peer(channel.clone(), peer_id);
+        }
+    }
+
+    /// Peers that [`AutoAccept::Friends`] lets in.
+    pub fn set_friends(&mut self, friends: impl IntoIterator<Item = PeerId>) {
+        self.friends = friends.into_iter().collect();
+    }
+
+    fn auto_accepts(&self, channel: &str, peer_id: &PeerId) -> bool {
+        match self.auto_accept.get(channel) {
+            None | Some(AutoAccept::Manual) => false,
+            Some(AutoAccept::Friends) => self.friends.contains(peer_id),
+            Some(AutoAccept::Everyone) => true,
+            Some(AutoAccept::AllowList(peers)) => peers.contains(peer_id),
+        }
+    }
+
+    fn auto_accept_peer(&mut self, channel: String, peer_id: PeerId) {
+        self.accept(channel.clone(), peer_id);
+        self.events
+            .push_back(ToSwarm::GenerateEvent(event::BehaviourEvent::Accepted {
+                channel,
+                from: peer_id,
+            }));
+    }
+
     pub fn connect(&mut self, channel: String) {
-        for peer in self.peers.iter() {
+        let credentials = self.credentials(&channel);
+        for peer in self.audience(&channel) {
             self.events.push_back(ToSwarm::NotifyHandler {
-                peer_id: *peer,
+                peer_id: peer,
                 handler: libp2p::swarm::NotifyHandler::Any,
-                event: handler::InputEvent::Connect(channel.clone()),
+                event: handler::InputEvent::Connect {
+                    channel: channel.clone(),
+                    codecs: self.codecs.clone(),
+                    credentials: credentials.clone(),
+                },
             });
         }
-        self.connected.insert(channel);
+        self.connected.insert(channel.clone());
+        self.update_channel_codec(&channel);
     }
 
     pub fn disconnect(&mut self, channel: String) {
-        for peer in self.peers.iter() {
-            self.events.push_back(ToSwarm::NotifyHandler {
-                peer_id: *peer,
-                handler: libp2p::swarm::NotifyHandler::Any,
-                event: handler::InputEvent::Disconnect(channel.clone()),
-            });
+        // Leaving the channel of a call ends it.
+        if self.calls.contains_key(&channel) {
+            self.hang_up(&channel);
+            return;
         }
-        self.connected.remove(&channel);
+        self.leave(channel);
     }
 
-    pub fn audio_packet(&mut self, codec: String, data: Vec<u8>) {
+    fn leave(&mut self, channel: String) {
+        if self.connected.remove(&channel) {
+            for peer in self.audience(&channel) {
+                self.events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: peer,
+                    handler: libp2p::swarm::NotifyHandler::Any,
+                    event: handler::InputEvent::Disconnect(channel.clone()),
+                });
+            }
+        }
+        self.channel_codecs.remove(&channel);
+        self.recording.remove(&channel);
+        self.keys.remove(&channel);
+        self.remote_proofs.remove(&channel);
+        self.remote_invites.remove(&channel);
+    }
+
+    /// Peers a channel is announced to, the channel of a call only to the other side.
+    fn audience(&self, channel: &str) -> Vec<PeerId> {
+        match self.calls.get(channel) {
+            Some(call) => vec![call.peer],
+            None => self.peers.iter().copied().collect(),
+        }
+    }
+
+    /// Rings `peer_id` and returns the call id, the answer comes as an event.
+    ///
+    /// The call talks in a private channel named by the id, announced only to the peer.
+    pub fn call(&mut self, peer_id: PeerId) -> String {
+        let mut id = [0; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        let call = format!("{CALL_PREFIX}{}", bs58::encode(id).into_string());
+        self.calls.insert(
+            call.clone(),
+            Call {
+                peer: peer_id,
+                stage: CallStage::Ringing(Box::pin(tokio::time::sleep(RING_TIMEOUT))),
+            },
+        );
+        if self.peers.contains(&peer_id) {
+            self.send_call(peer_id, call.clone(), CallSignal::Ring);
+        } else {
+            self.end_call(&call, CallEnd::Disconnected, false);
+        }
+        call
+    }
+
+    /// Answers a call that rings us, accepting it joins its channel.
+    pub fn answer_call(&mut self, call: &str, answer: CallAnswer) {
+        let Some(peer_id) = self
+            .calls
+            .get(call)
+            .filter(|state| matches!(state.stage, CallStage::Incoming))
+            .map(|state| state.peer)
+        else {
+            return;
+        };
+        self.send_call(peer_id, call.to_string(), CallSignal::Answer(answer));
+        match answer {
+            CallAnswer::Accept => self.start_call(call),
+            CallAnswer::Decline => self.end_call(call, CallEnd::Declined, false),
+            CallAnswer::Busy => self.end_call(call, CallEnd::Busy, false),
+        }
+    }
+
+    /// Ends the call on both sides, or stops ringing when it was not answered yet.
+    pub fn hang_up(&mut self, call: &str) {
+        self.end_call(call, CallEnd::HungUp, true);
+    }
+
+    /// Whether `channel` is the private channel of a call.
+    pub fn is_call(&self, channel: &str) -> bool {
+        self.calls.contains_key(channel)
+    }
+
+    fn start_call(&mut self, call: &str) {
+        let Some(state) = self.calls.get_mut(call) else {
+            return;
+        };
+        state.stage = CallStage::Active;
+        let peer_id = state.peer;
+        // The other side gets in without a request, nobody else does.
+        self.auto_accept
+            .insert(call.to_string(), AutoAccept::AllowList(vec![peer_id]));
+        self.connect(call.to_string());
+        self.events
+            .push_back(ToSwarm::GenerateEvent(event::BehaviourEvent::CallStarted {
+                call: call.to_string(),
+                with: peer_id,
+            }));
+    }
+
+    /// Forgets the call and leaves its channel, `notify` tells the peer it ended.
+    fn end_call(&mut self, call: &str, reason: CallEnd, notify: bool) {
+        let Some(peer_id) = self.calls.get(call).map(|state| state.peer) else {
+            return;
+        };
+        if notify {
+            self.send_call(peer_id, call.to_string(), CallSignal::HangUp);
+        }
+        // Before the call is removed, so only the peer is told.
+        self.leave(call.to_string());
+        self.calls.remove(call);
+        self.auto_accept.remove(call);
+        self.mesh.remove(call);
+        self.remote_codecs.remove(call);
+        self.events
+            .push_back(ToSwarm::GenerateEvent(event::BehaviourEvent::CallEnded {
+                call: call.to_string(),
+                with: peer_id,
+                reason,
+            }));
+    }
+
+    fn send_call(&mut self, peer_id: PeerId, call: String, signal: CallSignal) {
+        self.events.push_back(ToSwarm::NotifyHandler {
+            peer_id,
+            handler: libp2p::swarm::NotifyHandler::Any,
+            event: handler::InputEvent::Call { call, signal },
+        });
+    }
+
+    fn on_call_signal(&mut self, peer_id: PeerId, call: String, signal: CallSignal) {
+        if signal == CallSignal::Ring {
+            if self.calls.contains_key(&call) {
+                return;
+            }
+            // Ending the call leaves its channel, it must not be one we are in.
+            if !call.starts_with(CALL_PREFIX)
+                || self.connected.contains(&call)
+                || self.mesh.contains_key(&call)
+            {
+                log::debug!("Ignoring ring of {call} from {peer_id}");
+                return;
+            }
+            // One call at a time.
+            if !self.calls.is_empty() {
+                self.send_call(peer_id, call, CallSignal::Answer(CallAnswer::Busy));
+                return;
+            }
+            self.calls.insert(
+                call.clone(),
+                Call {
+                    peer: peer_id,
+                    stage: CallStage::Incoming,
+                },
+            );
+            self.events.push_back(ToSwarm::GenerateEvent(
+                event::BehaviourEvent::IncomingCall {
+                    call,
+                    from: peer_id,
+                },
+            ));
+            return;
+        }
+
+        let (ringing, active) = match self.calls.get(&call) {
+            Some(state) if state.peer == peer_id => (
+                matches!(state.stage, CallStage::Ringing(_)),
+                matches!(state.stage, CallStage::Active),
+            ),
+            _ => {
+                log::debug!("Ignoring {signal:?} of {call} from {peer_id}");
+                return;
+            }
+        };
+        match signal {
+            CallSignal::Answer(CallAnswer::Accept) if ringing => self.start_call(&call),
+            CallSignal::Answer(CallAnswer::Decline) if ringing => {
+                self.end_call(&call, CallEnd::Declined, false)
+            }
+            CallSignal::Answer(CallAnswer::Busy) if ringing => {
+                self.end_call(&call, CallEnd::Busy, false)
+            }
+            CallSignal::HangUp if active => self.end_call(&call, CallEnd::HungUp, false),
+            // The caller gave up before we answered.
+            CallSignal::HangUp => self.end_call(&call, CallEnd::NoAnswer, false),
+            _ => {}
+        }
+    }
+
+    /// Lets only the peers the owner of `invite` invited into its channel, they join without a request.
+    ///
+    /// `invite` is ours, presented when we announce the channel, so it has to let us in.
+    /// Peers that are already accepted stay.
+    pub fn set_membership(&mut self, invite: Invite) -> Result<(), InviteError> {
+        let owner = invite.owner()?;
+        let channel = invite.channel.clone();
+        invite.verify(&channel, &owner, &self.peer_id, invite::unix_now())?;
+        self.memberships
+            .insert(channel.clone(), Membership { owner, invite });
+
+        let invited = self
+            .mesh
+            .get(&channel)
+            .into_iter()
+            .flatten()
+            .filter(|(peer_id, stage)| {
+                matches!(stage, Stage::Requested) && self.invited(&channel, peer_id).is_ok()
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect::<Vec<PeerId>>();
+        for peer_id in invited {
+            self.auto_accept_peer(channel.clone(), peer_id);
+        }
+
+        // Announce again, now with the invite.
+        if self.connected.contains(&channel) {
+            self.connect(channel);
+        }
+        Ok(())
+    }
+
+    pub fn remove_membership(&mut self, channel: &str) {
+        if self.memberships.remove(channel).is_some() && self.connected.contains(channel) {
+            self.connect(channel.to_string());
+        }
+    }
+
+    /// Whether the invite the peer sent lets it into the channel with an owner.
+    fn invited(&self, channel: &str, peer_id: &PeerId) -> Result<(), InviteError> {
+        let Some(membership) = self.memberships.get(channel) else {
+            return Ok(());
+        };
+        self.remote_invites
+            .get(channel)
+            .and_then(|invites| invites.get(peer_id))
+            .ok_or(InviteError::Missing)?
+            .verify(channel, &membership.owner, peer_id, invite::unix_now())
+    }
+
+    /// Protects the channel with a key derived from `passphrase`.
+    ///
+    /// Peers that prove they have it are accepted whatever the auto accept policy,
+    /// the others are refused. Removing a member takes a new passphrase.
+    pub fn set_passphrase(&mut self, channel: String, passphrase: &str) {
+        let key = ChannelKey::from_passphrase(&channel, passphrase);
+        self.set_key(channel, KeySource::Passphrase, key);
+    }
+
+    /// Protects the channel with a random key that every peer we accept gets.
+    ///
+    /// Peers without it still ask to join, the key is rotated when a member is removed.
+    pub fn create_key(&mut self, channel: String) {
+        let key = ChannelKey::generate(0);
+        for peer_id in self.accepted_peers(&channel) {
+            self.send_key(peer_id, channel.clone(), key.clone());
+        }
+        self.set_key(channel, KeySource::Created, key);
+    }
+
+    /// Stops protecting the channel, peers that are already accepted stay.
+    pub fn remove_key(&mut self, channel: String) {
+        self.keys.remove(&channel);
+        self.remote_proofs.remove(&channel);
+        if self.connected.contains(&channel) {
+            self.connect(channel);
+        }
+    }
+
+    fn set_key(&mut self, channel: String, source: KeySource, key: ChannelKey) {
+        let previous = self
+            .keys
+            .remove(&channel)
+            .filter(|keys| keys.source == source)
+            .map(|keys| keys.current);
+        self.events
+            .push_back(ToSwarm::GenerateEvent(event::BehaviourEvent::ChannelKey {
+                channel: channel.clone(),
+                epoch: key.epoch(),
+                source: source.clone(),
+            }));
+        let keys = ChannelKeys {
+            source,
+            current: key,
+            previous,
+        };
+
+        // Peers that proved the key before we had it, the key alone does not let them in.
+        let proven = self
+            .remote_proofs
+            .remove(&channel)
+            .into_iter()
+            .flatten()
+            .filter(|(peer_id, proof)| {
+                matches!(
+                    self.mesh.get(&channel).and_then(|mesh| mesh.get(peer_id)),
+                    Some(Stage::Requested)
+                ) && keys.verify(&channel, peer_id, proof)
+                    && (self.memberships.contains_key(&channel)
+                        || self.auto_accepts(&channel, peer_id))
+            })
+            .map(|(peer_id, _)| peer_id)
+            .collect::<Vec<PeerId>>();
+        self.keys.insert(channel.clone(), keys);
+        for peer_id in proven {
+            self.auto_accept_peer(channel.clone(), peer_id);
+        }
+
+        // Announce again, now with the proof.
+        if self.connected.contains(&channel) {
+            self.connect(channel);
+        }
+    }
+
+    /// Hands a new key to every member but the removed one, who cannot open new frames.
+    fn rotate_key(&mut self, channel: &str) {
+        let epoch = match self.keys.get(channel) {
+            Some(keys) if keys.source == KeySource::Created => keys.current.epoch(),
+            _ => return,
+        };
+        let key = ChannelKey::generate(epoch.wrapping_add(1));
+        for peer_id in self.accepted_peers(channel) {
+            self.send_key(peer_id, channel.to_string(), key.clone());
+        }
+        self.set_key(channel.to_string(), KeySource::Created, key);
+    }
+
+    fn send_key(&mut self, peer_id: PeerId, channel: String, key: ChannelKey) {
+        self.events.push_back(ToSwarm::NotifyHandler {
+            peer_id,
+            handler: libp2p::swarm::NotifyHandler::Any,
+            event: handler::InputEvent::Key { channel, key },
+        });
+    }
+
+    fn credentials(&self, channel: &str) -> Credentials {
+        Credentials {
+            proof: self
+                .keys
+                .get(channel)
+                .map(|keys| keys.current.proof(channel, &self.peer_id)),
+            invite: self
+                .memberships
+                .get(channel)
+                .map(|membership| membership.invite.clone()),
+        }
+    }
+
+    /// The channels a new connection to `peer_id` announces, with what lets us in.
+    fn announced(&self, peer_id: &PeerId) -> HashMap<String, Credentials> {
+        self.connected
+            .iter()
+            .filter(|channel| {
+                self.calls
+                    .get(*channel)
+                    .is_none_or(|call| call.peer == *peer_id)
+            })
+            .map(|channel| (channel.clone(), self.credentials(channel)))
+            .collect()
+    }
+
+    /// Where the key of a protected channel comes from and its epoch.
+    pub fn channel_key(&self, channel: &str) -> Option<(KeySource, u32)> {
+        self.keys
+            .get(channel)
+            .map(|keys| (keys.source.clone(), keys.current.epoch()))
+    }
+
+    fn accepted_peers(&self, channel: &str) -> Vec<PeerId> {
+        self.mesh
+            .get(channel)
+            .into_iter()
+            .flatten()
+            .filter(|(_, stage)| matches!(stage, Stage::Accepted))
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+
+    /// Drops a peer without the key or the invite the channel asks for.
+    fn refuse_peer(&mut self, channel: String, peer_id: PeerId, reason: &str) {
+        log::debug!("Refusing {peer_id} into {channel}: {reason}");
+        if let Some(mesh) = self.mesh.get_mut(&channel) {
+            mesh.remove(&peer_id);
+        }
+        self.events
+            .push_back(ToSwarm::GenerateEvent(event::BehaviourEvent::Refused {
+                channel: channel.clone(),
+                from: peer_id,
+            }));
+        self.update_channel_codec(&channel);
+    }
+
+    /// Tells every accepted peer of the channel that we started or stopped recording it.
+    pub fn set_recording(&mut self, channel: String, active: bool) {
+        if active {
+            self.recording.insert(channel.clone());
+        } else {
+            self.recording.remove(&channel);
+        }
+        let peers = self
+            .mesh
+            .get(&channel)
+            .into_iter()
+            .flatten()
+            .filter(|(_, stage)| matches!(stage, Stage::Accepted))
+            .map(|(peer_id, _)| *peer_id)
+            .collect::<Vec<PeerId>>();
+        for peer in peers {
+            self.notify_recording(peer, channel.clone(), active);
+        }
+    }
+
+    fn notify_recording(&mut self, peer_id: PeerId, channel: String, active: bool) {
+        self.events.push_back(ToSwarm::NotifyHandler {
+            peer_id,
+            handler: libp2p::swarm::NotifyHandler::Any,
+            event: handler::InputEvent::Recording { channel, active },
+        });
+    }
+
+    /// Sends `data` to every channel that agreed on `codec`.
+    pub fn audio_packet(&mut self, codec: String, sequence: u32, timestamp: u32, data: Vec<u8>) {
         for (channel, stages) in self.mesh.iter() {
+            match self.channel_codecs.get(channel) {
+                Some(agreed) if agreed.name == codec => {}
+                _ => continue,
+            }
+
             let peers = stages
                 .iter()
                 .flat_map(|(p, s)| {
@@ -73,12 +708,30 @@ impl TheManBehaviour {
                 })
                 .collect::<Vec<PeerId>>();
 
+            let data = match self.keys.get(channel) {
+                Some(keys) => {
+                    match keys
+                        .current
+                        .seal(&voice_header(channel, &codec, sequence, timestamp), &data)
+                    {
+                        Ok(sealed) => sealed,
+                        Err(error) => {
+                            log::warn!("{error} for {channel}");
+                            continue;
+                        }
+                    }
+                }
+                None => data.clone(),
+            };
+
             for peer in peers {
                 self.events.push_back(ToSwarm::NotifyHandler {
                     peer_id: peer,
                     handler: libp2p::swarm::NotifyHandler::Any,
                     event: handler::InputEvent::VoicePacket {
                         codec: codec.clone(),
+                        sequence,
+                        timestamp,
                         data: data.clone(),
                         channel: channel.clone(),
                     },
@@ -92,16 +745,86 @@ impl TheManBehaviour {
         } else {
             let mut hash = HashMap::new();
             hash.insert(peer_id, Stage::Accepted);
-            self.mesh.insert(channel, hash);
+            self.mesh.insert(channel.clone(), hash);
         }
+        if let Some(keys) = self.keys.get(&channel) {
+            if keys.source == KeySource::Created {
+                let key = keys.current.clone();
+                self.send_key(peer_id, channel.clone(), key);
+            }
+        }
+        // Whoever joins a recorded channel has to know before they talk.
+        if self.recording.contains(&channel) {
+            self.notify_recording(peer_id, channel.clone(), true);
+        }
+        self.update_channel_codec(&channel);
     }
     pub fn refuse(&mut self, channel: String, peer_id: PeerId) {
         if let Some(mesh) = self.mesh.get_mut(&channel) {
-            mesh.insert(peer_id, Stage::Requested);
+            if let Some(Stage::Accepted) = mesh.insert(peer_id, Stage::Requested) {
+                self.rotate_key(&channel);
+            }
         } else {
             let mut hash = HashMap::new();
             hash.insert(peer_id, Stage::Requested);
-            self.mesh.insert(channel, hash);
+            self.mesh.insert(channel.clone(), hash);
+        }
+        self.update_channel_codec(&channel);
+    }
+
+    pub fn channel_codec(&self, channel: &str) -> Option<&CodecInfo> {
+        self.channel_codecs.get(channel)
+    }
+
+    /// What the channels we are in send with.
+    pub fn channel_codecs(&self) -> impl Iterator<Item = &CodecInfo> {
+        self.channel_codecs.values()
+    }
+
+    /// The first of our codecs that every accepted peer of the channel advertised.
+    ///
+    /// Falls back to our preferred codec when there is no common one.
+    fn agreed_codec(&self, channel: &str) -> Option<CodecInfo> {
+        let remote = self.remote_codecs.get(channel);
+        let accepted = self
+            .mesh
+            .get(channel)
+            .into_iter()
+            .flatten()
+            .filter(|(_, stage)| matches!(stage, Stage::Accepted))
+            .map(|(peer_id, _)| peer_id)
+            .collect::<Vec<&PeerId>>();
+
+        self.codecs
+            .iter()
+            .find(|codec| {
+                accepted.iter().all(|peer_id| {
+                    remote
+                        .and_then(|remote| remote.get(peer_id))
+                        .map(|codecs| codecs.iter().any(|remote| remote.name == codec.name))
+                        .unwrap_or(false)
+                })
+            })
+            .or_else(|| self.codecs.first())
+            .cloned()
+    }
+
+    fn update_channel_codec(&mut self, channel: &str) {
+        if !self.connected.contains(channel) {
+            return;
+        }
+        let Some(codec) = self.agreed_codec(channel) else {
+            return;
+        };
+        if self.channel_codecs.get(channel) != Some(&codec) {
+            self.channel_codecs
+                .insert(channel.to_string(), codec.clone());
+            self.events.push_back(ToSwarm::GenerateEvent(
+                event::BehaviourEvent::ChannelCodec {
+                    channel: channel.to_string(),
+                    codec,
+                },
+            ));
         }
     }
 }
@@ -112,39 +835,76 @@ impl NetworkBehaviour for TheManBehaviour {
     type ToSwarm = event::BehaviourEvent;
 
     fn on_swarm_event(&mut self, event: libp2p::swarm::FromSwarm<Self::ConnectionHandler>) {
-        match event {
-            libp2p::swarm::FromSwarm::ConnectionClosed(event) => {
-                self.events.push_back(ToSwarm::GenerateEvent(
-                    event::BehaviourEvent::VoiceDisconnected {
-                        from: event.peer_id,
-                    },
-                ));
-                self.peers.remove(&event.peer_id);
+        if let libp2p::swarm::FromSwarm::ConnectionClosed(event) = event {
+            self.events.push_back(ToSwarm::GenerateEvent(
+                event::BehaviourEvent::VoiceDisconnected {
+                    from: event.peer_id,
+                },
+            ));
+            self.peers.remove(&event.peer_id);
+            if event.remaining_established == 0 {
+                let calls = self
+                    .calls
+                    .iter()
+                    .filter(|(_, call)| call.peer == event.peer_id)
+                    .map(|(call, _)| call.clone())
+                    .collect::<Vec<String>>();
+                for call in calls {
+                    self.end_call(&call, CallEnd::Disconnected, false);
+                }
             }
-            _ => {}
         }
     }
 
     fn on_connection_handler_event(
         &mut self,
         peer_id: libp2p::PeerId,
-        connection_id: libp2p::swarm::ConnectionId,
+        _connection_id: libp2p::swarm::ConnectionId,
         event: handler::OutputEvent,
     ) {
         // println!("SWEV: PeerId: {peer_id}, event: {event:?}");
         match event {
             handler::OutputEvent::VoicePacket {
                 codec,
+                sequence,
+                timestamp,
                 data,
                 channel,
             } => {
                 if self.connected.contains(&channel) {
                     if let Some(connection) = self.mesh.get(&channel) {
                         if let Some(Stage::Accepted) = connection.get(&peer_id) {
+                            let remote = self
+                                .remote_codecs
+                                .get(&channel)
+                                .and_then(|remote| remote.get(&peer_id))
+                                .and_then(|codecs| codecs.iter().find(|c| c.name == codec));
+                            let Some(codec) = remote.cloned() else {
+                                log::debug!(
+                                    "Dropping voice from {peer_id} with unadvertised codec: {codec}"
+                                );
+                                return;
+                            };
+                            let data = match self.keys.get(&channel) {
+                                Some(keys) => {
+                                    let header =
+                                        voice_header(&channel, &codec.name, sequence, timestamp);
+                                    let Some(data) = keys.open(&header, &data) else {
+                                        log::debug!(
+                                            "Dropping voice from {peer_id} the key of {channel} does not open"
+                                        );
+                                        return;
+                                    };
+                                    data
+                                }
+                                None => data,
+                            };
                             self.events.push_back(ToSwarm::GenerateEvent(
                                 event::BehaviourEvent::VoicePacket {
                                     from: peer_id,
                                     codec,
+                                    sequence,
+                                    timestamp,
                                     data,
                                     channel,
                                 },
@@ -153,24 +913,103 @@ impl NetworkBehaviour for TheManBehaviour {
                     }
                 }
             }
-            handler::OutputEvent::Connected(channel) => {
-                let stage = if self.auto_accept {
-                    Stage::Accepted
+            handler::OutputEvent::Connected {
+                channel,
+                codecs,
+                proof,
+            } => {
+                if let Some(remote) = self.remote_codecs.get_mut(&channel) {
+                    remote.insert(peer_id, codecs);
                 } else {
-                    self.events
-                        .push_back(ToSwarm::GenerateEvent(event::BehaviourEvent::Request {
-                            channel: channel.clone(),
-                            from: peer_id,
-                        }));
-                    Stage::Requested
+                    let mut hash = HashMap::new();
+                    hash.insert(peer_id, codecs);
+                    self.remote_codecs.insert(channel.clone(), hash);
+                }
+
+                if self
+                    .calls
+                    .get(&channel)
+                    .is_some_and(|call| call.peer != peer_id)
+                {
+                    self.refuse_peer(channel, peer_id, "Not in the call");
+                    return;
+                }
+                // The owner decides who gets in, the GUI is not asked.
+                if let Err(error) = self.invited(&channel, &peer_id) {
+                    self.refuse_peer(channel, peer_id, &error.to_string());
+                    return;
+                }
+                let stage = self.mesh.get(&channel).and_then(|mesh| mesh.get(&peer_id));
+                let keys = self.keys.get(&channel);
+                let proven = match (keys, &proof) {
+                    (Some(keys), Some(proof)) => keys.verify(&channel, &peer_id, proof),
+                    _ => false,
                 };
+                // A protected channel with an owner takes both the invite and the key.
+                if self.memberships.contains_key(&channel)
+                    && (keys.is_none() || proven)
+                    && !matches!(stage, Some(Stage::Accepted))
+                {
+                    self.auto_accept_peer(channel, peer_id);
+                    return;
+                }
+                // The key only lets the peer ask, the policy still decides.
+                if !proven {
+                    match (keys, proof) {
+                        // Rotated before us, we check it when the new key arrives.
+                        (Some(keys), Some(proof)) if proof.epoch > keys.current.epoch() => {
+                            let known = stage.is_some();
+                            self.remote_proofs
+                                .entry(channel.clone())
+                                .or_default()
+                                .insert(peer_id, proof);
+                            if known {
+                                self.update_channel_codec(&channel);
+                                return;
+                            }
+                        }
+                        // Only the creator lets peers without the key ask to join.
+                        (Some(keys), _) if keys.source != KeySource::Created => {
+                            self.refuse_peer(channel, peer_id, "No channel key");
+                            return;
+                        }
+                        (None, Some(proof)) => {
+                            self.remote_proofs
+                                .entry(channel.clone())
+                                .or_default()
+                                .insert(peer_id, proof);
+                        }
+                        _ => {}
+                    }
+                }
+
+                // Peers announce again when their codecs change, that is not a new request.
+                if let Some(true) = self
+                    .mesh
+                    .get(&channel)
+                    .map(|mesh| mesh.contains_key(&peer_id))
+                {
+                    self.update_channel_codec(&channel);
+                    return;
+                }
+
+                if self.auto_accepts(&channel, &peer_id) {
+                    self.auto_accept_peer(channel, peer_id);
+                    return;
+                }
+                self.events
+                    .push_back(ToSwarm::GenerateEvent(event::BehaviourEvent::Request {
+                        channel: channel.clone(),
+                        from: peer_id,
+                    }));
                 if let Some(mesh) = self.mesh.get_mut(&channel) {
-                    mesh.insert(peer_id, stage);
+                    mesh.insert(peer_id, Stage::Requested);
                 } else {
                     let mut hash = HashMap::new();
-                    hash.insert(peer_id, stage);
-                    self.mesh.insert(channel, hash);
+                    hash.insert(peer_id, Stage::Requested);
+                    self.mesh.insert(channel.clone(), hash);
                 }
+                self.update_channel_codec(&channel);
             }
             handler::OutputEvent::Disconnected(channel) => {
                 self.events.push_back(ToSwarm::GenerateEvent(
@@ -180,20 +1019,127 @@ impl NetworkBehaviour for TheManBehaviour {
                     },
                 ));
                 if let Some(mesh) = self.mesh.get_mut(&channel) {
-                    mesh.remove(&peer_id);
+                    if let Some(Stage::Accepted) = mesh.remove(&peer_id) {
+                        self.rotate_key(&channel);
+                    }
+                }
+                if let Some(remote) = self.remote_codecs.get_mut(&channel) {
+                    remote.remove(&peer_id);
+                }
+                if let Some(proofs) = self.remote_proofs.get_mut(&channel) {
+                    proofs.remove(&peer_id);
+                }
+                if let Some(invites) = self.remote_invites.get_mut(&channel) {
+                    invites.remove(&peer_id);
+                }
+                self.update_channel_codec(&channel);
+            }
+            handler::OutputEvent::Key {
+                channel,
+                epoch,
+                key,
+            } => {
+                let Some(key) = ChannelKey::from_bytes(epoch, &key) else {
+                    log::debug!("Dropping key of {channel} from {peer_id} with a wrong length");
+                    return;
+                };
+                let from_creator = match self.keys.get(&channel) {
+                    // We announced without a proof, the creator hands the key out when it
+                    // accepts us. It has to be the key the peer proved in its own announce,
+                    // a peer that only asked to join us has no proof to match.
+                    None => {
+                        self.audience(&channel).contains(&peer_id)
+                            && self
+                                .remote_proofs
+                                .get(&channel)
+                                .and_then(|proofs| proofs.get(&peer_id))
+                                .is_some_and(|proof| key.verify(&channel, &peer_id, proof))
+                    }
+                    Some(keys) => {
+                        keys.source == KeySource::Received(peer_id) && epoch > keys.current.epoch()
+                    }
+                };
+                if !self.connected.contains(&channel) || !from_creator {
+                    log::debug!("Ignoring key of {channel} from {peer_id}");
+                    return;
+                }
+                // Its proof only shows it has the key it sent, whether we accept the creator
+                // is still up to the auto accept policy or the user.
+                if let Some(proofs) = self.remote_proofs.get_mut(&channel) {
+                    proofs.remove(&peer_id);
+                }
+                self.set_key(channel, KeySource::Received(peer_id), key);
+            }
+            handler::OutputEvent::Recording { channel, active } => {
+                let known = self.connected.contains(&channel)
+                    && self
+                        .mesh
+                        .get(&channel)
+                        .is_some_and(|mesh| mesh.contains_key(&peer_id));
+                if known {
+                    self.events.push_back(ToSwarm::GenerateEvent(
+                        event::BehaviourEvent::Recording {
+                            channel,
+                            from: peer_id,
+                            active,
+                        },
+                    ));
                 }
             }
+            handler::OutputEvent::Invite(invite) => {
+                if self.connected.contains(&invite.channel) {
+                    self.remote_invites
+                        .entry(invite.channel.clone())
+                        .or_default()
+                        .insert(peer_id, invite);
+                }
+            }
+            handler::OutputEvent::Call { call, signal } => {
+                self.on_call_signal(peer_id, call, signal)
+            }
             handler::OutputEvent::SuccesfulyConnect => {
                 self.peers.insert(peer_id);
             }
+            handler::OutputEvent::StreamError(error) => {
+                for (channel, mesh) in self.mesh.iter() {
+                    if mesh.contains_key(&peer_id) {
+                        self.events.push_back(ToSwarm::GenerateEvent(
+                            event::BehaviourEvent::VoiceErrorConnection {
+                                to: peer_id,
+                                codec: self
+                                    .channel_codecs
+                                    .get(channel)
+                                    .map(|codec| codec.name.clone())
+                                    .unwrap_or_default(),
+                                channel: channel.clone(),
+                                error: error.to_string(),
+                            },
+                        ));
+                    }
+                }
+            }
         }
     }
 
     fn poll(
         &mut self,
         cx: &mut std::task::Context<'_>,
-        params: &mut impl libp2p::swarm::PollParameters,
+        _params: &mut impl libp2p::swarm::PollParameters,
     ) -> std::task::Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        let unanswered = self
+            .calls
+            .iter_mut()
+            .filter_map(|(call, state)| match &mut state.stage {
+                CallStage::Ringing(deadline) => {
+                    deadline.as_mut().poll(cx).is_ready().then(|| call.clone())
+                }
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+        for call in unanswered {
+            self.end_call(&call, CallEnd::NoAnswer, true);
+        }
+
         if let Some(event) = self.events.pop_front() {
             return std::task::Poll::Ready(event);
         }
@@ -204,33 +1150,55 @@ impl NetworkBehaviour for TheManBehaviour {
         &mut self,
         _connection_id: libp2p::swarm::ConnectionId,
         peer: libp2p::PeerId,
-        local_addr: &libp2p::Multiaddr,
-        remote_addr: &libp2p::Multiaddr,
+        _local_addr: &libp2p::Multiaddr,
+        _remote_addr: &libp2p::Multiaddr,
     ) -> Result<libp2p::swarm::THandler<Self>, libp2p::swarm::ConnectionDenied> {
-        Connection::new(self.connected.clone())
+        Connection::new(self.announced(&peer), self.codecs.clone())
     }
 
     fn handle_established_outbound_connection(
         &mut self,
         _connection_id: libp2p::swarm::ConnectionId,
         peer: libp2p::PeerId,
-        addr: &libp2p::Multiaddr,
-        role_override: libp2p::core::Endpoint,
+        _addr: &libp2p::Multiaddr,
+        _role_override: libp2p::core::Endpoint,
     ) -> Result<libp2p::swarm::THandler<Self>, libp2p::swarm::ConnectionDenied> {
-        Connection::new(self.connected.clone())
+        Connection::new(self.announced(&peer), self.codecs.clone())
     }
 }
 
 #[derive(Debug)]
 pub enum Failure {
+    Io {
+        error: std::io::Error,
+    },
+    Frame {
+        error: frame::FrameError,
+    },
+    Closed,
     Other {
         error: Box<dyn std::error::Error + Send + 'static>,
     },
 }
 
+impl From<std::io::Error> for Failure {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io { error }
+    }
+}
+
+impl From<frame::FrameError> for Failure {
+    fn from(error: frame::FrameError) -> Self {
+        Self::Frame { error }
+    }
+}
+
 impl std::fmt::Display for Failure {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Failure::Io { error } => write!(f, "TheMan io error: {error}"),
+            Failure::Frame { error } => write!(f, "TheMan frame error: {error}"),
+            Failure::Closed => write!(f, "TheMan stream closed by remote"),
             Failure::Other { error } => write!(f, "TheMan error: {error}"),
         }
     }
@@ -239,7 +1207,440 @@ impl std::fmt::Display for Failure {
 impl std::error::Error for Failure {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
+            Failure::Io { error } => Some(error),
+            Failure::Frame { error } => Some(error),
+            Failure::Closed => None,
             Failure::Other { error } => Some(&**error),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{identity::Keypair, swarm::ConnectionId};
+
+    use super::*;
+
+    const CHANNEL: &str = "lobby";
+
+    /// Us in the channel of `owner`, protected with a passphrase.
+    fn member(owner: &Keypair) -> TheManBehaviour {
+        let mut behaviour = TheManBehaviour::new(PeerId::random());
+        let invite = Invite::sign(owner, CHANNEL.into(), Some(behaviour.peer_id()), None).unwrap();
+        behaviour.set_membership(invite).unwrap();
+        behaviour.set_passphrase(CHANNEL.into(), "hunter2");
+        behaviour.connect(CHANNEL.into());
+        behaviour
+    }
+
+    fn receive(behaviour: &mut TheManBehaviour, peer_id: PeerId, event: handler::OutputEvent) {
+        behaviour.on_connection_handler_event(peer_id, ConnectionId::new_unchecked(0), event);
+    }
+
+    fn announce(behaviour: &mut TheManBehaviour, peer_id: PeerId, proof: Option<KeyProof>) {
+        receive(
+            behaviour,
+            peer_id,
+            handler::OutputEvent::Connected {
+                channel: CHANNEL.into(),
+                codecs: Vec::new(),
+                proof,
+            },
+        );
+    }
+
+    fn accepted(behaviour: &TheManBehaviour, peer_id: &PeerId) -> bool {
+        matches!(
+            behaviour
+                .mesh
+                .get(CHANNEL)
+                .and_then(|mesh| mesh.get(peer_id)),
+            Some(Stage::Accepted)
+        )
+    }
+
+    fn invited_peer(behaviour: &mut TheManBehaviour, owner: &Keypair) -> PeerId {
+        let peer_id = PeerId::random();
+        let invite = Invite::sign(owner, CHANNEL.into(), Some(peer_id), None).unwrap();
+        receive(behaviour, peer_id, handler::OutputEvent::Invite(invite));
+        peer_id
+    }
+
+    #[test]
+    fn owner_and_key_both_let_in() {
+        let owner = Keypair::generate_ed25519();
+        let mut behaviour = member(&owner);
+        let peer_id = invited_peer(&mut behaviour, &owner);
+        let proof = ChannelKey::from_passphrase(CHANNEL, "hunter2").proof(CHANNEL, &peer_id);
+        announce(&mut behaviour, peer_id, Some(proof));
+        assert!(accepted(&behaviour, &peer_id));
+    }
+
+    #[test]
+    fn invite_without_the_key_is_refused() {
+        let owner = Keypair::generate_ed25519();
+        let mut behaviour = member(&owner);
+
+        let peer_id = invited_peer(&mut behaviour, &owner);
+        announce(&mut behaviour, peer_id, None);
+        assert!(!accepted(&behaviour, &peer_id));
+
+        let peer_id = invited_peer(&mut behaviour, &owner);
+        let proof = ChannelKey::from_passphrase(CHANNEL, "wrong").proof(CHANNEL, &peer_id);
+        announce(&mut behaviour, peer_id, Some(proof));
+        assert!(!accepted(&behaviour, &peer_id));
+    }
+
+    /// Us in the channel without its key, `peer_id` announced it with `proof`.
+    fn joining(proof: impl FnOnce(&PeerId) -> Option<KeyProof>) -> (TheManBehaviour, PeerId) {
+        let mut behaviour = TheManBehaviour::new(PeerId::random());
+        let peer_id = PeerId::random();
+        receive(
+            &mut behaviour,
+            peer_id,
+            handler::OutputEvent::SuccesfulyConnect,
+        );
+        behaviour.connect(CHANNEL.into());
+        announce(&mut behaviour, peer_id, proof(&peer_id));
+        (behaviour, peer_id)
+    }
+
+    fn send_key(behaviour: &mut TheManBehaviour, peer_id: PeerId, key: &ChannelKey) {
+        receive(
+            behaviour,
+            peer_id,
+            handler::OutputEvent::Key {
+                channel: CHANNEL.into(),
+                epoch: key.epoch(),
+                key: key.bytes().to_vec(),
+            },
+        );
+    }
+
+    #[test]
+    fn creator_key_is_taken_without_accepting_the_creator() {
+        let key = ChannelKey::generate(0);
+        let (mut behaviour, creator) = joining(|peer_id| Some(key.proof(CHANNEL, peer_id)));
+        send_key(&mut behaviour, creator, &key);
+        assert_eq!(
+            behaviour.channel_key(CHANNEL),
+            Some((KeySource::Received(creator), 0))
+        );
+        assert!(!accepted(&behaviour, &creator));
+    }
+
+    #[test]
+    fn key_from_a_peer_asking_to_join_is_ignored() {
+        let (mut behaviour, peer_id) = joining(|_| None);
+        send_key(&mut behaviour, peer_id, &ChannelKey::generate(0));
+        assert_eq!(behaviour.channel_key(CHANNEL), None);
+        assert!(!accepted(&behaviour, &peer_id));
+    }
+
+    #[test]
+    fn key_that_does_not_match_the_proof_is_ignored() {
+        let key = ChannelKey::generate(0);
+        let (mut behaviour, creator) = joining(|peer_id| Some(key.proof(CHANNEL, peer_id)));
+        send_key(&mut behaviour, creator, &ChannelKey::generate(0));
+        assert_eq!(behaviour.channel_key(CHANNEL), None);
+    }
+
+    #[test]
+    fn key_without_an_invite_is_refused() {
+        let owner = Keypair::generate_ed25519();
+        let mut behaviour = member(&owner);
+        let peer_id = PeerId::random();
+        let proof = ChannelKey::from_passphrase(CHANNEL, "hunter2").proof(CHANNEL, &peer_id);
+        announce(&mut behaviour, peer_id, Some(proof));
+        assert!(!accepted(&behaviour, &peer_id));
+    }
+
+    #[test]
+    fn key_alone_does_not_bypass_the_policy() {
+        let mut behaviour = TheManBehaviour::new(PeerId::random());
+        behaviour.set_passphrase(CHANNEL.into(), "hunter2");
+        behaviour.connect(CHANNEL.into());
+        let key = ChannelKey::from_passphrase(CHANNEL, "hunter2");
+
+        let peer_id = PeerId::random();
+        announce(&mut behaviour, peer_id, Some(key.proof(CHANNEL, &peer_id)));
+        assert!(!accepted(&behaviour, &peer_id));
+        assert!(matches!(
+            behaviour.mesh[CHANNEL].get(&peer_id),
+            Some(Stage::Requested)
+        ));
+
+        behaviour.set_auto_accept(CHANNEL.into(), AutoAccept::Everyone);
+        let peer_id = PeerId::random();
+        announce(&mut behaviour, peer_id, Some(key.proof(CHANNEL, &peer_id)));
+        assert!(accepted(&behaviour, &peer_id));
+    }
+
+    /// Whether a peer asking to join the open channel gets in under `policy`.
+    fn auto_accepted(policy: AutoAccept, friends: &[PeerId], peer_id: PeerId) -> bool {
+        let mut behaviour = TheManBehaviour::new(PeerId::random());
+        behaviour.set_friends(friends.iter().copied());
+        behaviour.set_auto_accept(CHANNEL.into(), policy);
+        behaviour.connect(CHANNEL.into());
+        announce(&mut behaviour, peer_id, None);
+        accepted(&behaviour, &peer_id)
+    }
+
+    #[test]
+    fn manual_accepts_nobody() {
+        let peer_id = PeerId::random();
+        assert!(!auto_accepted(AutoAccept::Manual, &[peer_id], peer_id));
+    }
+
+    #[test]
+    fn friends_accepts_only_friends() {
+        let friend = PeerId::random();
+        assert!(auto_accepted(AutoAccept::Friends, &[friend], friend));
+        assert!(!auto_accepted(
+            AutoAccept::Friends,
+            &[friend],
+            PeerId::random()
+        ));
+    }
+
+    #[test]
+    fn everyone_accepts_anyone() {
+        assert!(auto_accepted(AutoAccept::Everyone, &[], PeerId::random()));
+    }
+
+    #[test]
+    fn allow_list_accepts_only_listed_peers() {
+        let listed = PeerId::random();
+        let policy = || AutoAccept::AllowList(vec![listed]);
+        assert!(auto_accepted(policy(), &[], listed));
+        assert!(!auto_accepted(policy(), &[listed], PeerId::random()));
+    }
+
+    fn signal(behaviour: &mut TheManBehaviour, peer_id: PeerId, call: &str, signal: CallSignal) {
+        receive(
+            behaviour,
+            peer_id,
+            handler::OutputEvent::Call {
+                call: call.into(),
+                signal,
+            },
+        );
+    }
+
+    /// Takes the call events that were generated so far.
+    fn call_events(behaviour: &mut TheManBehaviour) -> Vec<event::BehaviourEvent> {
+        behaviour
+            .events
+            .drain(..)
+            .filter_map(|event| match event {
+                ToSwarm::GenerateEvent(
+                    event @ (event::BehaviourEvent::IncomingCall { .. }
+                    | event::BehaviourEvent::CallStarted { .. }
+                    | event::BehaviourEvent::CallEnded { .. }),
+                ) => Some(event),
+                _ => None,
+            })
+            .collect()
+    }
+
+    const CALL: &str = "call/test";
+
+    /// Why the call ended, if it did since the last events were taken.
+    fn ended(behaviour: &mut TheManBehaviour) -> Option<CallEnd> {
+        call_events(behaviour)
+            .into_iter()
+            .find_map(|event| match event {
+                event::BehaviourEvent::CallEnded { reason, .. } => Some(reason),
+                _ => None,
+            })
+    }
+
+    /// The call signals queued for the peers, in order.
+    fn sent(behaviour: &TheManBehaviour) -> Vec<(PeerId, CallSignal)> {
+        behaviour
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                ToSwarm::NotifyHandler {
+                    peer_id,
+                    event: handler::InputEvent::Call { signal, .. },
+                    ..
+                } => Some((*peer_id, *signal)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Us calling a connected peer, the call rings.
+    fn calling() -> (TheManBehaviour, PeerId, String) {
+        let mut behaviour = TheManBehaviour::new(PeerId::random());
+        let peer_id = PeerId::random();
+        receive(
+            &mut behaviour,
+            peer_id,
+            handler::OutputEvent::SuccesfulyConnect,
+        );
+        let call = behaviour.call(peer_id);
+        assert_eq!(sent(&behaviour), [(peer_id, CallSignal::Ring)]);
+        call_events(&mut behaviour);
+        (behaviour, peer_id, call)
+    }
+
+    /// `caller` rings us.
+    fn rung() -> (TheManBehaviour, PeerId) {
+        let mut behaviour = TheManBehaviour::new(PeerId::random());
+        let caller = PeerId::random();
+        signal(&mut behaviour, caller, CALL, CallSignal::Ring);
+        assert!(matches!(
+            call_events(&mut behaviour)[..],
+            [event::BehaviourEvent::IncomingCall { from, .. }] if from == caller
+        ));
+        (behaviour, caller)
+    }
+
+    #[tokio::test]
+    async fn busy_callee_answers_busy() {
+        let (mut behaviour, _) = rung();
+        let other = PeerId::random();
+        signal(&mut behaviour, other, "call/other", CallSignal::Ring);
+        assert_eq!(
+            sent(&behaviour),
+            [(other, CallSignal::Answer(CallAnswer::Busy))]
+        );
+        assert!(call_events(&mut behaviour).is_empty());
+        assert!(!behaviour.is_call("call/other"));
+        assert!(behaviour.is_call(CALL));
+
+        let (mut behaviour, peer_id, call) = calling();
+        signal(
+            &mut behaviour,
+            peer_id,
+            &call,
+            CallSignal::Answer(CallAnswer::Busy),
+        );
+        assert_eq!(ended(&mut behaviour), Some(CallEnd::Busy));
+        assert!(!behaviour.is_call(&call));
+    }
+
+    #[tokio::test]
+    async fn declined_call_ends_on_both_sides() {
+        let (mut behaviour, peer_id, call) = calling();
+        // Only the callee answers.
+        signal(
+            &mut behaviour,
+            PeerId::random(),
+            &call,
+            CallSignal::Answer(CallAnswer::Decline),
+        );
+        assert!(behaviour.is_call(&call));
+        signal(
+            &mut behaviour,
+            peer_id,
+            &call,
+            CallSignal::Answer(CallAnswer::Decline),
+        );
+        assert_eq!(ended(&mut behaviour), Some(CallEnd::Declined));
+        assert!(!behaviour.is_call(&call));
+
+        let (mut behaviour, caller) = rung();
+        behaviour.answer_call(CALL, CallAnswer::Decline);
+        assert_eq!(
+            sent(&behaviour),
+            [(caller, CallSignal::Answer(CallAnswer::Decline))]
+        );
+        assert_eq!(ended(&mut behaviour), Some(CallEnd::Declined));
+        assert!(!behaviour.is_call(CALL));
+    }
+
+    #[test]
+    fn caller_hanging_up_while_ringing_is_no_answer() {
+        let (mut behaviour, caller) = rung();
+        signal(&mut behaviour, caller, CALL, CallSignal::HangUp);
+        assert_eq!(ended(&mut behaviour), Some(CallEnd::NoAnswer));
+        assert!(!behaviour.is_call(CALL));
+        // Too late to answer.
+        behaviour.answer_call(CALL, CallAnswer::Accept);
+        assert!(sent(&behaviour).is_empty());
+        assert!(!behaviour.connected.contains(CALL));
+    }
+
+    #[tokio::test]
+    async fn active_call_hangs_up() {
+        let (mut behaviour, caller) = rung();
+        behaviour.answer_call(CALL, CallAnswer::Accept);
+        assert!(matches!(
+            call_events(&mut behaviour)[..],
+            [event::BehaviourEvent::CallStarted { with, .. }] if with == caller
+        ));
+        assert!(behaviour.connected.contains(CALL));
+        signal(&mut behaviour, caller, CALL, CallSignal::HangUp);
+        assert_eq!(ended(&mut behaviour), Some(CallEnd::HungUp));
+        assert!(!behaviour.connected.contains(CALL));
+
+        let (mut behaviour, peer_id, call) = calling();
+        signal(
+            &mut behaviour,
+            peer_id,
+            &call,
+            CallSignal::Answer(CallAnswer::Accept),
+        );
+        assert!(behaviour.connected.contains(&call));
+        behaviour.events.clear();
+        behaviour.hang_up(&call);
+        assert_eq!(sent(&behaviour), [(peer_id, CallSignal::HangUp)]);
+        assert_eq!(ended(&mut behaviour), Some(CallEnd::HungUp));
+        assert!(!behaviour.is_call(&call));
+    }
+
+    #[test]
+    fn peer_disconnecting_ends_the_call() {
+        let (mut behaviour, caller) = rung();
+        behaviour.answer_call(CALL, CallAnswer::Accept);
+        call_events(&mut behaviour);
+        let endpoint = libp2p::core::ConnectedPoint::Dialer {
+            address: libp2p::Multiaddr::empty(),
+            role_override: libp2p::core::Endpoint::Dialer,
+        };
+        behaviour.on_swarm_event(libp2p::swarm::FromSwarm::ConnectionClosed(
+            libp2p::swarm::behaviour::ConnectionClosed {
+                peer_id: caller,
+                connection_id: ConnectionId::new_unchecked(0),
+                endpoint: &endpoint,
+                handler: Connection::new(HashMap::new(), Vec::new()).unwrap(),
+                remaining_established: 0,
+            },
+        ));
+        assert_eq!(ended(&mut behaviour), Some(CallEnd::Disconnected));
+        assert!(!behaviour.is_call(CALL));
+        assert!(!behaviour.connected.contains(CALL));
+    }
+
+    #[test]
+    fn ring_cannot_name_a_channel() {
+        let mut behaviour = TheManBehaviour::new(PeerId::random());
+        let member = PeerId::random();
+        behaviour.connect(CHANNEL.into());
+        announce(&mut behaviour, member, None);
+        behaviour.accept(CHANNEL.into(), member);
+        // Named like a call, but a channel we are in.
+        behaviour.connect("call/lobby".into());
+        call_events(&mut behaviour);
+
+        let attacker = PeerId::random();
+        for call in [CHANNEL, "call/lobby", "other"] {
+            signal(&mut behaviour, attacker, call, CallSignal::Ring);
+            assert!(!behaviour.is_call(call), "{call}");
+            signal(&mut behaviour, attacker, call, CallSignal::HangUp);
+        }
+        assert!(call_events(&mut behaviour).is_empty());
+        assert!(behaviour.connected.contains(CHANNEL));
+        assert!(behaviour.connected.contains("call/lobby"));
+        assert!(accepted(&behaviour, &member));
+
+        // Other members still get in.
+        let peer_id = PeerId::random();
+        signal(&mut behaviour, attacker, CHANNEL, CallSignal::Ring);
+        announce(&mut behaviour, peer_id, None);
+        assert!(behaviour.mesh[CHANNEL].contains_key(&peer_id));
+    }
+}
@@ -1,16 +1,252 @@
 use bytes_kman::prelude::*;
 
+use super::invite::Invite;
+
 #[derive(Clone, serde::Serialize, serde::Deserialize, bytes_kman::Bytes)]
 pub enum Packet {
+    /// A frame without sequence or timestamp, only older peers send it.
     VoicePacket {
         codec: String,
-        data: Vec<u8>,
+        data: Bounded<u8>,
         channel: String,
     },
     VoiceDisconnect {
         channel: String,
     },
+    /// Announces the channel without codecs, only older peers send it.
     VoiceConnect {
         channel: String,
     },
+    /// The sender started or stopped recording the channel.
+    VoiceRecording {
+        channel: String,
+        active: bool,
+    },
+    /// A `VoiceCodecsConnect` for a protected channel, with the proof that the sender has its key.
+    VoiceKeyedConnect {
+        channel: String,
+        codecs: Bounded<CodecInfo>,
+        epoch: u32,
+        proof: Bounded<u8>,
+    },
+    /// The channel creator hands out the channel key, again with a new epoch on every rotation.
+    VoiceKey {
+        channel: String,
+        epoch: u32,
+        key: Bounded<u8>,
+    },
+    /// Sent before the announce of a channel with an owner, lets the sender in.
+    VoiceInvite {
+        invite: Invite,
+    },
+    /// Rings the receiver, `call` names the private channel the call talks in.
+    CallRing {
+        call: String,
+    },
+    CallAnswer {
+        call: String,
+        answer: CallAnswer,
+    },
+    /// Ends the call, or stops ringing when it was not answered yet.
+    CallHangUp {
+        call: String,
+    },
+    /// Announces the channel with the codecs the sender can use, in order of preference.
+    VoiceCodecsConnect {
+        channel: String,
+        codecs: Bounded<CodecInfo>,
+    },
+    VoiceFrame {
+        codec: String,
+        /// Incremented by one for every frame the sender encodes.
+        sequence: u32,
+        /// Position of the first sample, in samples of the codec sample rate.
+        timestamp: u32,
+        /// Exactly one frame of `codec`, sealed with the channel key when the channel has one.
+        data: Bounded<u8>,
+        channel: String,
+    },
+}
+
+/// How the callee answered a `CallRing`.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, bytes_kman::Bytes,
+)]
+pub enum CallAnswer {
+    Accept,
+    Decline,
+    /// Sent without asking when the callee is already in a call.
+    Busy,
+}
+
+/// A codec a peer can encode and decode, with the parameters its encoder uses.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, bytes_kman::Bytes)]
+pub struct CodecInfo {
+    pub name: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl Packet {
+    /// How many variants `Packet` has, new variants should only be appended.
+    pub const TYPES: u8 = 12;
+}
+
+/// A `Vec` that is decoded only when its length prefix fits in the remaining buffer.
+///
+/// The `Vec<T>` implementation of `TBytes` allocates the decoded length up front,
+/// so a corrupted length could allocate gigabytes before failing.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Bounded<T>(pub Vec<T>);
+
+impl<T> From<Vec<T>> for Bounded<T> {
+    fn from(value: Vec<T>) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: TBytes> TBytes for Bounded<T> {
+    fn size(&self) -> usize {
+        self.0.size()
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(buffer: &mut TBuffer) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        let len = usize::from_bytes(&mut buffer.get(..0usize.size())?.to_vec())?;
+        // Every element takes at least one byte.
+        if len > buffer.len() - 0usize.size() {
+            return None;
+        }
+        Vec::<T>::from_bytes(buffer).map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity::Keypair;
+
+    use super::*;
+    use crate::network::frame::{self, FrameDecoder, HEADER_SIZE};
+
+    /// The variant index, a variant added without a sample here does not compile.
+    fn index(packet: &Packet) -> u8 {
+        match packet {
+            Packet::VoicePacket { .. } => 0,
+            Packet::VoiceDisconnect { .. } => 1,
+            Packet::VoiceConnect { .. } => 2,
+            Packet::VoiceRecording { .. } => 3,
+            Packet::VoiceKeyedConnect { .. } => 4,
+            Packet::VoiceKey { .. } => 5,
+            Packet::VoiceInvite { .. } => 6,
+            Packet::CallRing { .. } => 7,
+            Packet::CallAnswer { .. } => 8,
+            Packet::CallHangUp { .. } => 9,
+            Packet::VoiceCodecsConnect { .. } => 10,
+            Packet::VoiceFrame { .. } => 11,
+        }
+    }
+
+    /// One of every variant, in variant order.
+    fn samples() -> Vec<Packet> {
+        let channel = String::from("lobby");
+        let codecs = Bounded(vec![CodecInfo {
+            name: "opus".into(),
+            sample_rate: 48000,
+            channels: 1,
+        }]);
+        vec![
+            Packet::VoicePacket {
+                codec: "opus".into(),
+                data: Bounded(vec![1, 2, 3]),
+                channel: channel.clone(),
+            },
+            Packet::VoiceDisconnect {
+                channel: channel.clone(),
+            },
+            Packet::VoiceConnect {
+                channel: channel.clone(),
+            },
+            Packet::VoiceRecording {
+                channel: channel.clone(),
+                active: true,
+            },
+            Packet::VoiceKeyedConnect {
+                channel: channel.clone(),
+                codecs: codecs.clone(),
+                epoch: 2,
+                proof: Bounded(vec![4; 32]),
+            },
+            Packet::VoiceKey {
+                channel: channel.clone(),
+                epoch: 2,
+                key: Bounded(vec![5; 32]),
+            },
+            Packet::VoiceInvite {
+                invite: Invite::sign(&Keypair::generate_ed25519(), channel.clone(), None, None)
+                    .unwrap(),
+            },
+            Packet::CallRing {
+                call: channel.clone(),
+            },
+            Packet::CallAnswer {
+                call: channel.clone(),
+                answer: CallAnswer::Busy,
+            },
+            Packet::CallHangUp {
+                call: channel.clone(),
+            },
+            Packet::VoiceCodecsConnect {
+                channel: channel.clone(),
+                codecs,
+            },
+            Packet::VoiceFrame {
+                codec: "opus".into(),
+                sequence: 1,
+                timestamp: 960,
+                data: Bounded(vec![1, 2, 3]),
+                channel,
+            },
+        ]
+    }
+
+    #[test]
+    fn types_counts_every_variant() {
+        let samples = samples();
+        assert_eq!(samples.len(), Packet::TYPES as usize);
+        for (position, packet) in samples.iter().enumerate() {
+            assert_eq!(index(packet) as usize, position);
+        }
+    }
+
+    #[test]
+    fn frames_carry_the_variant_index() {
+        for packet in samples() {
+            let frame = frame::encode(&packet).unwrap();
+            assert_eq!(frame[1], index(&packet));
+
+            let mut decoder = FrameDecoder::default();
+            decoder.extend(&frame);
+            let decoded = decoder.decode().unwrap().unwrap();
+            assert_eq!(index(&decoded), index(&packet));
+            assert_eq!(frame::encode(&decoded).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn bounded_rejects_a_length_past_the_buffer() {
+        let mut bytes = Bounded(vec![1u8, 2, 3]).to_bytes();
+        bytes.truncate(bytes.len() - 1);
+        assert!(Bounded::<u8>::from_bytes(&mut bytes).is_none());
+
+        let mut huge = usize::MAX.to_bytes();
+        huge.extend([0; HEADER_SIZE]);
+        assert!(Bounded::<u8>::from_bytes(&mut huge).is_none());
+    }
 }
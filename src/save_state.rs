@@ -3,12 +3,39 @@ use std::collections::HashMap;
 use chrono::{DateTime, Utc};
 use libp2p::{Multiaddr, PeerId};
 
+use the_man::{
+    network::{invite::Invite, AutoAccept},
+    Atom,
+};
+
 use crate::state::TheManState;
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Friend {
     pub peer_id: PeerId,
     pub name: String,
+    #[serde(default)]
+    pub voice: PeerVoice,
+}
+
+/// How a remote peer is played in voice channels.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PeerVoice {
+    /// Gain, `1.0` plays the peer as received.
+    pub volume: f32,
+    pub muted: bool,
+    /// From `-1.0` left to `1.0` right.
+    pub pan: f32,
+}
+
+impl Default for PeerVoice {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            muted: false,
+            pan: 0.0,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -18,6 +45,78 @@ pub enum ChannelType {
     Voice,
 }
 
+/// A channel of the channel list, saved as `(name, kind, auto_accept, membership)`.
+///
+/// Saves from before `auto_accept` have only the first two, from before `membership` the first three.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Channel {
+    pub name: String,
+    pub kind: ChannelType,
+    /// Only used by voice channels.
+    pub auto_accept: AutoAccept,
+    /// Only used by voice channels, `None` lets anyone ask to join.
+    pub membership: Option<Membership>,
+}
+
+/// Who may join a voice channel that has an owner.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Membership {
+    /// Protobuf encoding of the owner keypair, when we own the channel.
+    pub owner: Option<Vec<u8>>,
+    /// Presented when we join, the owner signs one for itself too.
+    pub invite: Invite,
+    /// Every invite we signed as the owner.
+    #[serde(default)]
+    pub invites: Vec<Invite>,
+}
+
+impl serde::Serialize for Channel {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(
+            &(&self.name, &self.kind, &self.auto_accept, &self.membership),
+            serializer,
+        )
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Channel {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ChannelVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for ChannelVisitor {
+            type Value = Channel;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str(
+                    "a channel name, type and optionally auto accept policy and membership",
+                )
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Channel, A::Error> {
+                let name = seq
+                    .next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                let kind = seq
+                    .next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(1, &self))?;
+                let auto_accept = seq.next_element()?.unwrap_or_default();
+                let membership = seq.next_element()?.unwrap_or_default();
+                Ok(Channel {
+                    name,
+                    kind,
+                    auto_accept,
+                    membership,
+                })
+            }
+        }
+
+        deserializer.deserialize_tuple(4, ChannelVisitor)
+    }
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Account {
     pub name: String,
@@ -27,7 +126,7 @@ pub struct Account {
     #[serde(default = "default_expires")]
     pub expires: DateTime<Utc>,
     #[serde(default)]
-    pub channels: Vec<(String, ChannelType)>,
+    pub channels: Vec<Channel>,
     #[serde(default)]
     pub renew: bool,
 }
@@ -36,10 +135,77 @@ fn default_expires() -> DateTime<Utc> {
     Utc::now()
 }
 
+/// Audio host and devices by name, `None` is the host default.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AudioSettings {
+    pub host: Option<String>,
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+}
+
+/// When the microphone is sent to voice channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum CaptureMode {
+    #[default]
+    AlwaysOn,
+    PushToTalk,
+    VoiceActivity,
+}
+
+impl CaptureMode {
+    pub const ALL: [CaptureMode; 3] = [
+        CaptureMode::AlwaysOn,
+        CaptureMode::PushToTalk,
+        CaptureMode::VoiceActivity,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CaptureMode::AlwaysOn => "Always on",
+            CaptureMode::PushToTalk => "Push to talk",
+            CaptureMode::VoiceActivity => "Voice activity",
+        }
+    }
+}
+
+/// Capture gate, mute and deafen, shared by every voice channel.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct VoiceSettings {
+    pub mode: CaptureMode,
+    /// Level in dBFS the microphone has to reach to open the gate in [`CaptureMode::VoiceActivity`].
+    pub vad_threshold: f32,
+    pub push_to_talk_key: egui::Key,
+    pub muted: bool,
+    /// Nothing is played and the microphone is muted.
+    pub deafened: bool,
+}
+
+impl Default for VoiceSettings {
+    fn default() -> Self {
+        Self {
+            mode: CaptureMode::AlwaysOn,
+            vad_threshold: -50.0,
+            push_to_talk_key: egui::Key::V,
+            muted: false,
+            deafened: false,
+        }
+    }
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct TheManSaveState {
     pub accounts: Vec<Account>,
     pub bootnodes: Vec<Multiaddr>,
+    #[serde(default)]
+    pub audio: AudioSettings,
+    #[serde(default)]
+    pub voice: VoiceSettings,
+    /// Capture processing settings that were changed.
+    #[serde(default)]
+    pub dsp: Vec<(String, Atom)>,
+    /// Codecs to prefer, first the most.
+    #[serde(default)]
+    pub codec_order: Vec<String>,
 }
 
 impl From<TheManSaveState> for TheManState {
@@ -49,6 +215,10 @@ impl From<TheManSaveState> for TheManState {
             accounts: value.accounts,
             account: None,
             bootnodes: value.bootnodes,
+            audio: value.audio,
+            voice: value.voice,
+            dsp: value.dsp,
+            codec_order: value.codec_order,
         }
     }
 }
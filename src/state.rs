@@ -7,13 +7,15 @@ use chrono::Utc;
 use libp2p::{
     identify::Info,
     identity::Keypair,
-    kad::{store::MemoryStore, Kademlia, KademliaConfig, KademliaEvent},
+    kad::{store::MemoryStore, Kademlia, KademliaConfig},
     multiaddr::Protocol,
-    swarm::{derive_prelude::ListenerId, NetworkBehaviour, SwarmBuilder},
-    Multiaddr, PeerId, Swarm, Transport,
+    swarm::{NetworkBehaviour, SwarmBuilder},
+    Multiaddr, PeerId, Swarm,
 };
 
-use crate::save_state::{Account, Friend};
+use the_man::Atom;
+
+use crate::save_state::{Account, AudioSettings, Friend, VoiceSettings};
 
 #[derive(Default, Debug, Clone)]
 pub struct PeerStatus {
@@ -51,11 +53,17 @@ pub struct TheManState {
     pub account: Option<ActiveAccount>,
     pub peers: HashMap<PeerId, PeerStatus>,
     pub bootnodes: Vec<Multiaddr>,
+    pub audio: AudioSettings,
+    pub voice: VoiceSettings,
+    pub dsp: Vec<(String, Atom)>,
+    pub codec_order: Vec<String>,
 }
 
 impl TheManState {
     pub fn set_account(&mut self, account_index: usize) {
-        let Some(account) = self.accounts.get(account_index) else{return};
+        let Some(account) = self.accounts.get(account_index) else {
+            return;
+        };
 
         let keypair = Keypair::from_protobuf_encoding(&account.private).unwrap();
         let peer_id = PeerId::from(keypair.public());
@@ -85,8 +93,12 @@ impl TheManState {
         		"/ip4/104.131.131.82/udp/4001/quic/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ".parse().unwrap()]);
 
             for node in self.bootnodes.iter() {
-                let Some(protocol) = node.iter().last() else {continue};
-                let Protocol::P2p(peer_id) = protocol else {continue};
+                let Some(protocol) = node.iter().last() else {
+                    continue;
+                };
+                let Protocol::P2p(peer_id) = protocol else {
+                    continue;
+                };
                 log::debug!("Adding BOOTNODE to kademlia: {node}/p2p/{protocol}");
                 behaviour.add_address(&peer_id, node.clone());
             }
@@ -123,8 +135,10 @@ impl TheManState {
         };
 
         let autonat = {
-            let mut config = libp2p::autonat::Config::default();
-            config.only_global_ips = false;
+            let config = libp2p::autonat::Config {
+                only_global_ips: false,
+                ..Default::default()
+            };
             libp2p::autonat::Behaviour::new(peer_id, config)
         };
 